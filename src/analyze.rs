@@ -0,0 +1,440 @@
+use std::collections::HashSet;
+use std::fmt;
+use crate::cfg::{successors, Code, Rom};
+use crate::display::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::instruction::{Instruction, Platform};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Confidence::Low => "low",
+            Confidence::Medium => "medium",
+            Confidence::High => "high",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Quirk value guessed by the analysis
+pub struct Guess {
+    pub name: &'static str,
+    pub value: bool,
+    pub confidence: Confidence,
+    pub reasons: Vec<String>,
+}
+
+// Evidence for a quirk, positive votes are for turning it on
+struct Evidence {
+    name: &'static str,
+    votes: i32,
+    reasons: Vec<String>,
+}
+
+impl Evidence {
+    fn new(name: &'static str) -> Self {
+        Evidence {
+            name,
+            votes: 0,
+            reasons: Vec::new(),
+        }
+    }
+
+    fn vote(&mut self, value: bool, weight: i32, reason: String) {
+        self.votes += if value { weight } else { -weight };
+        self.reasons.push(reason);
+    }
+
+    // Falls back to the platform default when there is no evidence
+    fn guess(self, default: bool, platform: Platform) -> Guess {
+        let confidence = match self.votes.abs() {
+            0 | 1 => Confidence::Low,
+            2 => Confidence::Medium,
+            _ => Confidence::High,
+        };
+        if self.votes == 0 {
+            return Guess {
+                name: self.name,
+                value: default,
+                confidence: Confidence::Low,
+                reasons: vec![format!("default for {}", platform)],
+            };
+        }
+        Guess {
+            name: self.name,
+            value: self.votes > 0,
+            confidence,
+            reasons: self.reasons,
+        }
+    }
+}
+
+// Quirks programs for the platform usually expect: shift, load_store,
+// jump, clip and vf_reset
+fn platform_quirks(platform: Platform) -> [bool; 5] {
+    match platform {
+        Platform::Chip8 | Platform::Chip8X | Platform::Eti660 => [false, false, false, true, true],
+        Platform::Schip | Platform::MegaChip => [true, true, true, true, false],
+        Platform::XoChip => [false, false, false, false, false],
+    }
+}
+
+pub struct Report {
+    pub platform: Platform,
+    pub platform_confidence: Confidence,
+    // Instructions which need a platform newer than CHIP-8, by address
+    pub extensions: Vec<(u16, Instruction)>,
+    pub quirks: Vec<Guess>,
+    pub code: Code,
+}
+
+// Scans the ROM for instructions and idioms which depend on the platform
+// and interpreter quirks
+pub fn analyze(rom: &Rom) -> Report {
+    let code = Code::trace(rom);
+    let extensions: Vec<(u16, Instruction)> = code.instructions
+        .iter()
+        .filter(|(_, instruction)| instruction.platform() != Platform::Chip8)
+        .map(|(address, instruction)| (*address, *instruction))
+        .collect();
+    let platform = extensions.iter()
+        .map(|(_, instruction)| instruction.platform())
+        .max()
+        .unwrap_or(Platform::Chip8);
+    let platform_confidence = if !code.unknown.is_empty() {
+        Confidence::Low
+    } else if extensions.len() > 1 {
+        Confidence::High
+    } else {
+        Confidence::Medium
+    };
+
+    let written = written_registers(&code);
+    let mut shift = Evidence::new("shift");
+    let mut load_store = Evidence::new("load_store");
+    let mut jump = Evidence::new("jump");
+    let mut clip = Evidence::new("clip");
+    let mut vf_reset = Evidence::new("vf_reset");
+
+    for (address, instruction) in &code.instructions {
+        match *instruction {
+            Instruction::Shr(x, y) | Instruction::Shl(x, y) if x != y => {
+                if written.contains(&y) {
+                    shift.vote(false, 1, format!("{:03X}: {} shifts V{:X} into V{:X}", address, instruction, y, x));
+                } else {
+                    shift.vote(true, 2, format!("{:03X}: {} while V{:X} is never set", address, instruction, y));
+                }
+            }
+            Instruction::LdIVx(_) | Instruction::LdVxI(_) => match index_loop(&code, rom, *address) {
+                Some(false) => load_store.vote(
+                    false,
+                    3,
+                    format!("{:03X}: {} in a loop relies on I increment", address, instruction),
+                ),
+                Some(true) => load_store.vote(
+                    true,
+                    2,
+                    format!("{:03X}: {} in a loop increments I explicitly", address, instruction),
+                ),
+                None => {}
+            },
+            Instruction::JpV0(x, nnn) if x != 0 && written.contains(&x) && !written.contains(&0) => {
+                jump.vote(true, 2, format!("{:03X}: JP V0, {:03X} while only V{:X} is set", address, nnn, x));
+            }
+            Instruction::JpV0(x, nnn) if written.contains(&0) && (x == 0 || !written.contains(&x)) => {
+                jump.vote(false, 2, format!("{:03X}: JP V0, {:03X} with V0 set", address, nnn));
+            }
+            _ => {}
+        }
+    }
+    for (address, instruction) in edge_sprites(&code) {
+        clip.vote(
+            platform != Platform::XoChip,
+            1,
+            format!("{:03X}: {} crosses the screen edge", address, instruction),
+        );
+    }
+    let logic = code.instructions
+        .values()
+        .any(|instruction| matches!(instruction, Instruction::Or(..) | Instruction::And(..) | Instruction::Xor(..)));
+    if platform == Platform::Chip8 && logic {
+        vf_reset.vote(true, 1, String::from("logic instructions clobber VF on COSMAC VIP"));
+    }
+
+    let defaults = platform_quirks(platform);
+    let quirks = vec![shift, load_store, jump, clip, vf_reset]
+        .into_iter()
+        .zip(defaults.iter())
+        .map(|(evidence, default)| evidence.guess(*default, platform))
+        .collect();
+    Report {
+        platform,
+        platform_confidence,
+        extensions,
+        quirks,
+        code,
+    }
+}
+
+// Registers assigned by reachable instructions
+fn written_registers(code: &Code) -> HashSet<usize> {
+    use Instruction::*;
+    let mut written = HashSet::new();
+    for instruction in code.instructions.values() {
+        match *instruction {
+            LdByte(x, _) | AddByte(x, _) | Ld(x, _) | Or(x, _) | And(x, _) | Xor(x, _) | Add(x, _)
+            | Sub(x, _) | Shr(x, _) | Subn(x, _) | Shl(x, _) | Rnd(x, _) | LdVxDt(x) | LdVxK(x) => {
+                written.insert(x);
+            }
+            LdVxI(x) | LdVxR(x) => written.extend(0..=x),
+            LoadRange(x, y) => written.extend(x.min(y)..=x.max(y)),
+            _ => {}
+        }
+    }
+    written
+}
+
+// Follows control flow from a Fx55 or Fx65 looking for a way back to it.
+// Returns `Some(false)` if the loop leaves I untouched, relying on the
+// instruction to increment it, `Some(true)` if the loop increments I with
+// Fx1E, and `None` when there is no loop or I is reloaded.
+fn index_loop(code: &Code, rom: &Rom, start: u16) -> Option<bool> {
+    let mut visited = HashSet::new();
+    let first = successors(rom, start, code.instructions[&start]);
+    let mut pending: Vec<(u16, bool)> = first.into_iter().map(|address| (address, false)).collect();
+    let mut found = None;
+    while let Some((address, incremented)) = pending.pop() {
+        if address == start {
+            // A loop without explicit increment is the stronger signal
+            found = Some(found.unwrap_or(true) && incremented);
+            continue;
+        }
+        if !visited.insert((address, incremented)) {
+            continue;
+        }
+        let instruction = match code.instructions.get(&address) {
+            Some(instruction) => *instruction,
+            None => continue,
+        };
+        let incremented = match instruction {
+            Instruction::LdI(_) | Instruction::LdFVx(_) | Instruction::LdHfVx(_) | Instruction::LdILong => continue,
+            Instruction::AddIVx(_) => true,
+            _ => incremented,
+        };
+        for successor in successors(rom, address, instruction) {
+            pending.push((successor, incremented));
+        }
+    }
+    found
+}
+
+// Sprites drawn at constant coordinates which don't fit on the screen.
+// Register values are only tracked within straight-line code.
+fn edge_sprites(code: &Code) -> Vec<(u16, Instruction)> {
+    let mut sprites = Vec::new();
+    let mut known: [Option<u8>; 16] = [None; 16];
+    let mut expected = None;
+    let mut skipped = false;
+    for (address, instruction) in &code.instructions {
+        if expected != Some(*address) || code.targets.contains(address) {
+            known = [None; 16];
+        }
+        expected = Some(address + instruction.size());
+        match *instruction {
+            Instruction::LdByte(x, kk) => known[x] = if skipped { None } else { Some(kk) },
+            Instruction::Drw(x, y, n) => {
+                if let (Some(vx), Some(vy)) = (known[x], known[y]) {
+                    let (size, rows) = if n == 0 { (16, 16) } else { (8, n as usize) };
+                    let (vx, vy) = (vx as usize % DISPLAY_WIDTH, vy as usize % DISPLAY_HEIGHT);
+                    if vx + size > DISPLAY_WIDTH || vy + rows > DISPLAY_HEIGHT {
+                        sprites.push((*address, *instruction));
+                    }
+                }
+                known[0xf] = None;
+            }
+            _ => {
+                for register in written_by(*instruction) {
+                    known[register] = None;
+                }
+            }
+        }
+        skipped = instruction.is_skip();
+    }
+    sprites
+}
+
+fn written_by(instruction: Instruction) -> Vec<usize> {
+    use Instruction::*;
+    match instruction {
+        AddByte(x, _) | Ld(x, _) | Rnd(x, _) | LdVxDt(x) | LdVxK(x) => vec![x],
+        Or(x, _) | And(x, _) | Xor(x, _) | Add(x, _) | Sub(x, _) | Shr(x, _) | Subn(x, _) | Shl(x, _) => {
+            vec![x, 0xf]
+        }
+        AddIVx(_) => vec![0xf],
+        LdVxI(x) | LdVxR(x) => (0..=x).collect(),
+        LoadRange(x, y) => (x.min(y)..=x.max(y)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl Report {
+    // Command line switches running the ROM as analyzed: the platform,
+    // unless it is CHIP-8 or not emulated yet, and the guessed quirks
+    pub fn args(&self) -> String {
+        let mut args = Vec::new();
+        if self.platform != Platform::Chip8 && self.platform.emulated() {
+            args.push(format!("--platform {}", self.platform));
+        }
+        args.extend(
+            self.quirks
+                .iter()
+                .map(|guess| format!("-q {}={}", guess.name, if guess.value { "on" } else { "off" })),
+        );
+        args.join(" ")
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Platform: {} ({} confidence)", self.platform, self.platform_confidence)?;
+        for (address, instruction) in self.extensions.iter().take(8) {
+            writeln!(f, "  {:03X}: {} ({})", address, instruction, instruction.platform())?;
+        }
+        if self.extensions.len() > 8 {
+            writeln!(f, "  and {} more", self.extensions.len() - 8)?;
+        }
+        writeln!(f, "Reachable instructions: {}", self.code.instructions.len())?;
+        if !self.code.unknown.is_empty() {
+            writeln!(f, "Unknown opcodes:")?;
+            for (address, opcode) in &self.code.unknown {
+                writeln!(f, "  {:03X}: {:04X}", address, opcode)?;
+            }
+        }
+        if !self.code.computed_jumps.is_empty() {
+            writeln!(f, "Computed jumps, code behind them is not analyzed:")?;
+            for address in &self.code.computed_jumps {
+                writeln!(f, "  {:03X}: {}", address, self.code.instructions[address])?;
+            }
+        }
+        writeln!(f, "Quirks:")?;
+        for guess in &self.quirks {
+            let value = if guess.value { "on" } else { "off" };
+            writeln!(f, "  {} = {} ({} confidence)", guess.name, value, guess.confidence)?;
+            for reason in &guess.reasons {
+                writeln!(f, "    {}", reason)?;
+            }
+        }
+        if !self.platform.emulated() {
+            writeln!(f, "{} is not emulated yet, its instructions halt the program", self.platform)?;
+        }
+        write!(f, "Suggested options: {}", self.args())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chip8(data: &[u8]) -> Report {
+        analyze(&Rom::new(data, Platform::Chip8))
+    }
+
+    fn guess<'a>(report: &'a Report, name: &str) -> &'a Guess {
+        report.quirks.iter().find(|guess| guess.name == name).unwrap()
+    }
+
+    #[test]
+    fn evidence_without_votes_falls_back_to_the_platform() {
+        let guess = Evidence::new("clip").guess(true, Platform::Chip8);
+        assert!(guess.value);
+        assert_eq!(guess.confidence, Confidence::Low);
+        assert_eq!(guess.reasons, vec![String::from("default for chip8")]);
+
+        let mut evidence = Evidence::new("shift");
+        evidence.vote(true, 2, String::from("a"));
+        evidence.vote(false, 1, String::from("b"));
+        evidence.vote(true, 2, String::from("c"));
+        let guess = evidence.guess(false, Platform::Chip8);
+        assert!(guess.value);
+        assert_eq!(guess.confidence, Confidence::High);
+        assert_eq!(guess.reasons.len(), 3);
+    }
+
+    #[test]
+    fn shift_of_an_unset_register_shifts_in_place() {
+        // SHR V0, V1 with V1 never set
+        let report = chip8(&[0x60, 0x05, 0x80, 0x16, 0x12, 0x04]);
+        let shift = guess(&report, "shift");
+        assert!(shift.value);
+        assert_eq!(shift.confidence, Confidence::Medium);
+        // V1 is set first, so it is shifted into V0
+        let report = chip8(&[0x61, 0x05, 0x80, 0x16, 0x12, 0x04]);
+        assert!(!guess(&report, "shift").value);
+    }
+
+    #[test]
+    fn store_loop_without_fx1e_relies_on_increment() {
+        // LD I, 300; LD V0, [I]; ADD V1, 1; SE V1, 10; JP 202
+        let report = chip8(&[0xA3, 0x00, 0xF0, 0x65, 0x71, 0x01, 0x31, 0x10, 0x12, 0x02, 0x12, 0x0A]);
+        let load_store = guess(&report, "load_store");
+        assert!(!load_store.value);
+        assert_eq!(load_store.confidence, Confidence::High);
+        // Same loop with ADD I, V2
+        let report = chip8(&[0xA3, 0x00, 0xF0, 0x65, 0xF2, 0x1E, 0x31, 0x10, 0x12, 0x02, 0x12, 0x0A]);
+        assert!(guess(&report, "load_store").value);
+    }
+
+    #[test]
+    fn bnnn_with_only_vx_set_jumps_by_vx() {
+        let report = chip8(&[0x61, 0x04, 0xB1, 0x23]);
+        assert!(guess(&report, "jump").value);
+        assert_eq!(report.code.computed_jumps, vec![0x202]);
+        let report = chip8(&[0x60, 0x04, 0xB1, 0x23]);
+        assert!(!guess(&report, "jump").value);
+    }
+
+    #[test]
+    fn sprites_across_the_edge_are_clipped() {
+        // V0 = 3C, V1 = 0, DRW V0, V1, 5
+        let report = chip8(&[0x60, 0x3C, 0x61, 0x00, 0xD0, 0x15, 0x12, 0x06]);
+        let clip = guess(&report, "clip");
+        assert!(clip.value);
+        assert!(clip.reasons[0].contains("crosses the screen edge"));
+        // Fits on the screen, the platform default stays
+        let report = chip8(&[0x60, 0x38, 0x61, 0x00, 0xD0, 0x15, 0x12, 0x06]);
+        assert_eq!(guess(&report, "clip").reasons, vec![String::from("default for chip8")]);
+    }
+
+    #[test]
+    fn schip_instructions_give_schip() {
+        let report = chip8(&[0x00, 0xFF, 0x00, 0xFE, 0x12, 0x04]);
+        assert_eq!(report.platform, Platform::Schip);
+        assert_eq!(report.platform_confidence, Confidence::High);
+        assert_eq!(report.extensions.len(), 2);
+        assert!(!report.args().contains("--platform"));
+        assert!(report.to_string().contains("schip is not emulated yet"));
+    }
+
+    #[test]
+    fn emulated_platforms_are_suggested() {
+        let report = analyze(&Rom::new(&[0x02, 0xA0, 0x13, 0x02], Platform::Chip8X));
+        assert_eq!(report.platform, Platform::Chip8X);
+        assert_eq!(report.platform_confidence, Confidence::Medium);
+        assert!(report.args().starts_with("--platform chip8x -q shift=off"));
+        assert!(!report.to_string().contains("not emulated"));
+    }
+
+    #[test]
+    fn unknown_opcodes_lower_confidence() {
+        let report = chip8(&[0x00, 0xFF, 0x50, 0x04]);
+        assert_eq!(report.platform, Platform::Schip);
+        assert_eq!(report.platform_confidence, Confidence::Low);
+        assert_eq!(report.code.unknown.len(), 1);
+    }
+}
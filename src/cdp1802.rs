@@ -0,0 +1,545 @@
+// RCA CDP1802 microprocessor, as used in the COSMAC VIP
+
+// Memory and I/O seen by the processor
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // OUT 1-7, the value is taken from memory at R(X)
+    fn output(&mut self, port: u8, value: u8);
+    // INP 1-7, the value is stored to memory at R(X) and to D
+    fn input(&mut self, port: u8) -> u8;
+    // EF1-EF4 input flags, numbered from 1
+    fn flag(&self, number: u8) -> bool;
+}
+
+pub struct Cpu {
+    // Scratchpad registers
+    pub r: [u16; 16],
+    // Selects program counter register
+    pub p: u8,
+    // Selects data pointer register
+    pub x: u8,
+    // Accumulator
+    pub d: u8,
+    // Carry and not borrow
+    pub df: bool,
+    // X and P saved by interrupt or MARK
+    pub t: u8,
+    // Interrupts enabled
+    pub ie: bool,
+    // Output flip-flop, drives the VIP speaker
+    pub q: bool,
+    // Stopped by IDL until the next DMA or interrupt
+    idle: bool,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Cpu::new()
+    }
+}
+
+impl Cpu {
+    // Processor in its reset state, running from address 0 with R0
+    pub fn new() -> Self {
+        Cpu {
+            r: [0; 16],
+            p: 0,
+            x: 0,
+            d: 0,
+            df: false,
+            t: 0,
+            ie: true,
+            q: false,
+            idle: false,
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.r[self.p as usize]
+    }
+
+    // Services an interrupt request if enabled, returns machine cycles taken
+    pub fn interrupt(&mut self) -> u32 {
+        if !self.ie {
+            return 0;
+        }
+        self.t = self.x << 4 | self.p;
+        self.x = 2;
+        self.p = 1;
+        self.ie = false;
+        self.idle = false;
+        1
+    }
+
+    // Output DMA cycle: reads the byte at R0 for a peripheral
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+
+    // Executes one instruction, returns machine cycles taken
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.idle {
+            return 1;
+        }
+        let opcode = self.immediate(bus);
+        let (i, n) = (opcode >> 4, (opcode & 0xf) as usize);
+        let x = self.x as usize;
+        match i {
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = bus.read(self.r[n]),
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+            0x3 => {
+                let taken = self.condition(bus, n & 7) != (n & 8 != 0);
+                self.branch(bus, taken);
+            }
+            0x4 => {
+                self.d = bus.read(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            0x5 => bus.write(self.r[n], self.d),
+            0x6 if n == 0 => self.r[x] = self.r[x].wrapping_add(1),
+            0x6 if n < 8 => {
+                let value = bus.read(self.r[x]);
+                bus.output(n as u8, value);
+                self.r[x] = self.r[x].wrapping_add(1);
+            }
+            // 68 is not defined on the 1802
+            0x6 if n == 8 => {}
+            0x6 => {
+                let value = bus.input(n as u8 - 8);
+                bus.write(self.r[x], value);
+                self.d = value;
+            }
+            0x7 => self.op_7n(bus, n),
+            0x8 => self.d = self.r[n] as u8,
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            0xa => self.r[n] = self.r[n] & 0xff00 | self.d as u16,
+            0xb => self.r[n] = self.r[n] & 0x00ff | (self.d as u16) << 8,
+            0xc => {
+                self.op_cn(bus, n);
+                return 3;
+            }
+            0xd => self.p = n as u8,
+            0xe => self.x = n as u8,
+            _ => self.op_fn(bus, n),
+        }
+        2
+    }
+
+    fn op_7n<B: Bus>(&mut self, bus: &mut B, n: usize) {
+        let x = self.x as usize;
+        match n {
+            // RET and DIS
+            0x0 | 0x1 => {
+                let value = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+                self.x = value >> 4;
+                self.p = value & 0xf;
+                self.ie = n == 0;
+            }
+            // LDXA
+            0x2 => {
+                self.d = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+            }
+            // STXD
+            0x3 => {
+                bus.write(self.r[x], self.d);
+                self.r[x] = self.r[x].wrapping_sub(1);
+            }
+            // ADC, SDB, SMB and their immediate forms
+            0x4 | 0xc => {
+                let value = self.operand(bus, n);
+                self.add(value, self.df);
+            }
+            0x5 | 0xd => {
+                let value = self.operand(bus, n);
+                self.subtract(value, self.d, self.df);
+            }
+            0x7 | 0xf => {
+                let value = self.operand(bus, n);
+                self.subtract(self.d, value, self.df);
+            }
+            // SHRC
+            0x6 => {
+                let carry = self.df;
+                self.df = self.d & 1 != 0;
+                self.d = self.d >> 1 | (carry as u8) << 7;
+            }
+            // SHLC
+            0xe => {
+                let carry = self.df;
+                self.df = self.d & 0x80 != 0;
+                self.d = self.d << 1 | carry as u8;
+            }
+            // SAV
+            0x8 => bus.write(self.r[x], self.t),
+            // MARK
+            0x9 => {
+                self.t = self.x << 4 | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            // REQ and SEQ
+            _ => self.q = n == 0xb,
+        }
+    }
+
+    // Long branches and skips
+    fn op_cn<B: Bus>(&mut self, bus: &mut B, n: usize) {
+        let condition = match n & 3 {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            _ => self.df,
+        };
+        match n {
+            // LBR, LBQ, LBZ, LBDF and their negations
+            0x0..=0x3 | 0x9..=0xb => {
+                if condition != (n & 8 != 0) {
+                    let high = bus.read(self.pc());
+                    let low = bus.read(self.pc().wrapping_add(1));
+                    self.r[self.p as usize] = (high as u16) << 8 | low as u16;
+                } else {
+                    self.skip(2);
+                }
+            }
+            // NOP
+            0x4 => {}
+            // LSNQ, LSNZ and LSNF
+            0x5..=0x7 => {
+                if !condition {
+                    self.skip(2);
+                }
+            }
+            // LSKP
+            0x8 => self.skip(2),
+            // LSIE
+            0xc => {
+                if self.ie {
+                    self.skip(2);
+                }
+            }
+            // LSQ, LSZ and LSDF
+            _ => {
+                if condition {
+                    self.skip(2);
+                }
+            }
+        }
+    }
+
+    // Logic and arithmetic with memory at R(X), or immediate operand for F8-FF
+    fn op_fn<B: Bus>(&mut self, bus: &mut B, n: usize) {
+        match n {
+            // LDX and LDI
+            0x0 | 0x8 => self.d = self.operand(bus, n),
+            0x1 | 0x9 => self.d |= self.operand(bus, n),
+            0x2 | 0xa => self.d &= self.operand(bus, n),
+            0x3 | 0xb => self.d ^= self.operand(bus, n),
+            0x4 | 0xc => {
+                let value = self.operand(bus, n);
+                self.add(value, false);
+            }
+            // SD and SDI
+            0x5 | 0xd => {
+                let value = self.operand(bus, n);
+                self.subtract(value, self.d, true);
+            }
+            // SHR
+            0x6 => {
+                self.df = self.d & 1 != 0;
+                self.d >>= 1;
+            }
+            // SHL
+            0xe => {
+                self.df = self.d & 0x80 != 0;
+                self.d <<= 1;
+            }
+            // SM and SMI
+            _ => {
+                let value = self.operand(bus, n);
+                self.subtract(self.d, value, true);
+            }
+        }
+    }
+
+    // Short branch conditions: always, Q, D = 0, DF and EF1-EF4
+    fn condition<B: Bus>(&self, bus: &B, n: usize) -> bool {
+        match n {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            3 => self.df,
+            _ => bus.flag(n as u8 - 3),
+        }
+    }
+
+    fn branch<B: Bus>(&mut self, bus: &mut B, taken: bool) {
+        if taken {
+            let low = bus.read(self.pc());
+            let pc = &mut self.r[self.p as usize];
+            *pc = *pc & 0xff00 | low as u16;
+        } else {
+            self.skip(1);
+        }
+    }
+
+    fn skip(&mut self, bytes: u16) {
+        let pc = &mut self.r[self.p as usize];
+        *pc = pc.wrapping_add(bytes);
+    }
+
+    fn immediate<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.pc());
+        self.skip(1);
+        value
+    }
+
+    // Memory at R(X) for the low half of a row, immediate byte for the high
+    fn operand<B: Bus>(&mut self, bus: &mut B, n: usize) -> u8 {
+        if n & 8 != 0 {
+            self.immediate(bus)
+        } else {
+            bus.read(self.r[self.x as usize])
+        }
+    }
+
+    fn add(&mut self, value: u8, carry: bool) {
+        let sum = self.d as u16 + value as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xff;
+    }
+
+    // DF is set when there is no borrow, `carry` is the incoming DF
+    fn subtract(&mut self, minuend: u8, subtrahend: u8, carry: bool) {
+        let difference = minuend as i16 - subtrahend as i16 - !carry as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 64 KiB of RAM recording output
+    struct Ram {
+        memory: Vec<u8>,
+        output: Vec<(u8, u8)>,
+        flags: [bool; 4],
+    }
+
+    impl Ram {
+        // RAM with the program at address 0
+        fn with_program(program: &[u8]) -> Ram {
+            let mut memory = vec![0; 0x10000];
+            memory[..program.len()].copy_from_slice(program);
+            Ram { memory, output: Vec::new(), flags: [false; 4] }
+        }
+    }
+
+    impl Bus for Ram {
+        fn read(&mut self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.memory[address as usize] = value;
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.output.push((port, value));
+        }
+
+        fn input(&mut self, port: u8) -> u8 {
+            port * 0x11
+        }
+
+        fn flag(&self, number: u8) -> bool {
+            self.flags[number as usize - 1]
+        }
+    }
+
+    // Runs the given number of instructions, returns machine cycles taken
+    fn run(cpu: &mut Cpu, ram: &mut Ram, steps: usize) -> u32 {
+        (0..steps).map(|_| cpu.step(ram)).sum()
+    }
+
+    #[test]
+    fn add_immediate_sets_carry() {
+        let mut ram = Ram::with_program(&[0xf8, 0xff, 0xfc, 0x01, 0xf8, 0x10, 0x7c, 0x01]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 2);
+        assert_eq!((cpu.d, cpu.df), (0x00, true));
+        // ADCI adds the carry of ADI
+        run(&mut cpu, &mut ram, 2);
+        assert_eq!((cpu.d, cpu.df), (0x12, false));
+    }
+
+    #[test]
+    fn subtract_sets_df_when_there_is_no_borrow() {
+        // LDI 00, SMI 01
+        let mut ram = Ram::with_program(&[0xf8, 0x00, 0xff, 0x01]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 2);
+        assert_eq!((cpu.d, cpu.df), (0xff, false));
+
+        // LDI 03, SDI 05 gives 5 - 3
+        let mut ram = Ram::with_program(&[0xf8, 0x03, 0xfd, 0x05]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 2);
+        assert_eq!((cpu.d, cpu.df), (0x02, true));
+    }
+
+    #[test]
+    fn shifts_through_df() {
+        // LDI 01, SHR, SHRC
+        let mut ram = Ram::with_program(&[0xf8, 0x01, 0xf6, 0x76]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 2);
+        assert_eq!((cpu.d, cpu.df), (0x00, true));
+        run(&mut cpu, &mut ram, 1);
+        assert_eq!((cpu.d, cpu.df), (0x80, false));
+    }
+
+    #[test]
+    fn logic_with_memory_at_rx() {
+        // R1 = 0010, SEX 1, LDI F0, OR, AND, XOR
+        let mut ram = Ram::with_program(&[0xf8, 0x10, 0xa1, 0xe1, 0xf8, 0xf0, 0xf1, 0xf2, 0xf3]);
+        ram.memory[0x10] = 0x0f;
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 4);
+        assert_eq!(cpu.x, 1);
+        run(&mut cpu, &mut ram, 1);
+        assert_eq!(cpu.d, 0xff);
+        run(&mut cpu, &mut ram, 1);
+        assert_eq!(cpu.d, 0x0f);
+        run(&mut cpu, &mut ram, 1);
+        assert_eq!(cpu.d, 0x00);
+    }
+
+    #[test]
+    fn register_operations() {
+        // LDI 12, PHI 5, LDI 34, PLO 5, INC 5, DEC 6, GHI 5
+        let mut ram = Ram::with_program(&[0xf8, 0x12, 0xb5, 0xf8, 0x34, 0xa5, 0x15, 0x26, 0x95]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 7);
+        assert_eq!(cpu.r[5], 0x1235);
+        assert_eq!(cpu.r[6], 0xffff);
+        assert_eq!(cpu.d, 0x12);
+    }
+
+    #[test]
+    fn load_and_store_via_registers() {
+        // R1 = 0020, LDA 1, STR 1, LDN 1
+        let mut ram = Ram::with_program(&[0xf8, 0x20, 0xa1, 0x41, 0x51, 0x01]);
+        ram.memory[0x20] = 0xab;
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 4);
+        assert_eq!((cpu.d, cpu.r[1]), (0xab, 0x21));
+        assert_eq!(ram.memory[0x21], 0xab);
+        ram.memory[0x21] = 0xcd;
+        run(&mut cpu, &mut ram, 1);
+        assert_eq!(cpu.d, 0xcd);
+    }
+
+    #[test]
+    fn short_branches() {
+        // LDI 00, BZ 10
+        let mut ram = Ram::with_program(&[0xf8, 0x00, 0x32, 0x10]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 2);
+        assert_eq!(cpu.pc(), 0x10);
+
+        // BN1 10 is not taken while EF1 is set
+        let mut ram = Ram::with_program(&[0x3c, 0x10]);
+        ram.flags[0] = true;
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 1);
+        assert_eq!(cpu.pc(), 0x02);
+    }
+
+    #[test]
+    fn long_branches_and_skips_take_three_cycles() {
+        // LBR 1234
+        let mut ram = Ram::with_program(&[0xc0, 0x12, 0x34]);
+        let mut cpu = Cpu::new();
+        assert_eq!(run(&mut cpu, &mut ram, 1), 3);
+        assert_eq!(cpu.pc(), 0x1234);
+
+        // LSKP over two bytes, then LBNZ not taken while D is 0
+        let mut ram = Ram::with_program(&[0xc8, 0xff, 0xff, 0xca, 0x12, 0x34]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 1);
+        assert_eq!(cpu.pc(), 0x03);
+        assert_eq!(run(&mut cpu, &mut ram, 1), 3);
+        assert_eq!(cpu.pc(), 0x06);
+    }
+
+    #[test]
+    fn sep_switches_program_counter() {
+        // R3 = 0010, SEP 3
+        let mut ram = Ram::with_program(&[0xf8, 0x10, 0xa3, 0xd3]);
+        ram.memory[0x10] = 0xf8;
+        ram.memory[0x11] = 0x42;
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 5);
+        assert_eq!((cpu.p, cpu.d), (3, 0x42));
+    }
+
+    #[test]
+    fn mark_and_ret_restore_x_and_p() {
+        // R2 = 0080, SEX 5, MARK: T = 50 saved at R2, X = P
+        let mut ram = Ram::with_program(&[0xf8, 0x80, 0xa2, 0xe5, 0x79, 0xe2, 0x12, 0x70]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 4);
+        assert_eq!((cpu.t, cpu.x), (0x50, 0));
+        assert_eq!((ram.memory[0x80], cpu.r[2]), (0x50, 0x7f));
+        // SEX 2, INC 2 points R(X) back at the saved byte for RET
+        run(&mut cpu, &mut ram, 3);
+        assert_eq!((cpu.x, cpu.p, cpu.ie), (5, 0, true));
+    }
+
+    #[test]
+    fn output_and_input_use_memory_at_rx() {
+        // R1 = 0020, SEX 1, OUT 2, INP 3
+        let mut ram = Ram::with_program(&[0xf8, 0x20, 0xa1, 0xe1, 0x62, 0x6b]);
+        ram.memory[0x20] = 0x07;
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 5);
+        assert_eq!(ram.output, vec![(2, 0x07)]);
+        assert_eq!(cpu.r[1], 0x21);
+        run(&mut cpu, &mut ram, 1);
+        assert_eq!((cpu.d, ram.memory[0x21]), (0x33, 0x33));
+    }
+
+    #[test]
+    fn idle_waits_for_interrupt() {
+        let mut ram = Ram::with_program(&[0x00, 0xc4]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 1);
+        assert_eq!(run(&mut cpu, &mut ram, 3), 3);
+        assert_eq!(cpu.pc(), 0x01);
+        assert_eq!(cpu.interrupt(), 1);
+        assert_eq!((cpu.p, cpu.x, cpu.t, cpu.ie), (1, 2, 0x00, false));
+        // Disabled interrupts are not taken
+        assert_eq!(cpu.interrupt(), 0);
+    }
+
+    #[test]
+    fn q_is_set_and_reset() {
+        let mut ram = Ram::with_program(&[0x7b, 0x7a]);
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut ram, 1);
+        assert!(cpu.q);
+        run(&mut cpu, &mut ram, 1);
+        assert!(!cpu.q);
+    }
+}
@@ -0,0 +1,444 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use crate::instruction::{Instruction, Platform};
+use crate::memory::Layout;
+
+// ROM placed at its load address, as the interpreter of its platform sees it
+#[derive(Copy, Clone, Debug)]
+pub struct Rom<'a> {
+    pub data: &'a [u8],
+    // Load address, tracing starts there
+    pub start: u16,
+    // Decides how opcodes decode, e.g. Bxyn colors zones on CHIP-8X
+    pub platform: Platform,
+}
+
+impl<'a> Rom<'a> {
+    // ROM loaded where the platform loads programs
+    pub fn new(data: &'a [u8], platform: Platform) -> Self {
+        Rom::with_start(data, platform, Layout::for_platform(platform).start)
+    }
+
+    pub fn with_start(data: &'a [u8], platform: Platform, start: u16) -> Self {
+        Rom { data, start, platform }
+    }
+
+    // Reads an opcode at the given address
+    pub fn opcode(&self, address: u16) -> Option<u16> {
+        let offset = address.checked_sub(self.start)? as usize;
+        let bytes = self.data.get(offset..offset + 2)?;
+        Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    pub fn decode(&self, address: u16) -> Option<Instruction> {
+        self.opcode(address).and_then(|opcode| Instruction::decode_for(opcode, self.platform))
+    }
+}
+
+// Instructions reachable from the start address, found by following
+// jumps, calls and skips. Bytes never reached are treated as data.
+pub struct Code {
+    pub instructions: BTreeMap<u16, Instruction>,
+    // Reachable opcodes which decode to no known instruction
+    pub unknown: BTreeMap<u16, u16>,
+    // Addresses entered other than by falling through
+    pub targets: BTreeSet<u16>,
+    // Bnnn instructions, their targets are only known at run time
+    pub computed_jumps: Vec<u16>,
+}
+
+impl Code {
+    pub fn trace(rom: &Rom) -> Code {
+        let mut code = Code {
+            instructions: BTreeMap::new(),
+            unknown: BTreeMap::new(),
+            targets: BTreeSet::new(),
+            computed_jumps: Vec::new(),
+        };
+        let start = rom.start;
+        code.targets.insert(start);
+        let mut pending = vec![start];
+        while let Some(address) = pending.pop() {
+            if code.instructions.contains_key(&address) || code.unknown.contains_key(&address) {
+                continue;
+            }
+            let opcode = match rom.opcode(address) {
+                Some(opcode) => opcode,
+                None => continue,
+            };
+            let instruction = match Instruction::decode_for(opcode, rom.platform) {
+                Some(instruction) => instruction,
+                None => {
+                    code.unknown.insert(address, opcode);
+                    continue;
+                }
+            };
+            code.instructions.insert(address, instruction);
+            if let Instruction::JpV0(..) = instruction {
+                code.computed_jumps.push(address);
+            }
+            let next = address + instruction.size();
+            for successor in successors(rom, address, instruction) {
+                if successor != next {
+                    code.targets.insert(successor);
+                }
+                pending.push(successor);
+            }
+        }
+        code
+    }
+}
+
+// Addresses control may go to after the instruction, calls are followed
+// into the subroutine and past it
+pub fn successors(rom: &Rom, address: u16, instruction: Instruction) -> Vec<u16> {
+    let next = address + instruction.size();
+    match instruction {
+        Instruction::Jp(nnn) => vec![nnn],
+        Instruction::Call(nnn) => vec![nnn, next],
+        Instruction::Ret | Instruction::Exit | Instruction::JpV0(..) => Vec::new(),
+        instruction if instruction.is_skip() => {
+            // XO-CHIP skips over both words of F000
+            let skipped = rom.decode(next).map_or(2, |instruction| instruction.size());
+            vec![next, next + skipped]
+        }
+        _ => vec![next],
+    }
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    // Falling through to the next instruction, including a skip not taken
+    Next,
+    // Skip taken
+    Skip,
+    Jump,
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub target: u16,
+    pub kind: EdgeKind,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Line {
+    pub address: u16,
+    pub opcode: u16,
+    pub text: String,
+}
+
+// Straight-line code entered only at its start
+#[derive(Serialize, Clone, Debug)]
+pub struct Block {
+    pub start: u16,
+    // Address following the last instruction
+    pub end: u16,
+    pub instructions: Vec<Line>,
+    pub successors: Vec<Edge>,
+    // Subroutines called from the block
+    pub calls: Vec<u16>,
+    // Block ends with 00EE
+    pub returns: bool,
+    // Block ends with Bnnn, whose target is not known statically
+    pub indirect: bool,
+}
+
+// Blocks reachable from a subroutine entry without following calls,
+// the program entry point is treated as a subroutine too
+#[derive(Serialize, Clone, Debug)]
+pub struct Subroutine {
+    pub entry: u16,
+    pub blocks: Vec<u16>,
+}
+
+// Bytes of the ROM which are never executed, usually sprites and tables
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct Range {
+    pub start: u16,
+    pub end: u16,
+}
+
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct Unknown {
+    pub address: u16,
+    pub opcode: u16,
+}
+
+// Control-flow graph recovered by recursive descent from the start address
+#[derive(Serialize, Clone, Debug)]
+pub struct Graph {
+    pub entry: u16,
+    pub subroutines: Vec<Subroutine>,
+    pub blocks: Vec<Block>,
+    pub data: Vec<Range>,
+    // Addresses of Bnnn instructions
+    pub indirect_jumps: Vec<u16>,
+    pub unknown: Vec<Unknown>,
+}
+
+impl Graph {
+    pub fn build(rom: &Rom) -> Graph {
+        let code = Code::trace(rom);
+        let leaders = leaders(&code);
+        let mut blocks: BTreeMap<u16, Block> = BTreeMap::new();
+        for &start in &leaders {
+            if !code.instructions.contains_key(&start) {
+                continue;
+            }
+            let block = build_block(rom, &code, &leaders, start);
+            blocks.insert(start, block);
+        }
+
+        let mut entries: BTreeSet<u16> = blocks.values()
+            .flat_map(|block| block.calls.iter().copied())
+            .collect();
+        entries.insert(rom.start);
+        let subroutines = entries
+            .iter()
+            .filter(|entry| blocks.contains_key(entry))
+            .map(|entry| Subroutine {
+                entry: *entry,
+                blocks: subroutine_blocks(&blocks, *entry),
+            })
+            .collect();
+
+        Graph {
+            entry: rom.start,
+            subroutines,
+            blocks: blocks.into_values().collect(),
+            data: data_ranges(rom, &code),
+            indirect_jumps: code.computed_jumps.clone(),
+            unknown: code.unknown
+                .iter()
+                .map(|(address, opcode)| Unknown { address: *address, opcode: *opcode })
+                .collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Graph is always serializable")
+    }
+
+    // Graphviz graph with a cluster per subroutine. Skips taken are dashed,
+    // calls are dotted and indirect jumps are marked red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph cfg {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+        for subroutine in &self.subroutines {
+            let name = if subroutine.entry == self.entry {
+                String::from("main")
+            } else {
+                format!("sub_{:03X}", subroutine.entry)
+            };
+            writeln!(dot, "    subgraph cluster_{:03X} {{", subroutine.entry).unwrap();
+            writeln!(dot, "        label=\"{}\";", name).unwrap();
+            for start in &subroutine.blocks {
+                writeln!(dot, "        b_{:03X};", start).unwrap();
+            }
+            writeln!(dot, "    }}").unwrap();
+        }
+        for block in &self.blocks {
+            let mut label = String::new();
+            for line in &block.instructions {
+                write!(label, "{:03X}: {}\\l", line.address, line.text).unwrap();
+            }
+            let color = if block.indirect { ", color=red" } else { "" };
+            writeln!(dot, "    b_{:03X} [label=\"{}\"{}];", block.start, label, color).unwrap();
+            for edge in &block.successors {
+                let style = match edge.kind {
+                    EdgeKind::Next | EdgeKind::Jump => "",
+                    EdgeKind::Skip => " [style=dashed, label=\"skip\"]",
+                };
+                writeln!(dot, "    b_{:03X} -> b_{:03X}{};", block.start, edge.target, style).unwrap();
+            }
+            for call in &block.calls {
+                writeln!(dot, "    b_{:03X} -> b_{:03X} [style=dotted, label=\"call\"];", block.start, call).unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+// Addresses starting a basic block: the entry point, targets of jumps,
+// calls and skips, and instructions following block terminators
+fn leaders(code: &Code) -> BTreeSet<u16> {
+    let mut leaders = code.targets.clone();
+    for (address, instruction) in &code.instructions {
+        if ends_block(*instruction) {
+            leaders.insert(address + instruction.size());
+        }
+    }
+    leaders
+}
+
+fn ends_block(instruction: Instruction) -> bool {
+    match instruction {
+        Instruction::Jp(_) | Instruction::Ret | Instruction::Exit | Instruction::JpV0(..) => true,
+        instruction => instruction.is_skip(),
+    }
+}
+
+fn build_block(rom: &Rom, code: &Code, leaders: &BTreeSet<u16>, start: u16) -> Block {
+    let mut block = Block {
+        start,
+        end: start,
+        instructions: Vec::new(),
+        successors: Vec::new(),
+        calls: Vec::new(),
+        returns: false,
+        indirect: false,
+    };
+    let mut address = start;
+    while let Some(instruction) = code.instructions.get(&address) {
+        block.instructions.push(Line {
+            address,
+            opcode: rom.opcode(address).unwrap_or(0),
+            text: instruction.to_string(),
+        });
+        let next = address + instruction.size();
+        block.end = next;
+        match *instruction {
+            Instruction::Call(nnn) => block.calls.push(nnn),
+            Instruction::Ret => block.returns = true,
+            Instruction::JpV0(..) => block.indirect = true,
+            _ => {}
+        }
+        if ends_block(*instruction) {
+            for target in successors(rom, address, *instruction) {
+                let kind = match instruction {
+                    Instruction::Jp(_) => EdgeKind::Jump,
+                    _ if target == next => EdgeKind::Next,
+                    _ => EdgeKind::Skip,
+                };
+                block.successors.push(Edge { target, kind });
+            }
+            break;
+        }
+        if leaders.contains(&next) {
+            if code.instructions.contains_key(&next) {
+                block.successors.push(Edge { target: next, kind: EdgeKind::Next });
+            }
+            break;
+        }
+        address = next;
+    }
+    // Edges into undecodable opcodes or outside the ROM lead nowhere
+    block.successors.retain(|edge| code.instructions.contains_key(&edge.target));
+    block
+}
+
+// Blocks reachable from the entry without entering called subroutines
+fn subroutine_blocks(blocks: &BTreeMap<u16, Block>, entry: u16) -> Vec<u16> {
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![entry];
+    while let Some(start) = pending.pop() {
+        if !visited.insert(start) {
+            continue;
+        }
+        if let Some(block) = blocks.get(&start) {
+            pending.extend(block.successors.iter().map(|edge| edge.target));
+        }
+    }
+    visited.into_iter().collect()
+}
+
+// Byte ranges of the ROM covered by no reachable instruction
+fn data_ranges(rom: &Rom, code: &Code) -> Vec<Range> {
+    let start = rom.start;
+    let mut covered = vec![false; rom.data.len()];
+    for (address, instruction) in &code.instructions {
+        let offset = (address - start) as usize;
+        for byte in covered.iter_mut().skip(offset).take(instruction.size() as usize) {
+            *byte = true;
+        }
+    }
+    let mut ranges: Vec<Range> = Vec::new();
+    for (offset, _) in covered.iter().enumerate().filter(|(_, covered)| !**covered) {
+        let address = start + offset as u16;
+        match ranges.last_mut() {
+            Some(range) if range.end == address => range.end = address + 1,
+            _ => ranges.push(Range { start: address, end: address + 1 }),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Calls a subroutine, then loops on a skip, data follows the code
+    const ROM: [u8; 12] = [
+        0x22, 0x08, // 200: CALL 208
+        0x30, 0x00, // 202: SE V0, 00
+        0x12, 0x02, // 204: JP 202
+        0x12, 0x06, // 206: JP 206
+        0x00, 0xEE, // 208: RET
+        0x01, 0x02, // 20A: data
+    ];
+
+    fn starts(graph: &Graph) -> Vec<u16> {
+        graph.blocks.iter().map(|block| block.start).collect()
+    }
+
+    #[test]
+    fn trace_follows_calls_and_skips() {
+        let code = Code::trace(&Rom::new(&ROM, Platform::Chip8));
+        let addresses: Vec<u16> = code.instructions.keys().copied().collect();
+        assert_eq!(addresses, vec![0x200, 0x202, 0x204, 0x206, 0x208]);
+        assert!(code.targets.contains(&0x208));
+        assert!(code.unknown.is_empty());
+    }
+
+    #[test]
+    fn graph_splits_blocks_and_subroutines() {
+        let graph = Graph::build(&Rom::new(&ROM, Platform::Chip8));
+        assert_eq!(graph.entry, 0x200);
+        let entries: Vec<u16> = graph.subroutines.iter().map(|subroutine| subroutine.entry).collect();
+        assert_eq!(entries, vec![0x200, 0x208]);
+        assert!(starts(&graph).contains(&0x208));
+        let ret = graph.blocks.iter().find(|block| block.start == 0x208).unwrap();
+        assert!(ret.returns);
+        assert!(ret.successors.is_empty());
+        let skip = graph.blocks.iter().find(|block| block.instructions.iter().any(|line| line.address == 0x202)).unwrap();
+        let kinds: Vec<EdgeKind> = skip.successors.iter().map(|edge| edge.kind).collect();
+        assert_eq!(kinds, vec![EdgeKind::Next, EdgeKind::Skip]);
+    }
+
+    #[test]
+    fn unreached_bytes_are_data() {
+        let graph = Graph::build(&Rom::new(&ROM, Platform::Chip8));
+        let data: Vec<(u16, u16)> = graph.data.iter().map(|range| (range.start, range.end)).collect();
+        assert_eq!(data, vec![(0x20A, 0x20C)]);
+    }
+
+    #[test]
+    fn tracing_starts_at_the_load_address() {
+        let rom = [0x16, 0x04, 0xFF, 0xFF, 0x00, 0xEE];
+        let graph = Graph::build(&Rom::new(&rom, Platform::Eti660));
+        assert_eq!(graph.entry, 0x600);
+        assert!(graph.unknown.is_empty());
+        let data: Vec<(u16, u16)> = graph.data.iter().map(|range| (range.start, range.end)).collect();
+        assert_eq!(data, vec![(0x602, 0x604)]);
+        let moved = Graph::build(&Rom::with_start(&rom, Platform::Chip8, 0x200));
+        assert_eq!(moved.unknown.len(), 0);
+        assert_eq!(moved.data.len(), 1);
+        assert_eq!(moved.data[0].start, 0x202);
+    }
+
+    #[test]
+    fn bnnn_is_a_color_zone_on_chip8x() {
+        let rom = [0xB1, 0x23, 0x00, 0xEE];
+        let chip8 = Code::trace(&Rom::new(&rom, Platform::Chip8));
+        assert_eq!(chip8.computed_jumps, vec![0x200]);
+        assert_eq!(chip8.instructions.len(), 1);
+        let chip8x = Code::trace(&Rom::new(&rom, Platform::Chip8X));
+        assert!(chip8x.computed_jumps.is_empty());
+        assert_eq!(chip8x.instructions.len(), 2);
+    }
+}
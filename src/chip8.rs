@@ -1,13 +1,29 @@
 use rand::Rng;
 use rand::rngs::ThreadRng;
-use crate::memory::Memory;
+use crate::coverage::{Access, Coverage};
+use crate::font::Font;
+use crate::memory::{Layout, Memory, VIP_DISPLAY_ADDRESS, VIP_STACK_ADDRESS};
 use crate::display::Display;
-use crate::stack::Stack;
-use crate::keypad::Keypad;
+use crate::stack::{self, Overflow, Stack};
+use crate::keypad::{Keypad, KEYPAD_SIZE};
+use crate::machine::{self, Context, MachineCode};
+use crate::megachip::{Blend, Sample, Screen, MEGA_HEIGHT, MEGA_MEMORY_SIZE, MEGA_WIDTH};
+use crate::quirks::Quirks;
+use crate::database::{self, Program};
+use crate::instruction::{Instruction, Platform, OPCODE_SIZE};
+use crate::profiler::Profiler;
+use crate::timing::{self, Timing};
+use crate::vip::Vip;
 
 const REGISTER_COUNT: usize = 16;
-const START_ADDRESS: usize = 0x200;
-const OPCODE_SIZE: u16 = 2;
+// CHIP-8 HiRes programs start with a jump to 260, where the hi-res
+// interpreter patch sits on COSMAC VIP, the program itself starts at 2C0
+const HIRES_ENTRY: u16 = 0x1260;
+const HIRES_START_ADDRESS: u16 = 0x2c0;
+const HIRES_WIDTH: usize = 64;
+const HIRES_HEIGHT: usize = 64;
+// Clears the 64x64 display, calls a routine of the hi-res interpreter
+const HIRES_CLEAR: u16 = 0x230;
 
 pub struct Chip8 {
     v: [u8; REGISTER_COUNT],
@@ -16,12 +32,54 @@ pub struct Chip8 {
     display: Display,
     rng: ThreadRng,
 
-    index: u16,
+    // 24 bits wide on MEGA-CHIP
+    index: u32,
     // program counter
     pc: u16,
     delay_timer: u8,
     sound_timer: u8,
     keypad: Keypad,
+    quirks: Quirks,
+    // Key pressed during Fx0A, waiting for its release
+    key_wait: Option<u8>,
+    // Loaded ROM as found in the database
+    program: Option<&'static Program>,
+    // Memory access counters, recorded only when enabled
+    coverage: Option<Coverage>,
+    // Execution counters, recorded only when enabled
+    profiler: Option<Profiler>,
+    timing: Timing,
+    // Machine cycles left in the current frame with VIP timing, negative
+    // when an instruction runs over into the next frame
+    cycles: i32,
+    // Emulated COSMAC VIP running the original interpreter instead of
+    // the instructions below
+    vip: Option<Vip>,
+    machine_code: MachineCode,
+    platform: Platform,
+    layout: Layout,
+    // Last value written to the CHIP-8X I/O port by FxF8
+    io_port: u8,
+    // MEGA-CHIP rendering, present while switched on by 0011
+    mega: Option<Screen>,
+    // Digitized sound played by 060n
+    sample: Option<Sample>,
+    // Stack and display are kept in memory where the VIP interpreter has them
+    memory_mapped: bool,
+    // Running a CHIP-8 HiRes program on the 64x64 display
+    hires: bool,
+    // Why execution stopped, set on illegal instructions, failed machine
+    // code calls and stack overflows
+    halted: Option<String>,
+    // Emulation should end rather than show the halted program, set when
+    // the stack overflows in error mode
+    exit: bool,
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Chip8::new()
+    }
 }
 
 impl Chip8 {
@@ -30,86 +88,96 @@ impl Chip8 {
             v: [0; REGISTER_COUNT],
             memory: Memory::new(),
             index: 0,
-            pc: START_ADDRESS as u16,
+            pc: Layout::default().start,
             stack: Stack::new(),
             delay_timer: 0,
             sound_timer: 0,
             keypad: Keypad::new(),
+            quirks: Quirks::default(),
+            key_wait: None,
+            program: None,
+            coverage: None,
+            profiler: None,
+            timing: Timing::Ticks,
+            cycles: timing::FRAME_BUDGET,
+            vip: None,
+            machine_code: MachineCode::Run,
+            platform: Platform::Chip8,
+            layout: Layout::default(),
+            io_port: 0,
+            mega: None,
+            sample: None,
+            memory_mapped: false,
+            hires: false,
+            halted: None,
+            exit: false,
             display: Display::new(),
             rng: rand::thread_rng()
         }
     }
 
-    pub fn load_fontset(&mut self, fontset: &[u8]) {
-        self.memory.load_fontset(fontset);
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
     }
 
-    pub fn load_rom(&mut self, rom: &[u8]) {
-        self.memory.load_rom(rom);
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
     }
 
-    pub fn display_changed(&self) -> bool {
-        self.display.changed()
+    pub fn machine_code(&self) -> MachineCode {
+        self.machine_code
     }
 
-    pub fn draw(&mut self, buf: &mut [u8]) {
-        self.display.draw(buf);
+    // Chooses whether 0nnn runs machine code, is ignored or is an error
+    pub fn set_machine_code(&mut self, machine_code: MachineCode) {
+        self.machine_code = machine_code;
     }
 
-    pub fn tick(&mut self, keypad: Keypad) {
-        self.keypad = keypad;
-        let opcode = self.memory.get_opcode(self.pc);
-        self.pc += OPCODE_SIZE;
-        let nibbles = (
-            (opcode & 0xF000) >> 12 as u8,
-            (opcode & 0x0F00) >> 8 as u8,
-            (opcode & 0x00F0) >> 4 as u8,
-            (opcode & 0x000F) as u8,
-        );
-        let nnn = (opcode & 0x0FFF) as u16;
-        let kk = (opcode & 0x00FF) as u8;
-        let x = nibbles.1 as usize;
-        let y = nibbles.2 as usize;
-        let n = nibbles.3 as u8;
-
-        match nibbles {
-            (0x00, 0x00, 0x0e, 0x00) => self.op_00e0(),
-            (0x00, 0x00, 0x0e, 0x0e) => self.op_00ee(),
-            (0x01, _, _, _) => self.op_1nnn(nnn),
-            (0x02, _, _, _) => self.op_2nnn(nnn),
-            (0x03, _, _, _) => self.op_3xkk(x, kk),
-            (0x04, _, _, _) => self.op_4xkk(x, kk),
-            (0x05, _, _, 0x00) => self.op_5xy0(x, y),
-            (0x06, _, _, _) => self.op_6xkk(x, kk),
-            (0x07, _, _, _) => self.op_7xkk(x, kk),
-            (0x08, _, _, 0x00) => self.op_8xy0(x, y),
-            (0x08, _, _, 0x01) => self.op_8xy1(x, y),
-            (0x08, _, _, 0x02) => self.op_8xy2(x, y),
-            (0x08, _, _, 0x03) => self.op_8xy3(x, y),
-            (0x08, _, _, 0x04) => self.op_8xy4(x, y),
-            (0x08, _, _, 0x05) => self.op_8xy5(x, y),
-            (0x08, _, _, 0x06) => self.op_8x06(x),
-            (0x08, _, _, 0x07) => self.op_8xy7(x, y),
-            (0x08, _, _, 0x0e) => self.op_8x0e(x),
-            (0x09, _, _, 0x00) => self.op_9xy0(x, y),
-            (0x0a, _, _, _) => self.op_annn(nnn),
-            (0x0b, _, _, _) => self.op_bnnn(nnn),
-            (0x0c, _, _, _) => self.op_cxkk(x, kk),
-            (0x0d, _, _, _) => self.op_dxyn(x, y, n),
-            (0x0e, _, 0x09, 0x0e) => self.op_ex9e(x),
-            (0x0e, _, 0x0a, 0x01) => self.op_exa1(x),
-            (0x0f, _, 0x00, 0x07) => self.op_fx07(x),
-            (0x0f, _, 0x00, 0x0a) => self.op_fx0a(x),
-            (0x0f, _, 0x01, 0x05) => self.op_fx15(x),
-            (0x0f, _, 0x01, 0x08) => self.op_fx18(x),
-            (0x0f, _, 0x01, 0x0e) => self.op_fx1e(x),
-            (0x0f, _, 0x02, 0x09) => self.op_fx29(x),
-            (0x0f, _, 0x03, 0x03) => self.op_fx33(x),
-            (0x0f, _, 0x05, 0x05) => self.op_fx55(x),
-            (0x0f, _, 0x06, 0x05) => self.op_fx65(x),
-            _ => panic!("Unknown opcode: {} at {}", opcode, self.pc)
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    // Hands execution over to the emulated COSMAC VIP, which always
+    // runs with VIP timing
+    pub fn enable_vip(&mut self, vip: Vip) {
+        self.vip = Some(vip);
+        self.timing = Timing::Vip;
+    }
+
+    // With VIP timing, tells whether the interpreter has used up the
+    // current frame and waits for the next 60 Hz interrupt
+    pub fn frame_done(&self) -> bool {
+        match &self.vip {
+            Some(vip) => vip.frame_done(),
+            None => self.timing == Timing::Vip && self.cycles <= 0,
+        }
+    }
+
+    // Handles the 60 Hz interrupt with VIP timing: counts timers down and
+    // gives the interpreter cycles for another frame. The emulated VIP
+    // shows its display and CHIP-8 state instead.
+    pub fn start_frame(&mut self) {
+        if let Some(vip) = &mut self.vip {
+            vip.end_frame(&mut self.display);
+            self.v = vip.registers();
+            self.index = vip.index() as u32;
+            self.pc = vip.pc();
+            self.delay_timer = vip.delay_timer();
+            self.sound_timer = vip.sound_timer();
+            return;
         }
+        if self.timing != Timing::Vip {
+            return;
+        }
+        self.cycles = self.cycles.min(0) + timing::FRAME_BUDGET;
+        self.count_down();
+    }
 
+    fn count_down(&mut self) {
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
@@ -119,20 +187,342 @@ impl Chip8 {
         }
     }
 
+    pub fn load_font(&mut self, font: &Font) {
+        self.memory.load_font(font);
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    // Selects the instruction set along with its memory layout, to be
+    // called before `set_layout`, `load_font` and `load_rom`.
+    // MEGA-CHIP programs get 16 MiB of memory.
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+        if platform == Platform::MegaChip {
+            self.memory.resize(MEGA_MEMORY_SIZE);
+        }
+        self.set_layout(Layout::for_platform(platform));
+        self.stack.set_depth(stack::depth_for(platform));
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    // Sets how many nested calls fit, by default as many as the platform allows
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack.set_depth(depth);
+    }
+
+    // Chooses whether stack overflow and underflow stop the emulator,
+    // halt the program or wrap around
+    pub fn set_stack_overflow(&mut self, overflow: Overflow) {
+        self.stack.set_overflow(overflow);
+    }
+
+    pub fn halted(&self) -> Option<&str> {
+        self.halted.as_deref()
+    }
+
+    // Frontends should stop after reporting why the program halted
+    pub fn exit_requested(&self) -> bool {
+        self.exit
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    // Sets where the program starts and the font goes, to be called
+    // before `load_font` and `load_rom`
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+        self.memory.set_font_address(layout.font as usize);
+        self.pc = layout.start;
+    }
+
+    // Loads the ROM and looks it up in the database. Quirks recommended
+    // for it are merged with configured ones by `Config::settings`.
+    // HiRes programs switch the display to 64x64, CHIP-8X programs get
+    // the color layer.
+    pub fn load_rom(&mut self, rom: &[u8]) {
+        self.memory.load_rom_at(rom, self.layout.start as usize);
+        if self.memory.get_opcode(self.layout.start) == HIRES_ENTRY {
+            self.display = Display::with_size(HIRES_WIDTH, HIRES_HEIGHT);
+            self.pc = HIRES_START_ADDRESS;
+            self.hires = true;
+        }
+        if self.platform == Platform::Chip8X {
+            self.display.enable_colors();
+        }
+        self.program = database::find(rom);
+    }
+
+    pub fn program(&self) -> Option<&'static Program> {
+        self.program
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    // Keeps the call stack at EA0 and mirrors the display at F00, so
+    // programs writing there see it on screen and in the return stack.
+    // Displays too big to fit there are not mirrored, which is reported
+    // after mapping the stack
+    pub fn enable_memory_map(&mut self) -> Result<(), String> {
+        self.memory_mapped = true;
+        self.stack.map(VIP_STACK_ADDRESS);
+        if self.display_address().is_none() {
+            return Err(format!(
+                "The {}x{} display does not fit in memory at {:03X}, it is not mirrored there",
+                self.display.width(), self.display.height(), VIP_DISPLAY_ADDRESS,
+            ));
+        }
+        self.store_display();
+        Ok(())
+    }
+
+    pub fn memory_mapped(&self) -> bool {
+        self.memory_mapped
+    }
+
+    // Where the display is mirrored, if it fits below the end of memory
+    fn display_address(&self) -> Option<usize> {
+        let fits = VIP_DISPLAY_ADDRESS + self.display.memory_size() <= self.memory.size();
+        (self.memory_mapped && self.mega.is_none() && fits).then_some(VIP_DISPLAY_ADDRESS)
+    }
+
+    fn store_display(&mut self) {
+        if let Some(address) = self.display_address() {
+            self.display.store(&mut self.memory, address);
+        }
+    }
+
+    fn load_display(&mut self) {
+        if let Some(address) = self.display_address() {
+            self.display.load(&self.memory, address);
+        }
+    }
+
+    // Starts recording which memory is executed, read and written, to be
+    // called after `set_platform`, which sizes the memory
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::with_size(self.memory.size()));
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    fn cover(&mut self, access: Access, address: u32, len: usize) {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(access, address as usize, len);
+        }
+    }
+
+    // Starts counting executed instructions and time spent in subroutines
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::with_entry(self.layout.start));
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn io_port(&self) -> u8 {
+        self.io_port
+    }
+
+    // Sample being played, for frontends with sound output
+    pub fn sample(&self) -> Option<&Sample> {
+        self.sample.as_ref()
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    // Runs one instruction. Key transitions are collected over ticks which
+    // run nothing, as when waiting for the next frame with VIP timing, so
+    // the next instruction still sees them.
+    pub fn tick(&mut self, keypad: Keypad) {
+        self.keypad.update(&keypad);
+        if self.halted.is_some() || self.frame_done() {
+            return;
+        }
+        // The emulated VIP runs a whole frame at once
+        if let Some(vip) = &mut self.vip {
+            vip.run_frame(&self.keypad);
+            self.keypad.clear_edges();
+            return;
+        }
+        let opcode = self.memory.get_opcode(self.pc);
+        self.cover(Access::Execute, self.pc as u32, OPCODE_SIZE as usize);
+        let instruction = Instruction::decode_for(opcode, self.platform);
+        if let (Some(profiler), Some(instruction)) = (&mut self.profiler, instruction) {
+            profiler.record(self.pc, instruction);
+        }
+        let cycles = instruction.map_or(0, |instruction| timing::cycles(instruction, &self.v));
+        self.pc += OPCODE_SIZE;
+
+        match instruction {
+            Some(Instruction::Sys(nnn)) => self.op_0nnn(nnn),
+            Some(Instruction::Cls) => self.op_00e0(),
+            Some(Instruction::Ret) => self.op_00ee(),
+            Some(Instruction::Jp(nnn)) => self.op_1nnn(nnn),
+            Some(Instruction::Call(nnn)) => self.op_2nnn(nnn),
+            Some(Instruction::SeByte(x, kk)) => self.op_3xkk(x, kk),
+            Some(Instruction::SneByte(x, kk)) => self.op_4xkk(x, kk),
+            Some(Instruction::Se(x, y)) => self.op_5xy0(x, y),
+            Some(Instruction::LdByte(x, kk)) => self.op_6xkk(x, kk),
+            Some(Instruction::AddByte(x, kk)) => self.op_7xkk(x, kk),
+            Some(Instruction::Ld(x, y)) => self.op_8xy0(x, y),
+            Some(Instruction::Or(x, y)) => self.op_8xy1(x, y),
+            Some(Instruction::And(x, y)) => self.op_8xy2(x, y),
+            Some(Instruction::Xor(x, y)) => self.op_8xy3(x, y),
+            Some(Instruction::Add(x, y)) => self.op_8xy4(x, y),
+            Some(Instruction::Sub(x, y)) => self.op_8xy5(x, y),
+            Some(Instruction::Shr(x, y)) => self.op_8xy6(x, y),
+            Some(Instruction::Subn(x, y)) => self.op_8xy7(x, y),
+            Some(Instruction::Shl(x, y)) => self.op_8xye(x, y),
+            Some(Instruction::Sne(x, y)) => self.op_9xy0(x, y),
+            Some(Instruction::LdI(nnn)) => self.op_annn(nnn),
+            Some(Instruction::JpV0(x, nnn)) => self.op_bnnn(x, nnn),
+            Some(Instruction::Rnd(x, kk)) => self.op_cxkk(x, kk),
+            Some(Instruction::Drw(x, y, n)) => self.op_dxyn(x, y, n),
+            Some(Instruction::Skp(x)) => self.op_ex9e(x),
+            Some(Instruction::Sknp(x)) => self.op_exa1(x),
+            Some(Instruction::LdVxDt(x)) => self.op_fx07(x),
+            Some(Instruction::LdVxK(x)) => self.op_fx0a(x),
+            Some(Instruction::LdDtVx(x)) => self.op_fx15(x),
+            Some(Instruction::LdStVx(x)) => self.op_fx18(x),
+            Some(Instruction::AddIVx(x)) => self.op_fx1e(x),
+            Some(Instruction::LdFVx(x)) => self.op_fx29(x),
+            Some(Instruction::LdHfVx(x)) => self.op_fx30(x),
+            Some(Instruction::LdBVx(x)) => self.op_fx33(x),
+            Some(Instruction::LdIVx(x)) => self.op_fx55(x),
+            Some(Instruction::LdVxI(x)) => self.op_fx65(x),
+            Some(Instruction::Bgcol) => self.op_02a0(),
+            Some(Instruction::AddNibble(x, y)) => self.op_5xy1(x, y),
+            Some(Instruction::Col(x, y, n)) => self.op_bxyn(x, y, n),
+            Some(Instruction::SkpPad2(x)) => self.op_exf2(x),
+            Some(Instruction::SknpPad2(x)) => self.op_exf5(x),
+            Some(Instruction::Out(x)) => self.op_fxf8(x),
+            Some(Instruction::In(x)) => self.op_fxfb(x),
+            Some(Instruction::MegaOff) => self.op_0010(),
+            Some(Instruction::MegaOn) => self.op_0011(),
+            Some(Instruction::LdIHigh(nn)) => self.op_01nn(nn),
+            Some(Instruction::LdPal(nn)) => self.op_02nn(nn),
+            Some(Instruction::Sprw(nn)) => self.op_03nn(nn),
+            Some(Instruction::Sprh(nn)) => self.op_04nn(nn),
+            Some(Instruction::Alpha(nn)) => self.op_05nn(nn),
+            Some(Instruction::Digisnd(n)) => self.op_060n(n),
+            Some(Instruction::Stopsnd) => self.op_0700(),
+            Some(Instruction::Bmode(n)) => self.op_080n(n),
+            Some(Instruction::Ccol(nn)) => self.op_09nn(nn),
+            _ => {
+                self.pc -= OPCODE_SIZE;
+                return self.halt(format!("Illegal instruction {:04X} at {:03X}", opcode, self.pc));
+            }
+        }
+
+        self.keypad.clear_edges();
+
+        match self.timing {
+            Timing::Ticks => self.count_down(),
+            Timing::Vip => {
+                // Dxyn waits for the interrupt and draws in the next frame
+                if let Some(Instruction::Drw(..)) = instruction {
+                    self.cycles = self.cycles.min(0);
+                }
+                self.cycles -= cycles as i32;
+            }
+        }
+    }
+
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
     pub fn sound_timer(&self) -> u8 {
         self.sound_timer
     }
 
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn registers(&self) -> &[u8; REGISTER_COUNT] {
+        &self.v
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // SYS addr
+    // Call CDP1802 machine code subroutine at nnn. HiRes programs clear
+    // their 64x64 display with 0230, which is not left to machine code
+    // as the display it sees is only 64x32.
+    fn op_0nnn(&mut self, nnn: u16) {
+        if self.hires && nnn == HIRES_CLEAR {
+            return self.op_00e0();
+        }
+        match self.machine_code {
+            MachineCode::Run => {
+                let context = Context {
+                    memory: &mut self.memory,
+                    v: &mut self.v,
+                    display: &mut self.display,
+                    keypad: &self.keypad,
+                    index: &mut self.index,
+                    pc: &mut self.pc,
+                    delay_timer: &mut self.delay_timer,
+                    sound_timer: &mut self.sound_timer,
+                };
+                match machine::call(nnn, context) {
+                    Ok(cycles) if self.timing == Timing::Vip => self.cycles -= cycles as i32,
+                    Ok(_) => {}
+                    Err(e) => {
+                        self.pc -= OPCODE_SIZE;
+                        self.halt(e);
+                    }
+                }
+                self.store_display();
+            }
+            MachineCode::Nop => {}
+            MachineCode::Error => {
+                self.pc -= OPCODE_SIZE;
+                self.halt(format!("Machine code call {:03X} at {:03X}", nnn, self.pc));
+            }
+        }
+    }
+
     // CLS
-    // Clear screen
+    // Clear screen, MEGA-CHIP shows what was drawn since the last CLS
+    // and clears for the next frame instead
     fn op_00e0(&mut self) {
-        self.display.clear();
+        match &mut self.mega {
+            Some(screen) => screen.present(&mut self.display),
+            None => self.display.clear(),
+        }
+        self.store_display();
     }
 
     // RET
     // Return from subroutine
     fn op_00ee(&mut self) {
-        self.pc = self.stack.pop();
+        match self.stack.pop(&self.memory) {
+            Ok(address) => self.pc = address,
+            Err(e) => self.stack_error(e),
+        }
     }
 
     // JMP addr
@@ -144,8 +534,23 @@ impl Chip8 {
     // CALL addr
     // Call subroutine at nnn
     fn op_2nnn(&mut self, nnn: u16) {
-        self.stack.push(self.pc);
-        self.pc = nnn;
+        match self.stack.push(&mut self.memory, self.pc) {
+            Ok(()) => self.pc = nnn,
+            Err(e) => self.stack_error(e),
+        }
+    }
+
+    // Stops at the instruction which overflowed or underflowed the stack
+    fn stack_error(&mut self, message: String) {
+        self.pc -= OPCODE_SIZE;
+        self.exit = self.stack.overflow() == Overflow::Error;
+        self.halt(format!("{} at {:03X}", message, self.pc));
+    }
+
+    // Stops executing the program, frontends keep showing its display
+    // along with the message
+    fn halt(&mut self, message: String) {
+        self.halted = Some(message);
     }
 
     // SE Vx, byte
@@ -194,18 +599,28 @@ impl Chip8 {
     // Set Vx = Vx OR Vy
     fn op_8xy1(&mut self, x: usize, y: usize) {
         self.v[x] |= self.v[y];
+        self.reset_vf();
     }
 
     // AND Vx, Vy
     // Set Vx = Vx AND Vy
     fn op_8xy2(&mut self, x: usize, y: usize) {
         self.v[x] &= self.v[y];
+        self.reset_vf();
     }
 
     // XOR Vx, Vy
     // Set Vx = Vx XOR Vy
     fn op_8xy3(&mut self, x: usize, y: usize) {
         self.v[x] ^= self.v[y];
+        self.reset_vf();
+    }
+
+    // Logic instructions clobber VF on COSMAC VIP
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xf] = 0;
+        }
     }
 
     // ADD Vx, Vy
@@ -230,14 +645,15 @@ impl Chip8 {
         self.v[x] = self.v[x].wrapping_sub(self.v[y]);
     }
 
-    // SHR Vx
+    // SHR Vx {, Vy}
     // Set Vx = Vx SHR 1.
     //
     // If the least-significant bit of Vx is 1, then VF is set to 1, otherwise 0.
-    // Then Vx is divided by 2.
-    fn op_8x06(&mut self, x: usize) {
-        self.v[0xf] = self.v[x] & 0x1;
-        self.v[x] >>= 1;
+    // Then Vx is divided by 2. Without shift quirk Vy is shifted into Vx.
+    fn op_8xy6(&mut self, x: usize, y: usize) {
+        let value = if self.quirks.shift { self.v[x] } else { self.v[y] };
+        self.v[x] = value >> 1;
+        self.v[0xf] = value & 0x1;
     }
 
     // SUBN Vx, Vy
@@ -254,11 +670,12 @@ impl Chip8 {
     // Set Vx = Vx SHL 1.
     //
     // If the most-significant bit of Vx is 1, then VF is set to 1, otherwise to 0.
-    // Then Vx is multiplied by 2.
-    fn op_8x0e(&mut self, x: usize) {
+    // Then Vx is multiplied by 2. Without shift quirk Vy is shifted into Vx.
+    fn op_8xye(&mut self, x: usize, y: usize) {
+        let value = if self.quirks.shift { self.v[x] } else { self.v[y] };
+        self.v[x] = value << 1;
         // Save MSB in VF
-        self.v[0x0f] = (self.v[x] & 0x80) >> 7;
-        self.v[x] <<= 1;
+        self.v[0x0f] = (value & 0x80) >> 7;
     }
 
     // SNE Vx, Vy
@@ -272,13 +689,14 @@ impl Chip8 {
     // LD I, addr
     // Set I = nnn.
     fn op_annn(&mut self, nnn: u16) {
-        self.index = nnn;
+        self.index = nnn as u32;
     }
 
     // JP V0, addr
-    // Jump to location nnn + V0.
-    fn op_bnnn(&mut self, nnn: u16) {
-        self.pc = self.v[0] as u16 + nnn;
+    // Jump to location nnn + V0, or xnn + Vx with jump quirk.
+    fn op_bnnn(&mut self, x: usize, nnn: u16) {
+        let offset = if self.quirks.jump { self.v[x] } else { self.v[0] };
+        self.pc = offset as u16 + nnn;
     }
 
     // RND Vx, byte
@@ -294,15 +712,27 @@ impl Chip8 {
     // If this causes any pixels to be erased, VF is set to 1, otherwise
     // it is set to 0. If the sprite is positioned so part of it is outside
     // the coordinates of the display, it wraps around to the opposite side
-    // of the screen.
+    // of the screen, or is clipped with clip quirk.
     fn op_dxyn(&mut self, x: usize, y: usize, n: u8) {
+        if self.mega.is_some() {
+            return self.draw_mega(x, y, n);
+        }
+        self.cover(Access::Read, self.index, n as usize);
         self.v[0xf] = 0;
+        let (width, height) = (self.display.width(), self.display.height());
+        let (x0, y0) = (self.v[x] as usize % width, self.v[y] as usize % height);
 
         for byte in 0..n as usize {
-            let y_pos = (self.v[y] as usize + byte) % self.display.height();
+            if self.quirks.clip && y0 + byte >= height {
+                break;
+            }
+            let y_pos = (y0 + byte) % height;
             let sprite_byte = self.memory.peek(self.index as usize + byte);
             for bit in 0..=7 {
-                let x_pos = (self.v[x] as usize + bit) % self.display.width();
+                if self.quirks.clip && x0 + bit >= width {
+                    break;
+                }
+                let x_pos = (x0 + bit) % width;
 
                 let sprite_pixel = (sprite_byte >> (7 - bit)) & 1;
                 let screen_pixel = self.display.peek(x_pos, y_pos);
@@ -310,6 +740,7 @@ impl Chip8 {
                 self.display.poke(x_pos, y_pos, screen_pixel ^ sprite_pixel);
             }
         }
+        self.store_display();
     }
 
     // SKP Vx
@@ -338,12 +769,26 @@ impl Chip8 {
 
     // LD Vx, K
     // Wait for a key press, store the value of the key in Vx.
+    //
+    // With key_release quirk the instruction completes only when the key
+    // is released, as on COSMAC VIP, so one press is never read twice.
     fn op_fx0a(&mut self, x: usize) {
-        for k in 0..=0xF as u8 {
-            if self.keypad.pressed(k as usize) {
+        let key = match self.key_wait {
+            Some(k) if self.keypad.just_released(k as usize) => Some(k),
+            Some(_) => None,
+            // Keys tapped between two instructions count as well
+            None => (0..=0xFu8).find(|k| self.keypad.pressed(*k as usize) || self.keypad.just_pressed(*k as usize)),
+        };
+        match key {
+            Some(k) if self.quirks.key_release && self.key_wait.is_none() && self.keypad.pressed(k as usize) => {
+                self.key_wait = Some(k);
+            }
+            Some(k) => {
                 self.v[x] = k;
+                self.key_wait = None;
                 return;
             }
+            None => {}
         }
         self.pc -= OPCODE_SIZE;
     }
@@ -363,14 +808,20 @@ impl Chip8 {
     // ADD I, Vx
     // Set I = I + Vx.
     fn op_fx1e(&mut self, x: usize) {
-        self.index += self.v[x] as u16;
+        self.index += self.v[x] as u32;
         self.v[0x0f] = if self.index > 255 { 1 } else { 0 };
     }
 
     // LD F, Vx
-    // Set I = location of sprite for digit Vx.
+    // Set I = location of sprite for digit Vx, only its low nibble counts.
     fn op_fx29(&mut self, x: usize) {
-        self.index = self.memory.get_char_addr(x);
+        self.index = self.memory.get_char_addr((self.v[x] & 0xf) as usize) as u32;
+    }
+
+    // LD HF, Vx
+    // Set I = location of the large 8x10 sprite for digit Vx (SCHIP).
+    fn op_fx30(&mut self, x: usize) {
+        self.index = self.memory.get_big_char_addr((self.v[x] & 0xf) as usize) as u32;
     }
 
     // LD B, Vx
@@ -380,6 +831,7 @@ impl Chip8 {
     // digit in memory at location in I, the tens digit at location I+1, and
     // the ones digit at location I+2.
     fn op_fx33(&mut self, x: usize) {
+        self.cover(Access::Write, self.index, 3);
         let mut value = self.v[x];
         let address: usize = self.index as usize;
         // Ones-place
@@ -390,21 +842,594 @@ impl Chip8 {
         value /= 10;
         // Hundreds-place
         self.memory.poke(address, value % 10);
+        self.load_display();
     }
 
     // LD [I], Vx
     // Store registers V0 through Vx in memory starting at location I.
+    // Without load_store quirk I is left pointing past the stored registers.
     fn op_fx55(&mut self, x: usize) {
-        for i in 0..=x as u16 {
-            self.memory.poke((self.index + i) as usize, self.v[i as usize]);
+        self.cover(Access::Write, self.index, x + 1);
+        for i in 0..=x {
+            self.memory.poke(self.index as usize + i, self.v[i]);
+        }
+        self.load_display();
+        if !self.quirks.load_store {
+            self.index += x as u32 + 1;
         }
     }
 
     // LD Vx, [I]
     // Read registers V0 through Vx from memory starting at location I.
+    // Without load_store quirk I is left pointing past the loaded registers.
     fn op_fx65(&mut self, x: usize) {
-        for i in 0..=x as u16 {
-            self.v[i as usize] = self.memory.peek((self.index + i) as usize);
+        self.cover(Access::Read, self.index, x + 1);
+        for i in 0..=x {
+            self.v[i] = self.memory.peek(self.index as usize + i);
+        }
+        if !self.quirks.load_store {
+            self.index += x as u32 + 1;
+        }
+    }
+
+    // BGCOL
+    // Switch to the next background color (CHIP-8X)
+    fn op_02a0(&mut self) {
+        self.display.next_background();
+    }
+
+    // ADDN Vx, Vy
+    // Add Vy to Vx nibble by nibble, carry is dropped (CHIP-8X)
+    fn op_5xy1(&mut self, x: usize, y: usize) {
+        let high = (self.v[x] & 0xf0).wrapping_add(self.v[y] & 0xf0);
+        let low = (self.v[x] & 0x0f) + (self.v[y] & 0x0f);
+        self.v[x] = high & 0xf0 | low & 0x0f;
+    }
+
+    // COL Vx, Vy, n
+    // Set foreground color to Vy for zones given by Vx and Vx+1: low
+    // nibbles select the first zone, high nibbles add zones to the right
+    // and below. Zones are 8x4 pixels, with n > 0 they are 8 pixels wide
+    // and n rows starting at row Vx+1 are colored instead (CHIP-8X).
+    fn op_bxyn(&mut self, x: usize, y: usize, n: u8) {
+        let (horizontal, vertical) = (self.v[x], self.v[(x + 1) % 16]);
+        let left = (horizontal & 0xf) as usize * 8;
+        let right = left + ((horizontal >> 4) as usize + 1) * 8;
+        let (top, bottom) = if n == 0 {
+            let top = (vertical & 0xf) as usize * 4;
+            (top, top + ((vertical >> 4) as usize + 1) * 4)
+        } else {
+            (vertical as usize, vertical as usize + n as usize)
+        };
+        let (width, height) = (self.display.width(), self.display.height());
+        for row in top..bottom.min(height) {
+            for column in left..right.min(width) {
+                self.display.set_color(column, row, self.v[y] & 7);
+            }
+        }
+    }
+
+    // SKP2 Vx
+    // Skip next instruction if key Vx on the second keypad is pressed (CHIP-8X)
+    fn op_exf2(&mut self, x: usize) {
+        if self.keypad.pressed(KEYPAD_SIZE + (self.v[x] & 0xf) as usize) {
+            self.pc += OPCODE_SIZE;
+        }
+    }
+
+    // SKNP2 Vx
+    // Skip next instruction if key Vx on the second keypad is not pressed (CHIP-8X)
+    fn op_exf5(&mut self, x: usize) {
+        if !self.keypad.pressed(KEYPAD_SIZE + (self.v[x] & 0xf) as usize) {
+            self.pc += OPCODE_SIZE;
+        }
+    }
+
+    // OUT Vx
+    // Write Vx to the I/O port, sets tone of the VP-595 sound board (CHIP-8X)
+    fn op_fxf8(&mut self, x: usize) {
+        self.io_port = self.v[x];
+    }
+
+    // IN Vx
+    // Read the I/O port into Vx, no device answers so it reads back the
+    // last value written (CHIP-8X)
+    fn op_fxfb(&mut self, x: usize) {
+        self.v[x] = self.io_port;
+    }
+
+    // MEGAOFF
+    // Switch back to the 64x32 display (MEGA-CHIP)
+    fn op_0010(&mut self) {
+        self.mega = None;
+        self.display = Display::new();
+    }
+
+    // MEGAON
+    // Switch to the 256x192 color display (MEGA-CHIP)
+    fn op_0011(&mut self) {
+        self.mega = Some(Screen::new());
+        self.display = Display::with_size(MEGA_WIDTH, MEGA_HEIGHT);
+        self.display.enable_rgba();
+    }
+
+    // LDHI I, nnnnnn
+    // Set I = nn with the following word as its low 16 bits (MEGA-CHIP)
+    fn op_01nn(&mut self, nn: u8) {
+        self.cover(Access::Execute, self.pc as u32, OPCODE_SIZE as usize);
+        self.index = (nn as u32) << 16 | self.memory.get_opcode(self.pc) as u32;
+        self.pc += OPCODE_SIZE;
+    }
+
+    // LDPAL nn
+    // Load nn ARGB colors from I as palette entries 1 to nn (MEGA-CHIP)
+    fn op_02nn(&mut self, nn: u8) {
+        self.cover(Access::Read, self.index, nn as usize * 4);
+        if let Some(screen) = &mut self.mega {
+            screen.load_palette(&self.memory, self.index as usize, nn as usize);
+        }
+    }
+
+    // SPRW nn
+    // Set sprite width to nn, 0 means 256 (MEGA-CHIP)
+    fn op_03nn(&mut self, nn: u8) {
+        if let Some(screen) = &mut self.mega {
+            screen.set_sprite_width(nn);
+        }
+    }
+
+    // SPRH nn
+    // Set sprite height to nn, 0 means 256 (MEGA-CHIP)
+    fn op_04nn(&mut self, nn: u8) {
+        if let Some(screen) = &mut self.mega {
+            screen.set_sprite_height(nn);
+        }
+    }
+
+    // ALPHA nn
+    // Set brightness of the whole screen, used for fades (MEGA-CHIP)
+    fn op_05nn(&mut self, nn: u8) {
+        if let Some(screen) = &mut self.mega {
+            screen.set_alpha(nn);
+        }
+    }
+
+    // DIGISND n
+    // Play the sample at I, in a loop when n is 0 (MEGA-CHIP)
+    fn op_060n(&mut self, n: u8) {
+        self.sample = Some(Sample::load(&self.memory, self.index as usize, n == 0));
+    }
+
+    // STOPSND
+    // Stop the sample (MEGA-CHIP)
+    fn op_0700(&mut self) {
+        self.sample = None;
+    }
+
+    // BMODE n
+    // Set how sprites blend: normal, 25, 50 or 75% opaque, additive or
+    // multiplicative (MEGA-CHIP)
+    fn op_080n(&mut self, n: u8) {
+        if let Some(screen) = &mut self.mega {
+            screen.set_blend(Blend::from_mode(n));
+        }
+    }
+
+    // CCOL nn
+    // Set the palette entry sprites collide with (MEGA-CHIP)
+    fn op_09nn(&mut self, nn: u8) {
+        if let Some(screen) = &mut self.mega {
+            screen.set_collision(nn);
+        }
+    }
+
+    // DRW Vx, Vy in MEGA-CHIP mode
+    // Draw a sprite of palette indices sized by SPRW and SPRH, or n rows
+    // of a 1-bit font sprite when I points into the font. VF is set when
+    // a pixel of the collision color is drawn over.
+    fn draw_mega(&mut self, x: usize, y: usize, n: u8) {
+        let (x0, y0) = (self.v[x] as usize, self.v[y] as usize);
+        let address = self.index as usize;
+        let screen = self.mega.as_mut().expect("MEGA-CHIP mode is on");
+        let collision = if address < self.layout.start as usize {
+            screen.draw_font(&self.memory, address, x0, y0, n as usize)
+        } else {
+            screen.draw(&self.memory, address, x0, y0)
+        };
+        self.v[0xf] = collision as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::CHAR_SIZE;
+    use crate::memory::FONTSET_START_ADDRESS;
+    use crate::palette::{Color, Palette, VP590_BACKGROUNDS, VP590_FOREGROUNDS};
+
+    fn keys(pressed: &[usize]) -> Keypad {
+        let mut keypad = Keypad::new();
+        for &key in pressed {
+            keypad.press(key);
+        }
+        keypad
+    }
+
+    // Runs LD V0, K with VIP timing until the frame has no cycles left
+    fn waiting_for_frame(quirks: Quirks, held: &[usize]) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.set_quirks(quirks);
+        chip8.set_timing(Timing::Vip);
+        chip8.load_rom(&[0xF0, 0x0A, 0x12, 0x02]);
+        chip8.start_frame();
+        while !chip8.frame_done() {
+            chip8.tick(keys(held));
         }
+        chip8
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn keys_tapped_between_frames_are_seen() {
+        let mut chip8 = waiting_for_frame(Quirks::default(), &[]);
+        chip8.tick(keys(&[7]));
+        chip8.tick(keys(&[]));
+        assert_eq!(chip8.pc(), 0x200);
+        chip8.start_frame();
+        chip8.tick(keys(&[]));
+        assert_eq!(chip8.registers()[0], 7);
+        assert_eq!(chip8.pc(), 0x202);
+    }
+
+    #[test]
+    fn releases_between_frames_end_the_wait() {
+        let quirks = Quirks { key_release: true, ..Quirks::default() };
+        let mut chip8 = waiting_for_frame(quirks, &[9]);
+        assert_eq!(chip8.pc(), 0x200);
+        chip8.tick(keys(&[]));
+        chip8.start_frame();
+        chip8.tick(keys(&[]));
+        assert_eq!(chip8.registers()[0], 9);
+        assert_eq!(chip8.pc(), 0x202);
+    }
+
+    #[test]
+    fn fx29_points_at_the_digit_in_vx() {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&[0x6A, 0x0B, 0xFA, 0x29, 0x61, 0x3C, 0xF1, 0x29]);
+        chip8.tick(Keypad::new());
+        chip8.tick(Keypad::new());
+        assert_eq!(chip8.index(), (FONTSET_START_ADDRESS + 0xB * CHAR_SIZE) as u32);
+        chip8.tick(Keypad::new());
+        chip8.tick(Keypad::new());
+        assert_eq!(chip8.index(), (FONTSET_START_ADDRESS + 0xC * CHAR_SIZE) as u32);
+    }
+
+    #[test]
+    fn vip_frames_run_out_of_cycles_and_carry_the_overrun() {
+        let mut chip8 = Chip8::new();
+        chip8.set_timing(Timing::Vip);
+        // JP 200, 80 cycles each
+        chip8.load_rom(&[0x12, 0x00]);
+        let mut ticks = 0;
+        while !chip8.frame_done() {
+            chip8.tick(Keypad::new());
+            ticks += 1;
+        }
+        assert_eq!(ticks, 23);
+        assert_eq!(chip8.cycles, timing::FRAME_BUDGET - 23 * 80);
+        chip8.start_frame();
+        assert_eq!(chip8.cycles, 2 * timing::FRAME_BUDGET - 23 * 80);
+    }
+
+    #[test]
+    fn vip_timers_count_per_frame_and_dxyn_ends_it() {
+        let mut chip8 = Chip8::new();
+        chip8.set_timing(Timing::Vip);
+        // LD V0, 5; LD DT, V0; DRW V0, V0, 1; JP 206
+        chip8.load_rom(&[0x60, 0x05, 0xF0, 0x15, 0xD0, 0x01, 0x12, 0x06]);
+        for _ in 0..3 {
+            chip8.tick(Keypad::new());
+        }
+        assert!(chip8.frame_done());
+        assert_eq!(chip8.delay_timer(), 5);
+        chip8.tick(Keypad::new());
+        assert_eq!(chip8.pc(), 0x206);
+        chip8.start_frame();
+        assert_eq!(chip8.delay_timer(), 4);
+    }
+
+    // SYS 300 followed by a CDP1802 routine at 300 setting V0
+    fn machine_code_rom(routine: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0x03, 0x00, 0x12, 0x02];
+        rom.resize(0x100, 0);
+        rom.extend_from_slice(routine);
+        rom
+    }
+
+    #[test]
+    fn machine_code_modes() {
+        // LDI 0E; PHI R6; LDI F0; PLO R6; LDI 42; STR R6; SEP R4
+        let rom = machine_code_rom(&[0xF8, 0x0E, 0xB6, 0xF8, 0xF0, 0xA6, 0xF8, 0x42, 0x56, 0xD4]);
+        let run = |mode| {
+            let mut chip8 = Chip8::new();
+            chip8.set_machine_code(mode);
+            chip8.load_rom(&rom);
+            chip8.tick(Keypad::new());
+            chip8
+        };
+        let chip8 = run(MachineCode::Run);
+        assert_eq!(chip8.registers()[0], 0x42);
+        assert_eq!(chip8.pc(), 0x202);
+        let chip8 = run(MachineCode::Nop);
+        assert_eq!(chip8.registers()[0], 0);
+        assert_eq!(chip8.pc(), 0x202);
+        let chip8 = run(MachineCode::Error);
+        assert_eq!(chip8.halted(), Some("Machine code call 300 at 200"));
+        assert_eq!(chip8.pc(), 0x200);
+    }
+
+    #[test]
+    fn stuck_machine_code_halts() {
+        let mut chip8 = Chip8::new();
+        chip8.set_machine_code(MachineCode::Run);
+        // BR 00
+        chip8.load_rom(&machine_code_rom(&[0x30, 0x00]));
+        chip8.tick(Keypad::new());
+        assert_eq!(chip8.halted(), Some("Machine code at 300 did not return, stuck at 0300"));
+        assert_eq!(chip8.pc(), 0x200);
+        assert!(!chip8.exit_requested());
+        chip8.tick(Keypad::new());
+        assert_eq!(chip8.pc(), 0x200);
+    }
+
+    // Jumps to the HiRes interpreter patch, the program at 2C0 draws digit
+    // 0 at row 40 and clears the screen with 0230
+    fn hires_rom() -> Vec<u8> {
+        let mut rom = vec![0x12, 0x60];
+        rom.resize(0xC0, 0);
+        rom.extend_from_slice(&[0x61, 0x28, 0xF0, 0x29, 0xD0, 0x15, 0x02, 0x30, 0x12, 0xC8]);
+        rom
+    }
+
+    fn lit(display: &Display) -> usize {
+        (0..display.height())
+            .flat_map(|y| (0..display.width()).map(move |x| (x, y)))
+            .filter(|&(x, y)| display.peek(x, y) == 1)
+            .count()
+    }
+
+    #[test]
+    fn hires_programs_get_a_64x64_display() {
+        let mut chip8 = Chip8::new();
+        chip8.load_font(&Font::default());
+        chip8.load_rom(&hires_rom());
+        assert_eq!((chip8.display().width(), chip8.display().height()), (64, 64));
+        assert_eq!(chip8.pc(), 0x2C0);
+        for _ in 0..3 {
+            chip8.tick(Keypad::new());
+        }
+        assert!(lit(chip8.display()) > 0);
+        chip8.tick(Keypad::new());
+        assert_eq!(lit(chip8.display()), 0);
+        assert_eq!(chip8.pc(), 0x2C8);
+    }
+
+    #[test]
+    fn other_programs_keep_the_display_and_0230() {
+        let mut chip8 = Chip8::new();
+        chip8.set_machine_code(MachineCode::Nop);
+        chip8.load_font(&Font::default());
+        // LD V1, 8; LD F, V0; DRW V0, V1, 5; SYS 230
+        chip8.load_rom(&[0x61, 0x08, 0xF0, 0x29, 0xD0, 0x15, 0x02, 0x30]);
+        assert_eq!((chip8.display().width(), chip8.display().height()), (64, 32));
+        assert_eq!(chip8.pc(), 0x200);
+        for _ in 0..4 {
+            chip8.tick(Keypad::new());
+        }
+        assert!(lit(chip8.display()) > 0);
+    }
+
+    #[test]
+    fn chip8x_colors_cover_the_hires_display() {
+        let mut chip8 = Chip8::new();
+        chip8.set_platform(Platform::Chip8X);
+        chip8.load_rom(&hires_rom());
+        assert_eq!(chip8.display().height(), 64);
+        assert!(chip8.display().colored());
+    }
+
+    fn chip8x(rom: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.set_platform(Platform::Chip8X);
+        chip8.load_rom(rom);
+        chip8
+    }
+
+    fn run(chip8: &mut Chip8, ticks: usize, keypad: Keypad) {
+        for _ in 0..ticks {
+            chip8.tick(keypad);
+        }
+    }
+
+    fn foreground(chip8: &Chip8, x: usize, y: usize) -> Color {
+        chip8.display().palette(x, y, &Palette::default()).foreground
+    }
+
+    #[test]
+    fn chip8x_adds_registers_nibble_by_nibble() {
+        // LD V0, 9F; LD V1, 93; ADDN V0, V1
+        let mut chip8 = chip8x(&[0x60, 0x9F, 0x61, 0x93, 0x50, 0x11]);
+        assert_eq!(chip8.pc(), 0x300);
+        run(&mut chip8, 3, Keypad::new());
+        assert_eq!(chip8.registers()[0], 0x22);
+        assert_eq!(chip8.registers()[0xF], 0);
+    }
+
+    #[test]
+    fn chip8x_colors_zones_and_rows() {
+        // LD V0, 11; LD V1, 12; LD V2, 5; COL V0, V2, 0: zones 1-2 across, 2-3 down
+        let mut chip8 = chip8x(&[0x60, 0x11, 0x61, 0x12, 0x62, 0x05, 0xB0, 0x20]);
+        run(&mut chip8, 4, Keypad::new());
+        assert_eq!(foreground(&chip8, 8, 8), VP590_FOREGROUNDS[5]);
+        assert_eq!(foreground(&chip8, 23, 15), VP590_FOREGROUNDS[5]);
+        assert_eq!(foreground(&chip8, 24, 8), VP590_FOREGROUNDS[1]);
+        assert_eq!(foreground(&chip8, 8, 16), VP590_FOREGROUNDS[1]);
+        assert_eq!(foreground(&chip8, 7, 8), VP590_FOREGROUNDS[1]);
+        // LD V0, 00; LD V1, 03; LD V2, 2; COL V0, V2, 2: rows 3 and 4
+        let mut chip8 = chip8x(&[0x60, 0x00, 0x61, 0x03, 0x62, 0x02, 0xB0, 0x22]);
+        run(&mut chip8, 4, Keypad::new());
+        assert_eq!(foreground(&chip8, 0, 2), VP590_FOREGROUNDS[1]);
+        assert_eq!(foreground(&chip8, 7, 3), VP590_FOREGROUNDS[2]);
+        assert_eq!(foreground(&chip8, 7, 4), VP590_FOREGROUNDS[2]);
+        assert_eq!(foreground(&chip8, 7, 5), VP590_FOREGROUNDS[1]);
+        assert_eq!(foreground(&chip8, 8, 3), VP590_FOREGROUNDS[1]);
+    }
+
+    #[test]
+    fn chip8x_cycles_the_background() {
+        // BGCOL; BGCOL
+        let mut chip8 = chip8x(&[0x02, 0xA0, 0x02, 0xA0]);
+        let background = |chip8: &Chip8| chip8.display().palette(0, 0, &Palette::default()).background;
+        assert_eq!(background(&chip8), VP590_BACKGROUNDS[0]);
+        run(&mut chip8, 1, Keypad::new());
+        assert_eq!(background(&chip8), VP590_BACKGROUNDS[1]);
+        run(&mut chip8, 1, Keypad::new());
+        assert_eq!(background(&chip8), VP590_BACKGROUNDS[2]);
+    }
+
+    #[test]
+    fn chip8x_reads_the_second_keypad() {
+        // LD V0, 3; SKP2 V0; LD V1, 1; SKNP2 V0; LD V2, 1
+        let rom = [0x60, 0x03, 0xE0, 0xF2, 0x61, 0x01, 0xE0, 0xF5, 0x62, 0x01];
+        let mut chip8 = chip8x(&rom);
+        let mut keypad = Keypad::new();
+        keypad.press(KEYPAD_SIZE + 3);
+        run(&mut chip8, 4, keypad);
+        assert_eq!(&chip8.registers()[1..3], &[0, 1]);
+        // Key 3 of the first keypad doesn't count
+        let mut chip8 = chip8x(&rom);
+        let mut keypad = Keypad::new();
+        keypad.press(3);
+        run(&mut chip8, 4, keypad);
+        assert_eq!(&chip8.registers()[1..3], &[1, 0]);
+    }
+
+    #[test]
+    fn chip8x_port_reads_back_the_last_write() {
+        // LD V0, 7F; OUT V0; IN V1
+        let mut chip8 = chip8x(&[0x60, 0x7F, 0xF0, 0xF8, 0xF1, 0xFB]);
+        run(&mut chip8, 3, Keypad::new());
+        assert_eq!(chip8.io_port(), 0x7F);
+        assert_eq!(chip8.registers()[1], 0x7F);
+    }
+
+    #[test]
+    fn megachip_draws_palette_sprites_on_the_big_display() {
+        let mut rom = vec![
+            0x00, 0x11, // MEGAON
+            0x01, 0x00, 0x02, 0x30, // LDHI I, 000230
+            0x02, 0x01, // LDPAL 1
+            0x01, 0x00, 0x02, 0x34, // LDHI I, 000234
+            0x03, 0x02, // SPRW 2
+            0x04, 0x01, // SPRH 1
+            0x60, 0x04, 0x61, 0x05, // LD V0, 4; LD V1, 5
+            0xD0, 0x10, // DRW V0, V1
+            0x00, 0xE0, // CLS shows the frame
+            0x12, 0x18, // JP 218
+        ];
+        rom.resize(0x30, 0);
+        rom.extend_from_slice(&[0xFF, 0x10, 0x20, 0x30, 0x01, 0x00]);
+        let mut chip8 = Chip8::new();
+        chip8.set_platform(Platform::MegaChip);
+        chip8.load_rom(&rom);
+        assert_eq!(chip8.memory().size(), MEGA_MEMORY_SIZE);
+        chip8.tick(Keypad::new());
+        assert_eq!((chip8.display().width(), chip8.display().height()), (MEGA_WIDTH, MEGA_HEIGHT));
+        assert!(chip8.display().colored());
+        chip8.tick(Keypad::new());
+        assert_eq!((chip8.index(), chip8.pc()), (0x230, 0x206));
+        run(&mut chip8, 8, Keypad::new());
+        assert_eq!(foreground(&chip8, 4, 5), [0x10, 0x20, 0x30, 0xFF]);
+        assert_eq!(chip8.display().peek(4, 5), 1);
+        assert_eq!(chip8.display().peek(5, 5), 0);
+    }
+
+    #[test]
+    fn megachip_mode_switches_back() {
+        let mut chip8 = Chip8::new();
+        chip8.set_platform(Platform::MegaChip);
+        // MEGAON; MEGAOFF
+        chip8.load_rom(&[0x00, 0x11, 0x00, 0x10]);
+        run(&mut chip8, 2, Keypad::new());
+        assert_eq!((chip8.display().width(), chip8.display().height()), (64, 32));
+        assert!(!chip8.display().colored());
+    }
+
+    #[test]
+    fn megachip_samples_start_and_stop() {
+        let mut chip8 = Chip8::new();
+        chip8.set_platform(Platform::MegaChip);
+        // LD I, 300; DIGISND 1; STOPSND
+        let mut rom = vec![0xA3, 0x00, 0x06, 0x01, 0x07, 0x00];
+        rom.resize(0x100, 0);
+        rom.extend_from_slice(&[0x1F, 0x40, 0x00, 0x00, 0x10, 0x00]);
+        chip8.load_rom(&rom);
+        run(&mut chip8, 2, Keypad::new());
+        assert_eq!(chip8.sample(), Some(&Sample { rate: 8000, address: 0x306, length: 0x10, repeat: false }));
+        chip8.tick(Keypad::new());
+        assert_eq!(chip8.sample(), None);
+    }
+
+    // Loads the program with the stack at EA0 and the display at F00
+    fn memory_mapped(rom: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_font(&Font::default());
+        chip8.load_rom(rom);
+        assert_eq!(chip8.enable_memory_map(), Ok(()));
+        chip8
+    }
+
+    #[test]
+    fn mapped_display_is_read_and_written_through_memory() {
+        let mut chip8 = memory_mapped(&[
+            // Draws digit 0 at the top left, reads back its first row
+            0x60, 0x00, 0xF0, 0x29, 0xD0, 0x15, 0xAF, 0x00, 0xF1, 0x65,
+            // Overwrites its third row with 81
+            0x60, 0x81, 0xAF, 0x10, 0xF0, 0x55,
+        ]);
+        run(&mut chip8, 5, Keypad::new());
+        assert_eq!(&chip8.registers()[..2], &[0xF0, 0x00]);
+        assert_eq!(chip8.display().peek(3, 2), 1);
+        run(&mut chip8, 3, Keypad::new());
+        assert_eq!(chip8.memory().peek(0xF10), 0x81);
+        let row: Vec<u8> = (0..8).map(|x| chip8.display().peek(x, 2)).collect();
+        assert_eq!(row, [1, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn mapped_stack_returns_to_the_address_in_memory() {
+        let mut chip8 = memory_mapped(&[
+            0x22, 0x08, 0x12, 0x02, 0x12, 0x04, 0x00, 0x00,
+            // Reads the return address at EA0 and changes it to 204
+            0xAE, 0xA0, 0xF1, 0x65, 0x61, 0x04, 0xAE, 0xA0, 0xF1, 0x55, 0x00, 0xEE,
+        ]);
+        run(&mut chip8, 3, Keypad::new());
+        assert_eq!(chip8.stack().len(), 1);
+        assert_eq!(&chip8.registers()[..2], &[0x02, 0x02]);
+        run(&mut chip8, 4, Keypad::new());
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(chip8.halted(), None);
+    }
+
+    #[test]
+    fn hires_display_is_not_mapped() {
+        let mut chip8 = Chip8::new();
+        chip8.load_font(&Font::default());
+        chip8.load_rom(&hires_rom());
+        assert!(chip8.enable_memory_map().is_err());
+        assert!(chip8.memory_mapped());
+        run(&mut chip8, 3, Keypad::new());
+        assert_eq!(lit(chip8.display()), 14);
+        assert!((VIP_DISPLAY_ADDRESS..chip8.memory().size()).all(|a| chip8.memory().peek(a) == 0));
+    }
+}
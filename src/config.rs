@@ -0,0 +1,365 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use sha1::{Digest, Sha1};
+use crate::database;
+use crate::palette::{self, Palette};
+use crate::filter::Filter;
+use crate::keymap::Keymap;
+use crate::keypad::KEY_COUNT;
+use crate::quirks::Quirks;
+use crate::renderer::Persistence;
+
+// Settings file, by default `chip8/config.toml` in the user config directory
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    #[serde(flatten)]
+    pub settings: Settings,
+    // Per-ROM overrides keyed by SHA-1 hash of the ROM or by its file name
+    pub roms: HashMap<String, Settings>,
+}
+
+// Settings given in the config file, globally or for a ROM, or on command line
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Settings {
+    // Timeout between ticks in milliseconds, fractions allowed
+    pub sleep: Option<f64>,
+    // `ticks` or `vip`, see `timing::Timing`
+    pub timing: Option<String>,
+    // What 0nnn does: `run`, `nop` or `error`
+    pub machine_code: Option<String>,
+    // Instruction set: `chip8`, `chip8x`, `eti660` or `megachip`
+    pub platform: Option<String>,
+    // Program load address and font address, by default those of the platform
+    pub load_address: Option<u16>,
+    pub font_address: Option<u16>,
+    // Built-in font name or path to a font image
+    pub font: Option<String>,
+    // Keep stack and display in memory at EA0 and F00
+    pub memory_map: Option<bool>,
+    // Nested calls allowed, by default as many as the platform allows
+    pub stack_depth: Option<usize>,
+    // `error`, `halt` or `wrap`, see `stack::Overflow`
+    pub stack_overflow: Option<String>,
+    // Sound while the sound timer runs, on by default
+    pub audio: Option<bool>,
+    // Integer scale of the window, by default it takes 2/3 of the screen height
+    pub scale: Option<u32>,
+    pub screenshot_scale: Option<u32>,
+    pub record_scale: Option<u32>,
+    pub palette: PaletteConfig,
+    pub render: RenderConfig,
+    pub keymap: KeymapConfig,
+    // Quirk switches by name
+    pub quirks: HashMap<String, bool>,
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct PaletteConfig {
+    // Name of a built-in theme
+    pub theme: Option<String>,
+    // Colors in `#rrggbb` notation, override theme colors
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct RenderConfig {
+    // One of `off`, `fade` or `blend`
+    pub persistence: Option<String>,
+    // Fraction of brightness a faded pixel loses every frame
+    pub decay: Option<f32>,
+    // Upscaling filter name
+    pub filter: Option<String>,
+    // Show clickable hex keypad beside the display
+    pub keypad: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct KeymapConfig {
+    // Name of a built-in layout
+    pub preset: Option<String>,
+    // Host keys for CHIP-8 keys given as hex digits, replace preset bindings
+    pub bindings: HashMap<String, Vec<String>>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let text = std::fs::read_to_string(path)?;
+        Config::parse(&text)
+    }
+
+    // Loads config from the user config directory, if there is one
+    pub fn load_default() -> io::Result<Config> {
+        match Config::default_path() {
+            Some(path) if path.exists() => Config::load(path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("chip8").join("config.toml"))
+    }
+
+    pub fn parse(text: &str) -> io::Result<Config> {
+        toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Finds overrides for a ROM by its hash, or by its file name with or
+    // without extension
+    pub fn rom<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Option<&Settings> {
+        let hash = rom_hash(data);
+        if let Some((_, settings)) = self.roms.iter().find(|(key, _)| key.eq_ignore_ascii_case(&hash)) {
+            return Some(settings);
+        }
+        let path = path.as_ref();
+        [path.file_name(), path.file_stem()]
+            .iter()
+            .flatten()
+            .filter_map(|name| name.to_str())
+            .find_map(|name| self.roms.get(name))
+    }
+
+    // Global settings with settings recommended by the ROM database and
+    // ROM overrides applied, in this order. This is the only place quirks
+    // of the database meet configured ones, command line settings are
+    // merged on top by the caller.
+    pub fn settings<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Settings {
+        let mut settings = self.settings.clone();
+        if let Some(program) = database::find(data) {
+            settings = settings.merge(&program.settings());
+        }
+        if let Some(rom) = self.rom(path, data) {
+            settings = settings.merge(rom);
+        }
+        settings
+    }
+}
+
+impl Settings {
+    // Settings of `other` take precedence over settings of `self`
+    pub fn merge(&self, other: &Settings) -> Settings {
+        let mut quirks = self.quirks.clone();
+        quirks.extend(other.quirks.iter().map(|(name, value)| (name.clone(), *value)));
+        Settings {
+            sleep: other.sleep.or(self.sleep),
+            timing: other.timing.clone().or_else(|| self.timing.clone()),
+            machine_code: other.machine_code.clone().or_else(|| self.machine_code.clone()),
+            platform: other.platform.clone().or_else(|| self.platform.clone()),
+            load_address: other.load_address.or(self.load_address),
+            font_address: other.font_address.or(self.font_address),
+            font: other.font.clone().or_else(|| self.font.clone()),
+            memory_map: other.memory_map.or(self.memory_map),
+            stack_depth: other.stack_depth.or(self.stack_depth),
+            stack_overflow: other.stack_overflow.clone().or_else(|| self.stack_overflow.clone()),
+            audio: other.audio.or(self.audio),
+            scale: other.scale.or(self.scale),
+            screenshot_scale: other.screenshot_scale.or(self.screenshot_scale),
+            record_scale: other.record_scale.or(self.record_scale),
+            palette: self.palette.merge(&other.palette),
+            render: self.render.merge(&other.render),
+            keymap: self.keymap.merge(&other.keymap),
+            quirks,
+        }
+    }
+
+    pub fn apply_quirks(&self, mut quirks: Quirks) -> Result<Quirks, String> {
+        for (name, value) in &self.quirks {
+            quirks.set(name, *value)?;
+        }
+        Ok(quirks)
+    }
+}
+
+impl PaletteConfig {
+    // Settings of `other` take precedence, its theme replaces colors of `self`
+    pub fn merge(&self, other: &PaletteConfig) -> PaletteConfig {
+        let inherited = |color: &Option<String>| if other.theme.is_some() { None } else { color.clone() };
+        PaletteConfig {
+            theme: other.theme.clone().or_else(|| self.theme.clone()),
+            foreground: other.foreground.clone().or_else(|| inherited(&self.foreground)),
+            background: other.background.clone().or_else(|| inherited(&self.background)),
+        }
+    }
+
+    // Applies theme and color overrides on top of the given palette
+    pub fn apply(&self, mut palette: Palette) -> Result<Palette, String> {
+        if let Some(theme) = &self.theme {
+            palette = Palette::theme(theme)
+                .ok_or_else(|| format!("Unknown theme: {}", theme))?;
+        }
+        if let Some(color) = &self.foreground {
+            palette.foreground = palette::parse_color(color)?;
+        }
+        if let Some(color) = &self.background {
+            palette.background = palette::parse_color(color)?;
+        }
+        Ok(palette)
+    }
+}
+
+impl RenderConfig {
+    // Settings of `other` take precedence over settings of `self`
+    pub fn merge(&self, other: &RenderConfig) -> RenderConfig {
+        RenderConfig {
+            persistence: other.persistence.clone().or_else(|| self.persistence.clone()),
+            decay: other.decay.or(self.decay),
+            filter: other.filter.clone().or_else(|| self.filter.clone()),
+            keypad: other.keypad.or(self.keypad),
+        }
+    }
+
+    pub fn filter(&self) -> Result<Filter, String> {
+        match &self.filter {
+            Some(filter) => filter.parse(),
+            None => Ok(Filter::None),
+        }
+    }
+
+    pub fn persistence(&self) -> Result<Persistence, String> {
+        let persistence = match &self.persistence {
+            Some(mode) => mode.parse()?,
+            None => Persistence::Off,
+        };
+        match (persistence, self.decay) {
+            (Persistence::Fade(_), Some(decay)) if !(0.0..=1.0).contains(&decay) => {
+                Err(format!("Decay must be between 0 and 1: {}", decay))
+            }
+            (Persistence::Fade(_), Some(decay)) => Ok(Persistence::Fade(decay)),
+            _ => Ok(persistence),
+        }
+    }
+}
+
+impl KeymapConfig {
+    // Settings of `other` take precedence, its preset replaces bindings of `self`
+    pub fn merge(&self, other: &KeymapConfig) -> KeymapConfig {
+        let mut bindings = if other.preset.is_some() { HashMap::new() } else { self.bindings.clone() };
+        bindings.extend(other.bindings.iter().map(|(key, host_keys)| (key.clone(), host_keys.clone())));
+        KeymapConfig {
+            preset: other.preset.clone().or_else(|| self.preset.clone()),
+            bindings,
+        }
+    }
+
+    // Applies preset and bindings on top of the given keymap
+    pub fn apply(&self, mut keymap: Keymap) -> Result<Keymap, String> {
+        if let Some(preset) = &self.preset {
+            keymap = Keymap::preset(preset)
+                .ok_or_else(|| format!("Unknown keymap preset: {}", preset))?;
+        }
+        for (key, host_keys) in &self.bindings {
+            let key = parse_key(key)?;
+            let host_keys = host_keys
+                .iter()
+                .map(|host_key| host_key.parse())
+                .collect::<Result<Vec<_>, _>>()?;
+            keymap.bind(key, host_keys);
+        }
+        Ok(keymap)
+    }
+}
+
+// Parses CHIP-8 key given as a hex digit, 10 to 1F for the second keypad
+pub fn parse_key(key: &str) -> Result<usize, String> {
+    let digits = key.trim_start_matches("0x");
+    match usize::from_str_radix(digits, 16) {
+        Ok(key) if key < KEY_COUNT => Ok(key),
+        _ => Err(format!("Bad CHIP-8 key: {}", key)),
+    }
+}
+
+// Lower case hex SHA-1 of ROM data, identifies a ROM regardless of its file name
+pub fn rom_hash(data: &[u8]) -> String {
+    Sha1::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r##"
+        sleep = 2
+        platform = "chip8"
+        [quirks]
+        shift = false
+        clip = true
+        [palette]
+        foreground = "#00ff00"
+        [roms."pong.ch8"]
+        sleep = 0.5
+        audio = false
+        [roms."pong.ch8".quirks]
+        clip = false
+    "##;
+
+    #[test]
+    fn parse_reads_global_and_rom_settings() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.settings.sleep, Some(2.0));
+        assert_eq!(config.settings.platform.as_deref(), Some("chip8"));
+        assert_eq!(config.settings.quirks.get("shift"), Some(&false));
+        assert_eq!(config.roms["pong.ch8"].sleep, Some(0.5));
+        assert!(Config::parse("sleep = \"fast\"").is_err());
+    }
+
+    #[test]
+    fn rom_overrides_are_found_by_name_or_hash() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert!(config.rom("games/pong.ch8", &[0x00, 0xE0]).is_some());
+        assert!(config.rom("games/tetris.ch8", &[0x00, 0xE0]).is_none());
+        let hash = rom_hash(&[0x00, 0xE0]).to_ascii_uppercase();
+        let config = Config::parse(&format!("[roms.{}]\nscale = 3", hash)).unwrap();
+        assert_eq!(config.rom("any.ch8", &[0x00, 0xE0]).and_then(|rom| rom.scale), Some(3));
+    }
+
+    #[test]
+    fn later_settings_take_precedence() {
+        let config = Config::parse(CONFIG).unwrap();
+        let settings = config.settings("pong.ch8", &[0x00, 0xE0]);
+        assert_eq!(settings.sleep, Some(0.5));
+        assert_eq!(settings.audio, Some(false));
+        assert_eq!(settings.platform.as_deref(), Some("chip8"));
+        assert_eq!(settings.quirks.get("shift"), Some(&false));
+        assert_eq!(settings.quirks.get("clip"), Some(&false));
+        let cli = Settings { sleep: Some(1.0), ..Settings::default() };
+        assert_eq!(settings.merge(&cli).sleep, Some(1.0));
+        assert_eq!(settings.merge(&cli).audio, Some(false));
+    }
+
+    #[test]
+    fn merged_quirks_apply_by_name() {
+        let config = Config::parse(CONFIG).unwrap();
+        let quirks = config.settings("pong.ch8", &[]).apply_quirks(Quirks::default()).unwrap();
+        assert!(!quirks.shift);
+        assert!(!quirks.clip);
+        let unknown = Config::parse("[quirks]\nwobble = true").unwrap();
+        assert!(unknown.settings.apply_quirks(Quirks::default()).is_err());
+    }
+
+    #[test]
+    fn theme_replaces_inherited_colors() {
+        let config = Config::parse(CONFIG).unwrap();
+        let themed = PaletteConfig { theme: Some(String::from("amber")), ..PaletteConfig::default() };
+        let palette = config.settings.palette.merge(&themed);
+        assert_eq!(palette.theme.as_deref(), Some("amber"));
+        assert_eq!(palette.foreground, None);
+        let recolored = PaletteConfig { background: Some(String::from("#000000")), ..PaletteConfig::default() };
+        let palette = config.settings.palette.merge(&recolored);
+        assert_eq!(palette.foreground.as_deref(), Some("#00ff00"));
+    }
+
+    #[test]
+    fn keys_parse_as_hex_digits() {
+        assert_eq!(parse_key("a"), Ok(0xA));
+        assert_eq!(parse_key("0x1F"), Ok(0x1F));
+        assert!(parse_key("20").is_err());
+        assert!(parse_key("g").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use crate::instruction::Instruction;
+use crate::memory::{Memory, MEMORY_SIZE};
+use crate::renderer::Frame;
+use crate::screenshot;
+
+// Heat map shows one pixel per byte, 64 bytes per row
+const HEAT_MAP_WIDTH: usize = 64;
+const HEAT_MAP_SCALE: u32 = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    // Fetched as an instruction
+    Execute,
+    // Read as data by Dxyn or Fx65
+    Read,
+    // Written by Fx33 or Fx55
+    Write,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    // Listing of touched bytes, executed ones disassembled
+    Text,
+    Json,
+    // Heat map of the address space: green for executed, blue for read
+    // and red for written bytes, brighter for more frequent access
+    Png,
+}
+
+impl Format {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Format> {
+        path.as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ext.parse().ok())
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" | "txt" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "png" => Ok(Format::Png),
+            _ => Err(format!("Unknown coverage format: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Byte {
+    address: u32,
+    executed: u32,
+    read: u32,
+    written: u32,
+}
+
+// Per-byte access counters of the whole address space
+pub struct Coverage {
+    executed: Vec<u32>,
+    read: Vec<u32>,
+    written: Vec<u32>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::with_size(MEMORY_SIZE)
+    }
+
+    // Counters for memory of the given size, MEGA-CHIP has 16 MiB
+    pub fn with_size(size: usize) -> Self {
+        Coverage {
+            executed: vec![0; size],
+            read: vec![0; size],
+            written: vec![0; size],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.executed.len()
+    }
+
+    // Records access to `len` bytes starting at the address
+    pub fn record(&mut self, access: Access, address: usize, len: usize) {
+        let counters = match access {
+            Access::Execute => &mut self.executed,
+            Access::Read => &mut self.read,
+            Access::Write => &mut self.written,
+        };
+        for counter in counters.iter_mut().skip(address).take(len) {
+            *counter = counter.saturating_add(1);
+        }
+    }
+
+    pub fn executed(&self, address: usize) -> u32 {
+        self.executed[address]
+    }
+
+    pub fn read(&self, address: usize) -> u32 {
+        self.read[address]
+    }
+
+    pub fn written(&self, address: usize) -> u32 {
+        self.written[address]
+    }
+
+    fn touched(&self, address: usize) -> bool {
+        self.executed[address] > 0 || self.read[address] > 0 || self.written[address] > 0
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: Format, memory: &Memory) -> io::Result<()> {
+        match format {
+            Format::Text => {
+                let mut out = BufWriter::new(File::create(path)?);
+                self.write_listing(&mut out, memory)?;
+                out.flush()
+            }
+            Format::Json => {
+                let out = BufWriter::new(File::create(path)?);
+                serde_json::to_writer_pretty(out, &self.bytes()).map_err(io::Error::other)
+            }
+            Format::Png => screenshot::save(path, &self.heat_map(), HEAT_MAP_SCALE),
+        }
+    }
+
+    fn bytes(&self) -> Vec<Byte> {
+        (0..self.size())
+            .filter(|address| self.touched(*address))
+            .map(|address| Byte {
+                address: address as u32,
+                executed: self.executed[address],
+                read: self.read[address],
+                written: self.written[address],
+            })
+            .collect()
+    }
+
+    // Executed instructions are disassembled from memory as it is at exit,
+    // other touched bytes are listed one per line with their counters
+    pub fn write_listing<W: Write>(&self, out: &mut W, memory: &Memory) -> io::Result<()> {
+        let count = |counters: &[u32]| counters.iter().filter(|counter| **counter > 0).count();
+        writeln!(
+            out,
+            "# {} bytes executed, {} read, {} written",
+            count(&self.executed),
+            count(&self.read),
+            count(&self.written)
+        )?;
+        let mut address = 0;
+        while address < self.size() {
+            if !self.touched(address) {
+                address += 1;
+                continue;
+            }
+            let named = [
+                ("exec", self.executed[address]),
+                ("read", self.read[address]),
+                ("write", self.written[address]),
+            ];
+            let mut counters = String::new();
+            for (name, counter) in named.iter().filter(|(_, counter)| *counter > 0) {
+                counters.push_str(&format!("  {} {}", name, counter));
+            }
+            if self.executed[address] > 0 && address + 1 < self.size() {
+                let opcode = memory.get_opcode(address as u16);
+                let text = Instruction::decode(opcode)
+                    .map_or(String::from("???"), |instruction| instruction.to_string());
+                writeln!(out, "{:03X}  {:04X}  {:<16}{}", address, opcode, text, counters)?;
+                address += 2;
+            } else {
+                writeln!(out, "{:03X}  {:02X}    {:<16}{}", address, memory.peek(address), "", counters)?;
+                address += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Heat map of the first 4 KiB and any larger memory up to the last
+    // touched row, so the 16 MiB of MEGA-CHIP do not make a huge image
+    pub fn heat_map(&self) -> Frame {
+        let touched = (0..self.size()).rev().find(|address| self.touched(*address)).map_or(0, |address| address + 1);
+        let size = touched.max(MEMORY_SIZE).min(self.size());
+        let mut frame = Frame::new(HEAT_MAP_WIDTH, size.div_ceil(HEAT_MAP_WIDTH));
+        for address in 0..size {
+            let color = [
+                heat(self.written[address]),
+                heat(self.executed[address]),
+                heat(self.read[address]),
+                0xff,
+            ];
+            frame.put(address % HEAT_MAP_WIDTH, address / HEAT_MAP_WIDTH, color);
+        }
+        frame
+    }
+}
+
+// Brightness of a counter on a log scale, any access is clearly visible
+fn heat(counter: u32) -> u8 {
+    if counter == 0 {
+        0
+    } else {
+        (64.0 + 191.0 * ((counter as f32).ln() / (u32::MAX as f32).ln()).powf(0.5)).min(255.0) as u8
+    }
+}
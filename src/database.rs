@@ -0,0 +1,197 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::OnceLock;
+use crate::config::{self, Settings};
+use crate::instruction::Platform;
+use crate::keymap::{HostKey, Keymap};
+use crate::record;
+
+// Offline compatibility database shipped with the emulator
+const DATABASE: &str = include_str!("database.toml");
+
+// Host keys suggested for keymap hints, see `Program::keys`
+const HINT_KEYS: [(&str, HostKey); 5] = [
+    ("up", HostKey::Up),
+    ("down", HostKey::Down),
+    ("left", HostKey::Left),
+    ("right", HostKey::Right),
+    ("a", HostKey::Space),
+];
+
+// Known ROM and settings it needs to run correctly
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Program {
+    pub title: String,
+    pub author: Option<String>,
+    pub year: Option<u32>,
+    // `chip8`, `chip8x`, `schip`, `xochip` or `megachip`
+    pub platform: Option<String>,
+    pub description: Option<String>,
+    // Instructions per 60 Hz frame
+    pub tickrate: Option<u32>,
+    // Recommended quirk switches by name
+    pub quirks: HashMap<String, bool>,
+    // CHIP-8 keys as hex digits for `up`, `down`, `left`, `right` and
+    // `a` (action) controls, bound to arrow keys and space when free
+    pub keys: HashMap<String, String>,
+}
+
+impl Program {
+    // Settings recommended for the program, to be overridden by the user
+    pub fn settings(&self) -> Settings {
+        Settings {
+            sleep: self.tickrate.map(sleep_duration),
+            // Programs for platforms which are not emulated yet run as
+            // CHIP-8, `main` warns about them
+            platform: self.platform.clone().filter(|platform| platform.parse::<Platform>().is_ok()),
+            quirks: self.quirks.clone(),
+            ..Settings::default()
+        }
+    }
+
+    // Binds hinted controls to host keys which are not bound yet
+    pub fn apply_hints(&self, mut keymap: Keymap) -> Result<Keymap, String> {
+        for (control, host_key) in HINT_KEYS.iter() {
+            let key = match self.keys.get(*control) {
+                Some(key) => config::parse_key(key)?,
+                None => continue,
+            };
+            if keymap.keys(*host_key).next().is_none() {
+                let mut host_keys = keymap.host_keys(key).to_vec();
+                host_keys.push(*host_key);
+                keymap.bind(key, host_keys);
+            }
+        }
+        Ok(keymap)
+    }
+}
+
+// Title with author and year, e.g. `Pong (Paul Vervalin, 1990)`
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.title)?;
+        match (&self.author, self.year) {
+            (Some(author), Some(year)) => write!(f, " ({}, {})", author, year),
+            (Some(author), None) => write!(f, " ({})", author),
+            (None, Some(year)) => write!(f, " ({})", year),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+// Programs keyed by lower case SHA-1 hash of the ROM
+#[derive(Default, Debug)]
+pub struct Database {
+    programs: HashMap<String, Program>,
+}
+
+impl Database {
+    pub fn parse(text: &str) -> Result<Database, String> {
+        let programs: HashMap<String, Program> = toml::from_str(text)
+            .map_err(|e| format!("Malformed ROM database: {}", e))?;
+        Ok(Database {
+            programs: programs
+                .into_iter()
+                .map(|(hash, program)| (hash.to_ascii_lowercase(), program))
+                .collect(),
+        })
+    }
+
+    pub fn find(&self, data: &[u8]) -> Option<&Program> {
+        self.programs.get(&config::rom_hash(data))
+    }
+}
+
+// Looks a ROM up by its SHA-1 hash in the built-in database
+pub fn find(data: &[u8]) -> Option<&'static Program> {
+    static BUILTIN: OnceLock<Database> = OnceLock::new();
+    BUILTIN
+        .get_or_init(|| Database::parse(DATABASE).expect("Built-in ROM database is valid"))
+        .find(data)
+}
+
+// Timeout between ticks in milliseconds giving the tick rate
+fn sleep_duration(tickrate: u32) -> f64 {
+    1000.0 / (tickrate.max(1) as f64 * record::FRAME_RATE as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PONG: &[u8] = &[0x6A, 0x02, 0x6B, 0x0C, 0x00, 0xEE];
+
+    fn database() -> Database {
+        let text = format!(
+            r#"
+            ["{}"]
+            title = "Pong"
+            author = "Paul Vervalin"
+            year = 1990
+            platform = "chip8"
+            tickrate = 15
+            quirks = {{ vf_reset = true }}
+            keys = {{ up = "1", down = "4", a = "f" }}
+
+            ["0000000000000000000000000000000000000000"]
+            title = "Ant"
+            platform = "schip"
+            "#,
+            config::rom_hash(PONG).to_ascii_uppercase()
+        );
+        Database::parse(&text).unwrap()
+    }
+
+    #[test]
+    fn builtin_database_parses() {
+        assert!(Database::parse(DATABASE).is_ok());
+        assert!(find(PONG).is_none());
+    }
+
+    #[test]
+    fn programs_are_found_by_hash() {
+        let database = database();
+        assert_eq!(database.programs.len(), 2);
+        let program = database.find(PONG).unwrap();
+        assert_eq!(program.to_string(), "Pong (Paul Vervalin, 1990)");
+        assert!(database.find(&PONG[1..]).is_none());
+        assert!(Database::parse("[pong]\ntitle = 1").is_err());
+    }
+
+    #[test]
+    fn settings_follow_the_program() {
+        let database = database();
+        let settings = database.find(PONG).unwrap().settings();
+        assert_eq!(settings.platform.as_deref(), Some("chip8"));
+        assert_eq!(settings.sleep, Some(sleep_duration(15)));
+        assert_eq!(settings.quirks.get("vf_reset"), Some(&true));
+        let ant = &database.programs["0000000000000000000000000000000000000000"];
+        assert_eq!(ant.settings().platform, None);
+        assert_eq!(ant.settings().sleep, None);
+    }
+
+    #[test]
+    fn tickrates_map_to_sleep_durations() {
+        assert!((sleep_duration(15) - 1000.0 / 900.0).abs() < 1e-9);
+        assert!((sleep_duration(1000) - 1000.0 / 60000.0).abs() < 1e-9);
+        assert!(sleep_duration(100) < sleep_duration(15));
+        assert_eq!(sleep_duration(0), sleep_duration(1));
+    }
+
+    #[test]
+    fn hints_bind_free_host_keys_only() {
+        let program = database().find(PONG).unwrap().clone();
+        let mut keymap = Keymap::empty();
+        keymap.bind(0x2, vec![HostKey::Up]);
+        let keymap = program.apply_hints(keymap).unwrap();
+        assert_eq!(keymap.host_keys(0x1), &[] as &[HostKey]);
+        assert_eq!(keymap.host_keys(0x2), &[HostKey::Up]);
+        assert_eq!(keymap.host_keys(0x4), &[HostKey::Down]);
+        assert_eq!(keymap.host_keys(0xF), &[HostKey::Space]);
+        let mut broken = program;
+        broken.keys.insert(String::from("a"), String::from("x"));
+        assert!(broken.apply_hints(Keymap::empty()).is_err());
+    }
+}
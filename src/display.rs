@@ -1,59 +1,139 @@
-const DISPLAY_WIDTH: usize = 64;
-const DISPLAY_HEIGHT: usize = 32;
+use crate::memory::Memory;
+use crate::palette::{Color, Palette, VP590_BACKGROUNDS, VP590_FOREGROUNDS};
+
+// Standard CHIP-8 resolution
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+// CHIP-8X draws in red until zones are colored
+const DEFAULT_FOREGROUND: u8 = 1;
 
 pub struct Display {
-    buffer: [u8; DISPLAY_WIDTH * DISPLAY_HEIGHT],
-    changed: bool
+    width: usize,
+    height: usize,
+    buffer: Vec<u8>,
+    // Color attributes of the VP-590 color board: foreground color of
+    // every pixel and a background color for the whole screen
+    colors: Option<Vec<u8>>,
+    background: u8,
+    // True color pixels of MEGA-CHIP
+    rgba: Option<Vec<Color>>,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
+    }
 }
 
 impl Display {
     pub fn new() -> Self {
+        Display::with_size(DISPLAY_WIDTH, DISPLAY_HEIGHT)
+    }
+
+    pub fn with_size(width: usize, height: usize) -> Self {
         Display{
-            buffer: [0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
-            changed: false
+            width,
+            height,
+            buffer: vec![0; width * height],
+            colors: None,
+            background: 0,
+            rgba: None,
         }
     }
 
     pub fn clear(&mut self) {
-        self.buffer = [0; DISPLAY_WIDTH * DISPLAY_HEIGHT];
-        self.changed = true;
+        self.buffer.iter_mut().for_each(|pixel| *pixel = 0);
+        if let Some(rgba) = &mut self.rgba {
+            rgba.iter_mut().for_each(|color| *color = [0, 0, 0, 0xff]);
+        }
     }
 
     pub fn width(&self) -> usize {
-        DISPLAY_WIDTH
+        self.width
     }
 
     pub fn height(&self) -> usize {
-        DISPLAY_HEIGHT
+        self.height
     }
 
     pub fn peek(&self, x: usize, y: usize) -> u8 {
-        self.buffer[y * DISPLAY_WIDTH + x]
+        self.buffer[y * self.width + x]
     }
 
     pub fn poke(&mut self, x: usize, y: usize, data: u8) {
-        self.buffer[y * DISPLAY_WIDTH + x] = data;
-        self.changed = true;
-    }
-
-    pub fn changed(&self) -> bool {
-        self.changed
-    }
-
-    pub fn draw(&mut self, buf: &mut [u8]) {
-        for i in 0..self.buffer.len() {
-            if self.buffer[i] == 1 {
-                buf[i*4] = 0xff;
-                buf[i*4+1] = 0xff;
-                buf[i*4+2] = 0xff;
-                buf[i*4+3] = 0xff;
-            } else {
-                buf[i*4] = 0;
-                buf[i*4+1] = 0;
-                buf[i*4+2] = 0;
-                buf[i*4+3] = 0;
+        self.buffer[y * self.width + x] = data;
+    }
+
+    // Adds the color attribute layer, pixels are drawn in VP-590 colors
+    // instead of the palette from then on
+    pub fn enable_colors(&mut self) {
+        self.colors = Some(vec![DEFAULT_FOREGROUND; self.width * self.height]);
+        self.background = 0;
+    }
+
+    // Adds the true color layer, pixels are drawn in their own colors
+    pub fn enable_rgba(&mut self) {
+        self.rgba = Some(vec![[0, 0, 0, 0xff]; self.width * self.height]);
+    }
+
+    pub fn colored(&self) -> bool {
+        self.colors.is_some() || self.rgba.is_some()
+    }
+
+    // Sets a true color pixel, lit unless black, ignored without the layer
+    pub fn set_rgba(&mut self, x: usize, y: usize, color: Color) {
+        if let Some(rgba) = &mut self.rgba {
+            let i = y * self.width + x;
+            rgba[i] = color;
+            self.buffer[i] = (color[..3] != [0, 0, 0]) as u8;
+        }
+    }
+
+    // Sets foreground color of a pixel, ignored without the color layer
+    pub fn set_color(&mut self, x: usize, y: usize, color: u8) {
+        if let Some(colors) = &mut self.colors {
+            colors[y * self.width + x] = color % VP590_FOREGROUNDS.len() as u8;
+        }
+    }
+
+    pub fn next_background(&mut self) {
+        self.background = (self.background + 1) % VP590_BACKGROUNDS.len() as u8;
+    }
+
+    // Colors a pixel is drawn with, the given palette unless colored
+    pub fn palette(&self, x: usize, y: usize, palette: &Palette) -> Palette {
+        if let Some(rgba) = &self.rgba {
+            return Palette::new(rgba[y * self.width + x], [0, 0, 0, 0xff]);
+        }
+        match &self.colors {
+            Some(colors) => Palette::new(
+                VP590_FOREGROUNDS[colors[y * self.width + x] as usize],
+                VP590_BACKGROUNDS[self.background as usize],
+            ),
+            None => *palette,
+        }
+    }
+
+    // Bytes taken by the display in memory, 8 pixels each
+    pub fn memory_size(&self) -> usize {
+        self.width * self.height / 8
+    }
+
+    // Writes pixels to memory at the given address, row by row
+    pub fn store(&self, memory: &mut Memory, address: usize) {
+        for (i, pixels) in self.buffer.chunks(8).enumerate() {
+            let byte = pixels.iter().fold(0, |byte, pixel| byte << 1 | pixel);
+            memory.poke(address + i, byte);
+        }
+    }
+
+    // Reads pixels back from memory written by `store`
+    pub fn load(&mut self, memory: &Memory, address: usize) {
+        for i in 0..self.memory_size() {
+            let byte = memory.peek(address + i);
+            for bit in 0..8 {
+                self.buffer[i * 8 + bit] = byte >> (7 - bit) & 1;
             }
         }
-        self.changed = false;
     }
 }
@@ -0,0 +1,273 @@
+use std::str::FromStr;
+use crate::palette::Color;
+use crate::renderer::Frame;
+
+// Software upscaling filters, applied on CPU to the rendered frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Filter {
+    None,
+    // EPX family, keeps the palette and sharpens diagonal edges
+    Scale2x,
+    Scale3x,
+    Scale4x,
+    // 4x edge smoother in the spirit of xBR, blends colors along edges
+    Xbr,
+    // Doubles pixels and darkens every second line
+    Scanlines,
+    // Triples pixels and applies scanlines with an RGB aperture mask
+    Crt,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Filter::None),
+            "scale2x" | "epx" => Ok(Filter::Scale2x),
+            "scale3x" => Ok(Filter::Scale3x),
+            "scale4x" => Ok(Filter::Scale4x),
+            "xbr" => Ok(Filter::Xbr),
+            "scanlines" => Ok(Filter::Scanlines),
+            "crt" => Ok(Filter::Crt),
+            _ => Err(format!("Unknown filter: {}", s)),
+        }
+    }
+}
+
+impl Filter {
+    // How many times the filter enlarges a frame
+    pub fn factor(&self) -> usize {
+        match self {
+            Filter::None => 1,
+            Filter::Scale2x | Filter::Scanlines => 2,
+            Filter::Scale3x | Filter::Crt => 3,
+            Filter::Scale4x | Filter::Xbr => 4,
+        }
+    }
+
+    pub fn apply(&self, frame: &Frame) -> Frame {
+        match self {
+            Filter::None => frame.clone(),
+            Filter::Scale2x => scale2x(frame),
+            Filter::Scale3x => scale3x(frame),
+            Filter::Scale4x => scale2x(&scale2x(frame)),
+            Filter::Xbr => xbr(frame),
+            Filter::Scanlines => scanlines(frame),
+            Filter::Crt => crt(frame),
+        }
+    }
+}
+
+// Returns pixel at the given offset, clamping coordinates to the frame edges
+fn neighbour(frame: &Frame, x: usize, y: usize, dx: isize, dy: isize) -> Color {
+    let x = (x as isize + dx).clamp(0, frame.width as isize - 1) as usize;
+    let y = (y as isize + dy).clamp(0, frame.height as isize - 1) as usize;
+    frame.get(x, y)
+}
+
+// Scale2x (AdvanceMAME), each pixel E with neighbours
+//   . B .
+//   D E F
+//   . H .
+// is replaced by a 2x2 block where corners take the color of the two
+// adjacent neighbours when they agree.
+fn scale2x(frame: &Frame) -> Frame {
+    let mut output = Frame::new(frame.width * 2, frame.height * 2);
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let b = neighbour(frame, x, y, 0, -1);
+            let d = neighbour(frame, x, y, -1, 0);
+            let e = frame.get(x, y);
+            let f = neighbour(frame, x, y, 1, 0);
+            let h = neighbour(frame, x, y, 0, 1);
+
+            let (e0, e1, e2, e3) = if b != h && d != f {
+                (
+                    if d == b { d } else { e },
+                    if b == f { f } else { e },
+                    if d == h { d } else { e },
+                    if h == f { f } else { e },
+                )
+            } else {
+                (e, e, e, e)
+            };
+            output.put(x * 2, y * 2, e0);
+            output.put(x * 2 + 1, y * 2, e1);
+            output.put(x * 2, y * 2 + 1, e2);
+            output.put(x * 2 + 1, y * 2 + 1, e3);
+        }
+    }
+    output
+}
+
+// Scale3x (AdvanceMAME), same idea as Scale2x on the full 3x3 neighbourhood
+//   A B C
+//   D E F
+//   G H I
+fn scale3x(frame: &Frame) -> Frame {
+    let mut output = Frame::new(frame.width * 3, frame.height * 3);
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let a = neighbour(frame, x, y, -1, -1);
+            let b = neighbour(frame, x, y, 0, -1);
+            let c = neighbour(frame, x, y, 1, -1);
+            let d = neighbour(frame, x, y, -1, 0);
+            let e = frame.get(x, y);
+            let f = neighbour(frame, x, y, 1, 0);
+            let g = neighbour(frame, x, y, -1, 1);
+            let h = neighbour(frame, x, y, 0, 1);
+            let i = neighbour(frame, x, y, 1, 1);
+
+            let block = if b != h && d != f {
+                [
+                    if d == b { d } else { e },
+                    if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                    if b == f { f } else { e },
+                    if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                    e,
+                    if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                    if d == h { d } else { e },
+                    if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                    if h == f { f } else { e },
+                ]
+            } else {
+                [e; 9]
+            };
+            for (n, color) in block.iter().enumerate() {
+                output.put(x * 3 + n % 3, y * 3 + n / 3, *color);
+            }
+        }
+    }
+    output
+}
+
+// Weighted YUV distance used by xBR to compare colors
+fn distance(a: Color, b: Color) -> u32 {
+    let (r, g, b) = (
+        a[0] as i32 - b[0] as i32,
+        a[1] as i32 - b[1] as i32,
+        a[2] as i32 - b[2] as i32,
+    );
+    let y = (299 * r + 587 * g + 114 * b).abs() / 1000;
+    let u = (-169 * r - 331 * g + 500 * b).abs() / 1000;
+    let v = (500 * r - 419 * g - 81 * b).abs() / 1000;
+    (48 * y + 7 * u + 6 * v) as u32
+}
+
+fn blend(a: Color, b: Color, weight: u32) -> Color {
+    let mut color = [0; 4];
+    for (i, channel) in color.iter_mut().enumerate() {
+        *channel = ((a[i] as u32 * (4 - weight) + b[i] as u32 * weight) / 4) as u8;
+    }
+    color
+}
+
+// Simplified 4x xBR. Every corner of the 4x4 output block is tested for
+// an edge running across it by comparing color distances along the two
+// diagonals of a 5x5 neighbourhood; detected edges are smoothed by
+// blending the corner with the closest neighbouring color.
+fn xbr(frame: &Frame) -> Frame {
+    const SIZE: usize = 4;
+    let mut output = frame.scaled(SIZE as u32);
+    // Rotations mapping (dx, dy) of the bottom-right corner to the others
+    let rotations: [(isize, isize, isize, isize); 4] = [
+        (1, 0, 0, 1),   // bottom-right
+        (0, 1, -1, 0),  // bottom-left
+        (-1, 0, 0, -1), // top-left
+        (0, -1, 1, 0),  // top-right
+    ];
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let e = frame.get(x, y);
+            for &(xx, xy, yx, yy) in rotations.iter() {
+                let p = |dx: isize, dy: isize| {
+                    neighbour(frame, x, y, dx * xx + dy * yx, dx * xy + dy * yy)
+                };
+                let (f, h, i) = (p(1, 0), p(0, 1), p(1, 1));
+                if e == f || e == h {
+                    continue;
+                }
+                let across = distance(e, p(1, -1)) + distance(e, p(-1, 1))
+                    + distance(i, p(2, 1)) + distance(i, p(1, 2))
+                    + 4 * distance(h, f);
+                let along = distance(h, p(-1, 1)) + distance(h, p(1, 2))
+                    + distance(f, p(2, 1)) + distance(f, p(1, -1))
+                    + 4 * distance(e, i);
+                if across >= along {
+                    continue;
+                }
+                let color = if distance(e, f) <= distance(e, h) { f } else { h };
+                // Subpixel coordinates within the block, rotated to the corner
+                for sy in 0..SIZE {
+                    for sx in 0..SIZE {
+                        let (cx, cy) = rotate_subpixel(sx, sy, SIZE, xx, xy);
+                        let weight = match cx + cy {
+                            s if s > SIZE => 4,
+                            s if s == SIZE => 2,
+                            s if s == SIZE - 1 => 1,
+                            _ => 0,
+                        };
+                        if weight > 0 {
+                            let (ox, oy) = (x * SIZE + sx, y * SIZE + sy);
+                            let current = output.get(ox, oy);
+                            output.put(ox, oy, blend(current, color, weight));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    output
+}
+
+// Maps subpixel coordinates so that the tested corner becomes bottom-right
+fn rotate_subpixel(sx: usize, sy: usize, size: usize, xx: isize, xy: isize) -> (usize, usize) {
+    let last = size - 1;
+    match (xx, xy) {
+        (1, 0) => (sx, sy),
+        (0, 1) => (sy, last - sx),
+        (-1, 0) => (last - sx, last - sy),
+        _ => (last - sy, sx),
+    }
+}
+
+fn scanlines(frame: &Frame) -> Frame {
+    let mut output = frame.scaled(2);
+    for y in (1..output.height).step_by(2) {
+        for x in 0..output.width {
+            let color = output.get(x, y);
+            output.put(x, y, shade(color, [128, 128, 128]));
+        }
+    }
+    output
+}
+
+fn crt(frame: &Frame) -> Frame {
+    // Aperture grille, every subpixel column lets mostly one primary through
+    const MASK: [[u32; 3]; 3] = [[255, 180, 180], [180, 255, 180], [180, 180, 255]];
+    let mut output = frame.scaled(3);
+    for y in 0..output.height {
+        for x in 0..output.width {
+            let mut mask = MASK[x % 3];
+            if y % 3 == 2 {
+                for channel in mask.iter_mut() {
+                    *channel /= 2;
+                }
+            }
+            let color = output.get(x, y);
+            output.put(x, y, shade(color, mask));
+        }
+    }
+    output
+}
+
+// Multiplies color channels by the given factors out of 255
+fn shade(color: Color, factors: [u32; 3]) -> Color {
+    [
+        (color[0] as u32 * factors[0] / 255) as u8,
+        (color[1] as u32 * factors[1] / 255) as u8,
+        (color[2] as u32 * factors[2] / 255) as u8,
+        color[3],
+    ]
+}
@@ -1,3 +1,7 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
 pub static DEFAULT_FONTSET: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
     0x20, 0x60, 0x20, 0x20, 0x70, // 1
@@ -16,3 +20,203 @@ pub static DEFAULT_FONTSET: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
     0xF0, 0x80, 0xF0, 0x80, 0x80  // F
 ];
+
+
+// Font of the COSMAC VIP interpreter
+pub static VIP_FONTSET: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x60, 0x20, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80  // F
+];
+
+// Font of the DREAM 6800, glyphs are 3 pixels wide
+pub static DREAM6800_FONTSET: [u8; 80] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x40, 0x40, 0x40, 0x40, 0x40, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80  // F
+];
+
+// Font of the ETI-660, glyphs are 3 pixels wide
+pub static ETI660_FONTSET: [u8; 80] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x20, 0x20, 0x20, 0x20, 0x20, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80  // F
+];
+
+// Font of the FISH'N'CHIPS interpreter, with rounded glyphs
+pub static FISH_N_CHIPS_FONTSET: [u8; 80] = [
+    0x60, 0xA0, 0xA0, 0xA0, 0xC0, // 0
+    0x40, 0xC0, 0x40, 0x40, 0xE0, // 1
+    0xC0, 0x20, 0x40, 0x80, 0xE0, // 2
+    0xC0, 0x20, 0x40, 0x20, 0xC0, // 3
+    0x20, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xC0, 0x20, 0xC0, // 5
+    0x40, 0x80, 0xC0, 0xA0, 0x40, // 6
+    0xE0, 0x20, 0x60, 0x40, 0x40, // 7
+    0x40, 0xA0, 0x40, 0xA0, 0x40, // 8
+    0x40, 0xA0, 0x60, 0x20, 0x40, // 9
+    0x40, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xC0, 0xA0, 0xC0, // B
+    0x60, 0x80, 0x80, 0x80, 0x60, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xC0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80  // F
+];
+
+// SCHIP 8x10 digits used by Fx30
+pub static SCHIP_BIG_FONTSET: [u8; 100] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C  // 9
+];
+
+pub const FONT_SIZE: usize = 80;
+pub const CHAR_SIZE: usize = 5;
+pub const BIG_CHAR_SIZE: usize = 10;
+
+// Built-in fonts by name, the first one is the default
+pub const FONTS: [(&str, &[u8; 80]); 5] = [
+    ("default", &DEFAULT_FONTSET),
+    ("vip", &VIP_FONTSET),
+    ("dream6800", &DREAM6800_FONTSET),
+    ("eti660", &ETI660_FONTSET),
+    ("fishnchips", &FISH_N_CHIPS_FONTSET),
+];
+
+// Hex digits 5 rows high and large digits 10 rows high for SCHIP
+#[derive(Clone, Debug, PartialEq)]
+pub struct Font {
+    pub small: Vec<u8>,
+    pub big: Vec<u8>,
+}
+
+impl Default for Font {
+    fn default() -> Self {
+        Font::new(&DEFAULT_FONTSET)
+    }
+}
+
+impl Font {
+    // Font with the given small glyphs and SCHIP large digits
+    pub fn new(small: &[u8; 80]) -> Self {
+        Font {
+            small: small.to_vec(),
+            big: SCHIP_BIG_FONTSET.to_vec(),
+        }
+    }
+
+    pub fn builtin(name: &str) -> Option<Font> {
+        FONTS.iter()
+            .find(|(font, _)| font.eq_ignore_ascii_case(name))
+            .map(|(_, small)| Font::new(small))
+    }
+
+    // Reads a font image: 16 small glyphs of 5 bytes, optionally followed
+    // by large glyphs of 10 bytes, either 10 digits or all 16. Without
+    // them the SCHIP large digits are used.
+    pub fn from_bytes(data: &[u8]) -> Result<Font, String> {
+        let big = data.len().saturating_sub(FONT_SIZE);
+        if data.len() < FONT_SIZE || ![0, 10 * BIG_CHAR_SIZE, 16 * BIG_CHAR_SIZE].contains(&big) {
+            return Err(format!(
+                "Font must be {} bytes, {} or {} with large digits, got {}",
+                FONT_SIZE,
+                FONT_SIZE + 10 * BIG_CHAR_SIZE,
+                FONT_SIZE + 16 * BIG_CHAR_SIZE,
+                data.len()
+            ));
+        }
+        Ok(Font {
+            small: data[..FONT_SIZE].to_vec(),
+            big: if big == 0 { SCHIP_BIG_FONTSET.to_vec() } else { data[FONT_SIZE..].to_vec() },
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Font> {
+        let data = fs::read(path)?;
+        Font::from_bytes(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Bytes taken in memory, large glyphs follow the small ones
+    pub fn size(&self) -> usize {
+        self.small.len() + self.big.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_may_carry_large_digits() {
+        let small = Font::from_bytes(&[1; FONT_SIZE]).unwrap();
+        assert_eq!(small.small, vec![1; FONT_SIZE]);
+        assert_eq!(small.big, SCHIP_BIG_FONTSET.to_vec());
+        for &digits in [10, 16].iter() {
+            let font = Font::from_bytes(&vec![2; FONT_SIZE + digits * BIG_CHAR_SIZE]).unwrap();
+            assert_eq!(font.big, vec![2; digits * BIG_CHAR_SIZE]);
+            assert_eq!(font.size(), FONT_SIZE + digits * BIG_CHAR_SIZE);
+        }
+    }
+
+    #[test]
+    fn images_of_other_sizes_are_rejected() {
+        for &size in [0, 79, 81, 170, 200, 260].iter() {
+            assert!(Font::from_bytes(&vec![0; size]).is_err(), "{} bytes", size);
+        }
+    }
+
+    #[test]
+    fn builtin_fonts_are_found_by_name() {
+        for (name, small) in FONTS.iter() {
+            let font = Font::builtin(&name.to_ascii_uppercase()).unwrap();
+            assert_eq!(font.small, small.to_vec());
+        }
+        assert!(Font::builtin("comic").is_none());
+    }
+}
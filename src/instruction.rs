@@ -0,0 +1,640 @@
+use std::fmt;
+use std::str::FromStr;
+
+pub const OPCODE_SIZE: u16 = 2;
+
+// Interpreter an instruction first appeared in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Platform {
+    Chip8,
+    // COSMAC VIP with the VP-590 color board
+    Chip8X,
+    // ETI-660 computer, programs load at 600
+    Eti660,
+    Schip,
+    XoChip,
+    // SCHIP with a 256x192 color display and sampled sound
+    MegaChip,
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Platform::Chip8 => "chip8",
+            Platform::Chip8X => "chip8x",
+            Platform::Eti660 => "eti660",
+            Platform::Schip => "schip",
+            Platform::XoChip => "xochip",
+            Platform::MegaChip => "megachip",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Platform {
+    // Platform may be run, SCHIP and XO-CHIP are only recognized by the
+    // analyzer so far
+    pub fn emulated(&self) -> bool {
+        !matches!(self, Platform::Schip | Platform::XoChip)
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "chip8" => Ok(Platform::Chip8),
+            "chip8x" => Ok(Platform::Chip8X),
+            "eti660" => Ok(Platform::Eti660),
+            "megachip" => Ok(Platform::MegaChip),
+            // Recognized by the analyzer but not emulated yet
+            "schip" | "xochip" => Err(format!("Platform {} is not supported yet", s)),
+            _ => Err(format!("Unknown platform: {}", s)),
+        }
+    }
+}
+
+// Decoded instruction, registers are given by their numbers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    // 00E0
+    Cls,
+    // 00EE
+    Ret,
+    // 0nnn, machine code routine
+    Sys(u16),
+    // 1nnn
+    Jp(u16),
+    // 2nnn
+    Call(u16),
+    // 3xkk
+    SeByte(usize, u8),
+    // 4xkk
+    SneByte(usize, u8),
+    // 5xy0
+    Se(usize, usize),
+    // 6xkk
+    LdByte(usize, u8),
+    // 7xkk
+    AddByte(usize, u8),
+    // 8xy0
+    Ld(usize, usize),
+    // 8xy1
+    Or(usize, usize),
+    // 8xy2
+    And(usize, usize),
+    // 8xy3
+    Xor(usize, usize),
+    // 8xy4
+    Add(usize, usize),
+    // 8xy5
+    Sub(usize, usize),
+    // 8xy6
+    Shr(usize, usize),
+    // 8xy7
+    Subn(usize, usize),
+    // 8xyE
+    Shl(usize, usize),
+    // 9xy0
+    Sne(usize, usize),
+    // Annn
+    LdI(u16),
+    // Bnnn, x is the register used with jump quirk
+    JpV0(usize, u16),
+    // Cxkk
+    Rnd(usize, u8),
+    // Dxyn, SCHIP draws 16x16 sprite when n is 0
+    Drw(usize, usize, u8),
+    // Ex9E
+    Skp(usize),
+    // ExA1
+    Sknp(usize),
+    // Fx07
+    LdVxDt(usize),
+    // Fx0A
+    LdVxK(usize),
+    // Fx15
+    LdDtVx(usize),
+    // Fx18
+    LdStVx(usize),
+    // Fx1E
+    AddIVx(usize),
+    // Fx29
+    LdFVx(usize),
+    // Fx33
+    LdBVx(usize),
+    // Fx55
+    LdIVx(usize),
+    // Fx65
+    LdVxI(usize),
+
+    // SCHIP
+    // 00Cn
+    ScrollDown(u8),
+    // 00FB
+    ScrollRight,
+    // 00FC
+    ScrollLeft,
+    // 00FD
+    Exit,
+    // 00FE
+    Low,
+    // 00FF
+    High,
+    // Fx30
+    LdHfVx(usize),
+    // Fx75
+    LdRVx(usize),
+    // Fx85
+    LdVxR(usize),
+
+    // XO-CHIP
+    // 00Dn
+    ScrollUp(u8),
+    // 5xy2
+    SaveRange(usize, usize),
+    // 5xy3
+    LoadRange(usize, usize),
+    // F000 nnnn, address follows in the next word
+    LdILong,
+    // Fn01
+    Plane(u8),
+    // F002
+    Audio,
+    // Fx3A
+    Pitch(usize),
+
+    // CHIP-8X
+    // 02A0, cycles background color
+    Bgcol,
+    // 5xy1, adds nibbles without carry between them
+    AddNibble(usize, usize),
+    // Bxyn, colors zones given by Vx and Vx+1 with Vy, replaces Bnnn
+    Col(usize, usize, u8),
+    // ExF2, key Vx on the second keypad
+    SkpPad2(usize),
+    // ExF5
+    SknpPad2(usize),
+    // FxF8, output to I/O port
+    Out(usize),
+    // FxFB, input from I/O port
+    In(usize),
+
+    // MEGA-CHIP
+    // 0010
+    MegaOff,
+    // 0011
+    MegaOn,
+    // 01nn NNNN, low 16 address bits follow in the next word
+    LdIHigh(u8),
+    // 02nn, loads nn colors from I
+    LdPal(u8),
+    // 03nn
+    Sprw(u8),
+    // 04nn
+    Sprh(u8),
+    // 05nn, screen alpha
+    Alpha(u8),
+    // 060n, plays sample at I, once when n is 1
+    Digisnd(u8),
+    // 0700
+    Stopsnd,
+    // 080n
+    Bmode(u8),
+    // 09nn
+    Ccol(u8),
+}
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Option<Instruction> {
+        use Instruction::*;
+        let nibbles = (
+            (opcode & 0xF000) >> 12,
+            (opcode & 0x0F00) >> 8,
+            (opcode & 0x00F0) >> 4,
+            (opcode & 0x000F) as u8,
+        );
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+        let x = nibbles.1 as usize;
+        let y = nibbles.2 as usize;
+        let n = nibbles.3;
+
+        let instruction = match nibbles {
+            (0x00, 0x00, 0x0e, 0x00) => Cls,
+            (0x00, 0x00, 0x0e, 0x0e) => Ret,
+            (0x00, 0x00, 0x0c, _) => ScrollDown(n),
+            (0x00, 0x00, 0x0d, _) => ScrollUp(n),
+            (0x00, 0x00, 0x0f, 0x0b) => ScrollRight,
+            (0x00, 0x00, 0x0f, 0x0c) => ScrollLeft,
+            (0x00, 0x00, 0x0f, 0x0d) => Exit,
+            (0x00, 0x00, 0x0f, 0x0e) => Low,
+            (0x00, 0x00, 0x0f, 0x0f) => High,
+            (0x00, _, _, _) => Sys(nnn),
+            (0x01, _, _, _) => Jp(nnn),
+            (0x02, _, _, _) => Call(nnn),
+            (0x03, _, _, _) => SeByte(x, kk),
+            (0x04, _, _, _) => SneByte(x, kk),
+            (0x05, _, _, 0x00) => Se(x, y),
+            (0x05, _, _, 0x02) => SaveRange(x, y),
+            (0x05, _, _, 0x03) => LoadRange(x, y),
+            (0x06, _, _, _) => LdByte(x, kk),
+            (0x07, _, _, _) => AddByte(x, kk),
+            (0x08, _, _, 0x00) => Ld(x, y),
+            (0x08, _, _, 0x01) => Or(x, y),
+            (0x08, _, _, 0x02) => And(x, y),
+            (0x08, _, _, 0x03) => Xor(x, y),
+            (0x08, _, _, 0x04) => Add(x, y),
+            (0x08, _, _, 0x05) => Sub(x, y),
+            (0x08, _, _, 0x06) => Shr(x, y),
+            (0x08, _, _, 0x07) => Subn(x, y),
+            (0x08, _, _, 0x0e) => Shl(x, y),
+            (0x09, _, _, 0x00) => Sne(x, y),
+            (0x0a, _, _, _) => LdI(nnn),
+            (0x0b, _, _, _) => JpV0(x, nnn),
+            (0x0c, _, _, _) => Rnd(x, kk),
+            (0x0d, _, _, _) => Drw(x, y, n),
+            (0x0e, _, 0x09, 0x0e) => Skp(x),
+            (0x0e, _, 0x0a, 0x01) => Sknp(x),
+            (0x0f, 0x00, 0x00, 0x00) => LdILong,
+            (0x0f, _, 0x00, 0x01) => Plane(x as u8),
+            (0x0f, 0x00, 0x00, 0x02) => Audio,
+            (0x0f, _, 0x00, 0x07) => LdVxDt(x),
+            (0x0f, _, 0x00, 0x0a) => LdVxK(x),
+            (0x0f, _, 0x01, 0x05) => LdDtVx(x),
+            (0x0f, _, 0x01, 0x08) => LdStVx(x),
+            (0x0f, _, 0x01, 0x0e) => AddIVx(x),
+            (0x0f, _, 0x02, 0x09) => LdFVx(x),
+            (0x0f, _, 0x03, 0x00) => LdHfVx(x),
+            (0x0f, _, 0x03, 0x03) => LdBVx(x),
+            (0x0f, _, 0x03, 0x0a) => Pitch(x),
+            (0x0f, _, 0x05, 0x05) => LdIVx(x),
+            (0x0f, _, 0x06, 0x05) => LdVxI(x),
+            (0x0f, _, 0x07, 0x05) => LdRVx(x),
+            (0x0f, _, 0x08, 0x05) => LdVxR(x),
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    // Decodes an instruction of the given platform, CHIP-8X takes 02A0 and
+    // Bxyn over from CHIP-8 and adds a few instructions of its own,
+    // MEGA-CHIP takes over part of 0nnn
+    pub fn decode_for(opcode: u16, platform: Platform) -> Option<Instruction> {
+        use Instruction::*;
+        match platform {
+            Platform::Chip8X => {}
+            Platform::MegaChip => return Instruction::decode_mega(opcode),
+            _ => return Instruction::decode(opcode),
+        }
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        let instruction = match (opcode & 0xF000, opcode & 0x00FF) {
+            _ if opcode == 0x02A0 => Bgcol,
+            (0x5000, _) if opcode & 0xF == 1 => AddNibble(x, y),
+            (0xB000, _) => Col(x, y, (opcode & 0xF) as u8),
+            (0xE000, 0xF2) => SkpPad2(x),
+            (0xE000, 0xF5) => SknpPad2(x),
+            (0xF000, 0xF8) => Out(x),
+            (0xF000, 0xFB) => In(x),
+            _ => return Instruction::decode(opcode),
+        };
+        Some(instruction)
+    }
+
+    fn decode_mega(opcode: u16) -> Option<Instruction> {
+        use Instruction::*;
+        let nn = (opcode & 0x00FF) as u8;
+        let instruction = match opcode & 0xFF00 {
+            0x0000 if opcode == 0x0010 => MegaOff,
+            0x0000 if opcode == 0x0011 => MegaOn,
+            0x0100 => LdIHigh(nn),
+            0x0200 => LdPal(nn),
+            0x0300 => Sprw(nn),
+            0x0400 => Sprh(nn),
+            0x0500 => Alpha(nn),
+            0x0600 if nn < 0x10 => Digisnd(nn),
+            0x0700 if nn == 0 => Stopsnd,
+            0x0800 if nn < 0x10 => Bmode(nn),
+            0x0900 => Ccol(nn),
+            _ => return Instruction::decode(opcode),
+        };
+        Some(instruction)
+    }
+
+    // Oldest interpreter supporting the instruction
+    pub fn platform(&self) -> Platform {
+        use Instruction::*;
+        match self {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | Low | High | LdHfVx(_) | LdRVx(_)
+            | LdVxR(_) | Drw(_, _, 0) => Platform::Schip,
+            ScrollUp(_) | SaveRange(_, _) | LoadRange(_, _) | LdILong | Plane(_) | Audio | Pitch(_) => {
+                Platform::XoChip
+            }
+            Bgcol | AddNibble(..) | Col(..) | SkpPad2(_) | SknpPad2(_) | Out(_) | In(_) => Platform::Chip8X,
+            MegaOff | MegaOn | LdIHigh(_) | LdPal(_) | Sprw(_) | Sprh(_) | Alpha(_) | Digisnd(_) | Stopsnd
+            | Bmode(_) | Ccol(_) => Platform::MegaChip,
+            _ => Platform::Chip8,
+        }
+    }
+
+    // Opcode pattern identifying the instruction class, e.g. `8xy4`
+    pub fn pattern(&self) -> &'static str {
+        use Instruction::*;
+        match self {
+            Cls => "00E0",
+            Ret => "00EE",
+            Sys(_) => "0nnn",
+            Jp(_) => "1nnn",
+            Call(_) => "2nnn",
+            SeByte(..) => "3xkk",
+            SneByte(..) => "4xkk",
+            Se(..) => "5xy0",
+            LdByte(..) => "6xkk",
+            AddByte(..) => "7xkk",
+            Ld(..) => "8xy0",
+            Or(..) => "8xy1",
+            And(..) => "8xy2",
+            Xor(..) => "8xy3",
+            Add(..) => "8xy4",
+            Sub(..) => "8xy5",
+            Shr(..) => "8xy6",
+            Subn(..) => "8xy7",
+            Shl(..) => "8xyE",
+            Sne(..) => "9xy0",
+            LdI(_) => "Annn",
+            JpV0(..) => "Bnnn",
+            Rnd(..) => "Cxkk",
+            Drw(..) => "Dxyn",
+            Skp(_) => "Ex9E",
+            Sknp(_) => "ExA1",
+            LdVxDt(_) => "Fx07",
+            LdVxK(_) => "Fx0A",
+            LdDtVx(_) => "Fx15",
+            LdStVx(_) => "Fx18",
+            AddIVx(_) => "Fx1E",
+            LdFVx(_) => "Fx29",
+            LdBVx(_) => "Fx33",
+            LdIVx(_) => "Fx55",
+            LdVxI(_) => "Fx65",
+            ScrollDown(_) => "00Cn",
+            ScrollRight => "00FB",
+            ScrollLeft => "00FC",
+            Exit => "00FD",
+            Low => "00FE",
+            High => "00FF",
+            LdHfVx(_) => "Fx30",
+            LdRVx(_) => "Fx75",
+            LdVxR(_) => "Fx85",
+            ScrollUp(_) => "00Dn",
+            SaveRange(..) => "5xy2",
+            LoadRange(..) => "5xy3",
+            LdILong => "F000",
+            Plane(_) => "Fn01",
+            Audio => "F002",
+            Pitch(_) => "Fx3A",
+            Bgcol => "02A0",
+            AddNibble(..) => "5xy1",
+            Col(..) => "Bxyn",
+            SkpPad2(_) => "ExF2",
+            SknpPad2(_) => "ExF5",
+            Out(_) => "FxF8",
+            In(_) => "FxFB",
+            MegaOff => "0010",
+            MegaOn => "0011",
+            LdIHigh(_) => "01nn",
+            LdPal(_) => "02nn",
+            Sprw(_) => "03nn",
+            Sprh(_) => "04nn",
+            Alpha(_) => "05nn",
+            Digisnd(_) => "060n",
+            Stopsnd => "0700",
+            Bmode(_) => "080n",
+            Ccol(_) => "09nn",
+        }
+    }
+
+    // Size in bytes, including the address word of F000 and 01nn
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LdILong | Instruction::LdIHigh(_) => OPCODE_SIZE * 2,
+            _ => OPCODE_SIZE,
+        }
+    }
+
+    // Instruction conditionally skips the next one
+    pub fn is_skip(&self) -> bool {
+        use Instruction::*;
+        matches!(self, SeByte(..) | SneByte(..) | Se(..) | Sne(..) | Skp(_) | Sknp(_) | SkpPad2(_) | SknpPad2(_))
+    }
+}
+
+// Mnemonics follow Cowgod's Chip-8 technical reference
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Instruction::*;
+        match *self {
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            Sys(nnn) => write!(f, "SYS {:03X}", nnn),
+            Jp(nnn) => write!(f, "JP {:03X}", nnn),
+            Call(nnn) => write!(f, "CALL {:03X}", nnn),
+            SeByte(x, kk) => write!(f, "SE V{:X}, {:02X}", x, kk),
+            SneByte(x, kk) => write!(f, "SNE V{:X}, {:02X}", x, kk),
+            Se(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            LdByte(x, kk) => write!(f, "LD V{:X}, {:02X}", x, kk),
+            AddByte(x, kk) => write!(f, "ADD V{:X}, {:02X}", x, kk),
+            Ld(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Add(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Sne(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI(nnn) => write!(f, "LD I, {:03X}", nnn),
+            JpV0(_, nnn) => write!(f, "JP V0, {:03X}", nnn),
+            Rnd(x, kk) => write!(f, "RND V{:X}, {:02X}", x, kk),
+            Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {:X}", x, y, n),
+            Skp(x) => write!(f, "SKP V{:X}", x),
+            Sknp(x) => write!(f, "SKNP V{:X}", x),
+            LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            AddIVx(x) => write!(f, "ADD I, V{:X}", x),
+            LdFVx(x) => write!(f, "LD F, V{:X}", x),
+            LdBVx(x) => write!(f, "LD B, V{:X}", x),
+            LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            ScrollDown(n) => write!(f, "SCD {:X}", n),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            Low => write!(f, "LOW"),
+            High => write!(f, "HIGH"),
+            LdHfVx(x) => write!(f, "LD HF, V{:X}", x),
+            LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            ScrollUp(n) => write!(f, "SCU {:X}", n),
+            SaveRange(x, y) => write!(f, "SAVE V{:X}-V{:X}", x, y),
+            LoadRange(x, y) => write!(f, "LOAD V{:X}-V{:X}", x, y),
+            LdILong => write!(f, "LD I, LONG"),
+            Plane(n) => write!(f, "PLANE {:X}", n),
+            Audio => write!(f, "AUDIO"),
+            Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Bgcol => write!(f, "BGCOL"),
+            AddNibble(x, y) => write!(f, "ADDN V{:X}, V{:X}", x, y),
+            Col(x, y, n) => write!(f, "COL V{:X}, V{:X}, {:X}", x, y, n),
+            SkpPad2(x) => write!(f, "SKP2 V{:X}", x),
+            SknpPad2(x) => write!(f, "SKNP2 V{:X}", x),
+            Out(x) => write!(f, "OUT V{:X}", x),
+            In(x) => write!(f, "IN V{:X}", x),
+            MegaOff => write!(f, "MEGAOFF"),
+            MegaOn => write!(f, "MEGAON"),
+            LdIHigh(nn) => write!(f, "LDHI I, {:02X}", nn),
+            LdPal(nn) => write!(f, "LDPAL {:02X}", nn),
+            Sprw(nn) => write!(f, "SPRW {:02X}", nn),
+            Sprh(nn) => write!(f, "SPRH {:02X}", nn),
+            Alpha(nn) => write!(f, "ALPHA {:02X}", nn),
+            Digisnd(n) => write!(f, "DIGISND {:X}", n),
+            Stopsnd => write!(f, "STOPSND"),
+            Bmode(n) => write!(f, "BMODE {:X}", n),
+            Ccol(nn) => write!(f, "CCOL {:02X}", nn),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Instruction::*;
+
+    // Opcode of the instruction, the first word for F000 and 01nn
+    fn encode(instruction: Instruction) -> u16 {
+        let xy = |base: u16, x: usize, y: usize| base | (x as u16) << 8 | (y as u16) << 4;
+        let xkk = |base: u16, x: usize, kk: u8| base | (x as u16) << 8 | kk as u16;
+        let fx = |x: usize, low: u16| 0xF000 | (x as u16) << 8 | low;
+        match instruction {
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            Sys(nnn) => nnn,
+            Jp(nnn) => 0x1000 | nnn,
+            Call(nnn) => 0x2000 | nnn,
+            SeByte(x, kk) => xkk(0x3000, x, kk),
+            SneByte(x, kk) => xkk(0x4000, x, kk),
+            Se(x, y) => xy(0x5000, x, y),
+            LdByte(x, kk) => xkk(0x6000, x, kk),
+            AddByte(x, kk) => xkk(0x7000, x, kk),
+            Ld(x, y) => xy(0x8000, x, y),
+            Or(x, y) => xy(0x8001, x, y),
+            And(x, y) => xy(0x8002, x, y),
+            Xor(x, y) => xy(0x8003, x, y),
+            Add(x, y) => xy(0x8004, x, y),
+            Sub(x, y) => xy(0x8005, x, y),
+            Shr(x, y) => xy(0x8006, x, y),
+            Subn(x, y) => xy(0x8007, x, y),
+            Shl(x, y) => xy(0x800E, x, y),
+            Sne(x, y) => xy(0x9000, x, y),
+            LdI(nnn) => 0xA000 | nnn,
+            JpV0(_, nnn) => 0xB000 | nnn,
+            Rnd(x, kk) => xkk(0xC000, x, kk),
+            Drw(x, y, n) => xy(0xD000, x, y) | n as u16,
+            Skp(x) => fx(x, 0x9E) & 0xEFFF,
+            Sknp(x) => fx(x, 0xA1) & 0xEFFF,
+            LdVxDt(x) => fx(x, 0x07),
+            LdVxK(x) => fx(x, 0x0A),
+            LdDtVx(x) => fx(x, 0x15),
+            LdStVx(x) => fx(x, 0x18),
+            AddIVx(x) => fx(x, 0x1E),
+            LdFVx(x) => fx(x, 0x29),
+            LdBVx(x) => fx(x, 0x33),
+            LdIVx(x) => fx(x, 0x55),
+            LdVxI(x) => fx(x, 0x65),
+            ScrollDown(n) => 0x00C0 | n as u16,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Low => 0x00FE,
+            High => 0x00FF,
+            LdHfVx(x) => fx(x, 0x30),
+            LdRVx(x) => fx(x, 0x75),
+            LdVxR(x) => fx(x, 0x85),
+            ScrollUp(n) => 0x00D0 | n as u16,
+            SaveRange(x, y) => xy(0x5002, x, y),
+            LoadRange(x, y) => xy(0x5003, x, y),
+            LdILong => 0xF000,
+            Plane(n) => fx(n as usize, 0x01),
+            Audio => 0xF002,
+            Pitch(x) => fx(x, 0x3A),
+            Bgcol => 0x02A0,
+            AddNibble(x, y) => xy(0x5001, x, y),
+            Col(x, y, n) => xy(0xB000, x, y) | n as u16,
+            SkpPad2(x) => fx(x, 0xF2) & 0xEFFF,
+            SknpPad2(x) => fx(x, 0xF5) & 0xEFFF,
+            Out(x) => fx(x, 0xF8),
+            In(x) => fx(x, 0xFB),
+            MegaOff => 0x0010,
+            MegaOn => 0x0011,
+            LdIHigh(nn) => 0x0100 | nn as u16,
+            LdPal(nn) => 0x0200 | nn as u16,
+            Sprw(nn) => 0x0300 | nn as u16,
+            Sprh(nn) => 0x0400 | nn as u16,
+            Alpha(nn) => 0x0500 | nn as u16,
+            Digisnd(n) => 0x0600 | n as u16,
+            Stopsnd => 0x0700,
+            Bmode(n) => 0x0800 | n as u16,
+            Ccol(nn) => 0x0900 | nn as u16,
+        }
+    }
+
+    // Opcode fits the pattern, lower case letters stand for any digit
+    fn matches_pattern(opcode: u16, pattern: &str) -> bool {
+        let digits = format!("{:04X}", opcode);
+        pattern.chars().zip(digits.chars()).all(|(p, d)| p.is_ascii_lowercase() || p == d)
+    }
+
+    #[test]
+    fn decoded_instructions_encode_back() {
+        for platform in [Platform::Chip8, Platform::Chip8X, Platform::MegaChip].iter() {
+            for opcode in 0..=0xFFFF {
+                if let Some(instruction) = Instruction::decode_for(opcode, *platform) {
+                    assert_eq!(encode(instruction), opcode, "{} on {}", instruction, platform);
+                    assert!(matches_pattern(opcode, instruction.pattern()), "{:04X} {}", opcode, instruction.pattern());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn platform_instructions_are_decoded_for_their_platform() {
+        assert_eq!(Instruction::decode(0x02A0), Some(Sys(0x2A0)));
+        assert_eq!(Instruction::decode_for(0x02A0, Platform::Chip8X), Some(Bgcol));
+        assert_eq!(Instruction::decode(0xB123), Some(JpV0(1, 0x123)));
+        assert_eq!(Instruction::decode_for(0xB123, Platform::Chip8X), Some(Col(1, 2, 3)));
+        assert_eq!(Instruction::decode_for(0x0011, Platform::MegaChip), Some(MegaOn));
+        assert_eq!(Instruction::decode_for(0x00E0, Platform::MegaChip), Some(Cls));
+        assert_eq!(Instruction::decode(0x5004), None);
+        assert_eq!(Instruction::decode(0xE000), None);
+    }
+
+    #[test]
+    fn long_instructions_take_two_words() {
+        assert_eq!(LdILong.size(), 4);
+        assert_eq!(LdIHigh(0x12).size(), 4);
+        assert_eq!(Cls.size(), OPCODE_SIZE);
+    }
+
+    #[test]
+    fn unsupported_platforms_are_rejected() {
+        assert_eq!("CHIP8X".parse(), Ok(Platform::Chip8X));
+        assert!("schip".parse::<Platform>().is_err());
+        assert!("xochip".parse::<Platform>().is_err());
+        assert!(!Platform::Schip.emulated());
+        assert!(Platform::MegaChip.emulated());
+        assert!("pdp11".parse::<Platform>().is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::keypad::{KEYPAD_SIZE, KEY_COUNT};
+
+// Frontend independent name of a key on the host keyboard
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HostKey {
+    // Letter, digit or punctuation key, letters are upper case
+    Char(char),
+    // Key on numeric keypad: digit, `+`, `-`, `*`, `/` or `.`
+    Numpad(char),
+    NumpadEnter,
+    Up,
+    Down,
+    Left,
+    Right,
+    Space,
+    Enter,
+    Tab,
+    Backspace,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+}
+
+// Names of punctuation keys, usable where a bare symbol is awkward in TOML
+const CHAR_NAMES: [(&str, char); 11] = [
+    ("Comma", ','),
+    ("Period", '.'),
+    ("Slash", '/'),
+    ("Semicolon", ';'),
+    ("Apostrophe", '\''),
+    ("Minus", '-'),
+    ("Equals", '='),
+    ("LBracket", '['),
+    ("RBracket", ']'),
+    ("Backslash", '\\'),
+    ("Grave", '`'),
+];
+
+const NUMPAD_NAMES: [(&str, char); 5] = [
+    ("Add", '+'),
+    ("Subtract", '-'),
+    ("Multiply", '*'),
+    ("Divide", '/'),
+    ("Decimal", '.'),
+];
+
+const NAMED_KEYS: [(&str, HostKey); 15] = [
+    ("NumpadEnter", HostKey::NumpadEnter),
+    ("Up", HostKey::Up),
+    ("Down", HostKey::Down),
+    ("Left", HostKey::Left),
+    ("Right", HostKey::Right),
+    ("Space", HostKey::Space),
+    ("Enter", HostKey::Enter),
+    ("Tab", HostKey::Tab),
+    ("Backspace", HostKey::Backspace),
+    ("Insert", HostKey::Insert),
+    ("Delete", HostKey::Delete),
+    ("Home", HostKey::Home),
+    ("End", HostKey::End),
+    ("PageUp", HostKey::PageUp),
+    ("PageDown", HostKey::PageDown),
+];
+
+impl FromStr for HostKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            if c.is_ascii_graphic() {
+                return Ok(HostKey::Char(c.to_ascii_uppercase()));
+            }
+        }
+        if let Some((_, key)) = NAMED_KEYS.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+            return Ok(*key);
+        }
+        if let Some((_, c)) = CHAR_NAMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+            return Ok(HostKey::Char(*c));
+        }
+        if s.len() > 6 && s[..6].eq_ignore_ascii_case("numpad") {
+            let rest = &s[6..];
+            let mut chars = rest.chars();
+            if let (Some(c), None) = (chars.next(), chars.next()) {
+                if c.is_ascii_digit() {
+                    return Ok(HostKey::Numpad(c));
+                }
+            }
+            if let Some((_, c)) = NUMPAD_NAMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(rest)) {
+                return Ok(HostKey::Numpad(*c));
+            }
+        }
+        Err(format!("Unknown key: {}", s))
+    }
+}
+
+impl fmt::Display for HostKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostKey::Char(c) => write!(f, "{}", c),
+            HostKey::Numpad(c) if c.is_ascii_digit() => write!(f, "Numpad{}", c),
+            HostKey::Numpad(c) => {
+                let name = NUMPAD_NAMES.iter().find(|(_, symbol)| symbol == c).map_or("", |(name, _)| name);
+                write!(f, "Numpad{}", name)
+            }
+            key => {
+                let name = NAMED_KEYS.iter().find(|(_, named)| named == key).map_or("", |(name, _)| name);
+                write!(f, "{}", name)
+            }
+        }
+    }
+}
+
+// Built-in layouts, every string lists host keys for CHIP-8 keys 0 to F
+pub const PRESETS: [(&str, [&str; KEYPAD_SIZE]); 4] = [
+    // 1 2 3 4 / Q W E R / A S D F / Z X C V
+    ("qwerty", ["X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V"]),
+    // 1 2 3 4 / A Z E R / Q S D F / W X C V
+    ("azerty", ["X", "1", "2", "3", "A", "Z", "E", "Q", "S", "D", "W", "C", "4", "R", "F", "V"]),
+    // 1 2 3 4 / ' , . P / A O E U / ; Q J K
+    ("dvorak", ["Q", "1", "2", "3", "'", ",", ".", "A", "O", "E", ";", "J", "4", "P", "U", "K"]),
+    // 7 8 9 Divide / 4 5 6 Multiply / 1 2 3 Subtract / 0 Decimal Enter Add
+    ("numpad", [
+        "NumpadDecimal", "Numpad7", "Numpad8", "Numpad9", "Numpad4", "Numpad5", "Numpad6", "Numpad1",
+        "Numpad2", "Numpad3", "Numpad0", "NumpadEnter", "NumpadDivide", "NumpadMultiply",
+        "NumpadSubtract", "NumpadAdd",
+    ]),
+];
+
+// Maps host keys to CHIP-8 keys, several host keys may be bound to one key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    bindings: Vec<Vec<HostKey>>,
+}
+
+impl Keymap {
+    pub fn empty() -> Self {
+        Keymap {
+            bindings: vec![Vec::new(); KEY_COUNT],
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Keymap> {
+        let (_, keys) = PRESETS.iter().find(|(preset, _)| preset.eq_ignore_ascii_case(name))?;
+        let mut keymap = Keymap::empty();
+        for (key, host_key) in keys.iter().enumerate() {
+            keymap.bindings[key].push(host_key.parse().ok()?);
+        }
+        Some(keymap)
+    }
+
+    // Replaces host keys bound to the given CHIP-8 key
+    pub fn bind(&mut self, key: usize, host_keys: Vec<HostKey>) {
+        self.bindings[key] = host_keys;
+    }
+
+    pub fn host_keys(&self, key: usize) -> &[HostKey] {
+        &self.bindings[key]
+    }
+
+    // Returns all CHIP-8 keys the host key is bound to
+    pub fn keys(&self, host_key: HostKey) -> impl Iterator<Item = usize> + '_ {
+        self.bindings
+            .iter()
+            .enumerate()
+            .filter(move |(_, host_keys)| host_keys.contains(&host_key))
+            .map(|(key, _)| key)
+    }
+
+    // Iterates over all (CHIP-8 key, host key) pairs
+    pub fn bindings(&self) -> impl Iterator<Item = (usize, HostKey)> + '_ {
+        self.bindings
+            .iter()
+            .enumerate()
+            .flat_map(|(key, host_keys)| host_keys.iter().map(move |host_key| (key, *host_key)))
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::preset(PRESETS[0].0).unwrap()
+    }
+}
@@ -1,16 +1,29 @@
 use std::fmt::{Debug, Formatter, Result};
 
 pub const KEYPAD_SIZE: usize = 16;
+// Keys of both keypads, CHIP-8X reads the second one as keys 10 to 1F
+pub const KEY_COUNT: usize = KEYPAD_SIZE * 2;
 
 #[derive(Copy, Clone)]
 pub struct Keypad {
-    keys: [bool; KEYPAD_SIZE]
+    keys: [bool; KEY_COUNT],
+    // Keys which went down or up since edges were last cleared
+    pressed_edges: [bool; KEY_COUNT],
+    released_edges: [bool; KEY_COUNT],
+}
+
+impl Default for Keypad {
+    fn default() -> Self {
+        Keypad::new()
+    }
 }
 
 impl Keypad {
     pub fn new() -> Self {
         Keypad {
-            keys: [false; KEYPAD_SIZE],
+            keys: [false; KEY_COUNT],
+            pressed_edges: [false; KEY_COUNT],
+            released_edges: [false; KEY_COUNT],
         }
     }
 
@@ -18,16 +31,99 @@ impl Keypad {
         self.keys[key] = true;
     }
 
+    pub fn release(&mut self, key: usize) {
+        self.keys[key] = false;
+    }
+
+    // Key is currently down
     pub fn pressed(&self, key: usize) -> bool {
         self.keys[key]
     }
 
+    // Key went down since edges were last cleared
+    pub fn just_pressed(&self, key: usize) -> bool {
+        self.pressed_edges[key]
+    }
+
+    // Key went up since edges were last cleared
+    pub fn just_released(&self, key: usize) -> bool {
+        self.released_edges[key]
+    }
+
+    // Forgets transitions once an instruction has seen them
+    pub fn clear_edges(&mut self) {
+        self.pressed_edges = [false; KEY_COUNT];
+        self.released_edges = [false; KEY_COUNT];
+    }
+
+    // Takes key states from a snapshot made by a frontend, recording
+    // down/up transitions since the previous update. Transitions add up
+    // until cleared, so updates without an instruction run in between
+    // never lose them.
+    pub fn update(&mut self, snapshot: &Keypad) {
+        for key in 0..KEY_COUNT {
+            self.pressed_edges[key] |= snapshot.keys[key] && !self.keys[key];
+            self.released_edges[key] |= !snapshot.keys[key] && self.keys[key];
+            self.keys[key] = snapshot.keys[key];
+        }
+    }
 }
 
 impl Debug for Keypad {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("")
             .field("keys", &self.keys)
+            .field("pressed_edges", &self.pressed_edges)
+            .field("released_edges", &self.released_edges)
             .finish()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(keys: &[usize]) -> Keypad {
+        let mut keypad = Keypad::new();
+        for &key in keys {
+            keypad.press(key);
+        }
+        keypad
+    }
+
+    #[test]
+    fn update_records_transitions() {
+        let mut keypad = Keypad::new();
+        keypad.update(&snapshot(&[5]));
+        assert!(keypad.pressed(5));
+        assert!(keypad.just_pressed(5));
+        assert!(!keypad.just_released(5));
+        keypad.clear_edges();
+        keypad.update(&snapshot(&[]));
+        assert!(!keypad.pressed(5));
+        assert!(!keypad.just_pressed(5));
+        assert!(keypad.just_released(5));
+    }
+
+    #[test]
+    fn edges_stay_until_cleared() {
+        let mut keypad = Keypad::new();
+        keypad.update(&snapshot(&[0x1A]));
+        keypad.update(&snapshot(&[]));
+        keypad.update(&snapshot(&[]));
+        assert!(keypad.just_pressed(0x1A));
+        assert!(keypad.just_released(0x1A));
+        keypad.clear_edges();
+        assert!(!keypad.just_pressed(0x1A));
+        assert!(!keypad.just_released(0x1A));
+    }
+
+    #[test]
+    fn holding_a_key_is_no_transition() {
+        let mut keypad = Keypad::new();
+        keypad.update(&snapshot(&[3]));
+        keypad.clear_edges();
+        keypad.update(&snapshot(&[3]));
+        assert!(keypad.pressed(3));
+        assert!(!keypad.just_pressed(3));
+    }
+}
@@ -0,0 +1,28 @@
+pub mod font;
+pub mod vm;
+pub mod keypad;
+pub mod stack;
+pub mod display;
+pub mod memory;
+pub mod chip8;
+pub mod palette;
+pub mod screenshot;
+pub mod record;
+pub mod config;
+pub mod renderer;
+pub mod filter;
+pub mod tui;
+pub mod keymap;
+pub mod quirks;
+pub mod overlay;
+pub mod database;
+pub mod instruction;
+pub mod analyze;
+pub mod cfg;
+pub mod coverage;
+pub mod profiler;
+pub mod timing;
+pub mod cdp1802;
+pub mod vip;
+pub mod machine;
+pub mod megachip;
@@ -0,0 +1,230 @@
+use std::str::FromStr;
+use crate::cdp1802::{Bus, Cpu};
+use crate::display::Display;
+use crate::keypad::Keypad;
+use crate::memory::{Memory, MEMORY_SIZE, VIP_DISPLAY_ADDRESS};
+
+// Where the VIP interpreter keeps V0-VF, its stack and the display,
+// machine code finds them there
+const REGISTERS_ADDRESS: usize = 0xef0;
+const STACK_ADDRESS: u16 = 0xecf;
+const DISPLAY_ADDRESS: usize = VIP_DISPLAY_ADDRESS;
+const DISPLAY_SIZE: usize = 0x100;
+
+// Machine cycles after which a subroutine is considered stuck
+const CYCLE_LIMIT: u32 = 10_000_000;
+
+// What 0nnn does
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MachineCode {
+    // Runs the CDP1802 subroutine at nnn, as on COSMAC VIP
+    Run,
+    // Ignores the instruction
+    Nop,
+    // Halts the program
+    Error,
+}
+
+impl FromStr for MachineCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "run" => Ok(MachineCode::Run),
+            "nop" => Ok(MachineCode::Nop),
+            "error" => Ok(MachineCode::Error),
+            _ => Err(format!("Unknown machine code mode: {}", s)),
+        }
+    }
+}
+
+// Interpreter state as machine code sees it: memory with V registers
+// and the display mapped where the VIP interpreter keeps them
+struct Hybrid<'a> {
+    memory: &'a mut Memory,
+    v: &'a mut [u8; 16],
+    display: &'a mut Display,
+    keypad: &'a Keypad,
+    // Key selected by OUT 2, reported on EF3 when down
+    latch: u8,
+}
+
+impl Bus for Hybrid<'_> {
+    fn read(&mut self, address: u16) -> u8 {
+        let address = address as usize % MEMORY_SIZE;
+        match address {
+            _ if address >= DISPLAY_ADDRESS => {
+                let (x, y) = display_position(self.display, address - DISPLAY_ADDRESS);
+                (0..8).fold(0, |byte, bit| byte << 1 | self.display.peek(x + bit, y))
+            }
+            _ if address >= REGISTERS_ADDRESS => self.v[address - REGISTERS_ADDRESS],
+            _ => self.memory.peek(address),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let address = address as usize % MEMORY_SIZE;
+        match address {
+            _ if address >= DISPLAY_ADDRESS => {
+                let (x, y) = display_position(self.display, address - DISPLAY_ADDRESS);
+                for bit in 0..8 {
+                    self.display.poke(x + bit, y, value >> (7 - bit) & 1);
+                }
+            }
+            _ if address >= REGISTERS_ADDRESS => self.v[address - REGISTERS_ADDRESS] = value,
+            _ => self.memory.poke(address, value),
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port == 2 {
+            self.latch = value & 0xf;
+        }
+    }
+
+    fn input(&mut self, _port: u8) -> u8 {
+        0xff
+    }
+
+    fn flag(&self, number: u8) -> bool {
+        number == 3 && self.keypad.pressed(self.latch as usize)
+    }
+}
+
+// Display bytes hold 8 pixels each, row by row
+fn display_position(display: &Display, offset: usize) -> (usize, usize) {
+    let bytes_per_row = display.width() / 8;
+    let offset = offset % DISPLAY_SIZE;
+    (offset % bytes_per_row * 8, offset / bytes_per_row)
+}
+
+// CHIP-8 state handed to a machine code subroutine
+pub struct Context<'a> {
+    pub memory: &'a mut Memory,
+    pub v: &'a mut [u8; 16],
+    pub display: &'a mut Display,
+    pub keypad: &'a Keypad,
+    pub index: &'a mut u32,
+    pub pc: &'a mut u16,
+    pub delay_timer: &'a mut u8,
+    pub sound_timer: &'a mut u8,
+}
+
+// Runs the CDP1802 subroutine at the given address until it returns to
+// the interpreter with SEP R4. Registers are set up the way the VIP
+// interpreter leaves them: R3 is the program counter, R2 the stack,
+// RA holds I, R5 the CHIP-8 program counter and R8 the timers.
+// Returns machine cycles taken.
+pub fn call(address: u16, context: Context) -> Result<u32, String> {
+    let mut cpu = Cpu::new();
+    cpu.ie = false;
+    cpu.p = 3;
+    cpu.x = 2;
+    cpu.r[2] = STACK_ADDRESS;
+    cpu.r[3] = address;
+    cpu.r[5] = *context.pc;
+    cpu.r[8] = (*context.delay_timer as u16) << 8 | *context.sound_timer as u16;
+    cpu.r[0xa] = *context.index as u16;
+    cpu.r[0xb] = DISPLAY_ADDRESS as u16;
+    let mut bus = Hybrid {
+        memory: context.memory,
+        v: context.v,
+        display: context.display,
+        keypad: context.keypad,
+        latch: 0,
+    };
+    let mut cycles = 0;
+    while cpu.p != 4 {
+        if cycles > CYCLE_LIMIT {
+            return Err(format!("Machine code at {:03X} did not return, stuck at {:04X}", address, cpu.pc()));
+        }
+        cycles += cpu.step(&mut bus);
+    }
+    *context.index = cpu.r[0xa] as u32;
+    *context.pc = cpu.r[5];
+    *context.delay_timer = (cpu.r[8] >> 8) as u8;
+    *context.sound_timer = cpu.r[8] as u8;
+    Ok(cycles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct State {
+        memory: Memory,
+        v: [u8; 16],
+        display: Display,
+        keypad: Keypad,
+        index: u32,
+        pc: u16,
+        delay_timer: u8,
+        sound_timer: u8,
+    }
+
+    impl State {
+        fn new(routine: &[u8]) -> Self {
+            let mut memory = Memory::new();
+            memory.load_rom_at(routine, 0x300);
+            State {
+                memory,
+                v: [0; 16],
+                display: Display::new(),
+                keypad: Keypad::new(),
+                index: 0x123,
+                pc: 0x202,
+                delay_timer: 7,
+                sound_timer: 3,
+            }
+        }
+
+        fn call(&mut self) -> Result<u32, String> {
+            call(0x300, Context {
+                memory: &mut self.memory,
+                v: &mut self.v,
+                display: &mut self.display,
+                keypad: &self.keypad,
+                index: &mut self.index,
+                pc: &mut self.pc,
+                delay_timer: &mut self.delay_timer,
+                sound_timer: &mut self.sound_timer,
+            })
+        }
+    }
+
+    #[test]
+    fn routines_see_registers_and_display_where_the_interpreter_has_them() {
+        let mut state = State::new(&[
+            0xF8, 0x0E, 0xB6, // LDI 0E; PHI R6
+            0xF8, 0xF1, 0xA6, // LDI F1; PLO R6
+            0xF8, 0x42, 0x56, // LDI 42; STR R6, V1 = 42
+            0x9B, 0xB6, 0x8B, 0xA6, // GHI RB; PHI R6; GLO RB; PLO R6
+            0xF8, 0x80, 0x56, // LDI 80; STR R6, top left pixel
+            0x8A, 0xFC, 0x01, 0xAA, // GLO RA; ADI 01; PLO RA, I + 1
+            0xD4, // SEP R4
+        ]);
+        let cycles = state.call().unwrap();
+        // 16 instructions of 2 machine cycles
+        assert_eq!(cycles, 16 * 2);
+        assert_eq!(state.v[1], 0x42);
+        assert_eq!(state.display.peek(0, 0), 1);
+        assert_eq!(state.display.peek(1, 0), 0);
+        assert_eq!(state.index, 0x124);
+        assert_eq!((state.pc, state.delay_timer, state.sound_timer), (0x202, 7, 3));
+    }
+
+    #[test]
+    fn routines_which_never_return_are_stopped() {
+        // BR 00
+        let mut state = State::new(&[0x30, 0x00]);
+        let error = state.call().unwrap_err();
+        assert_eq!(error, "Machine code at 300 did not return, stuck at 0300");
+    }
+
+    #[test]
+    fn modes_parse_by_name() {
+        assert_eq!("NOP".parse(), Ok(MachineCode::Nop));
+        assert_eq!("run".parse(), Ok(MachineCode::Run));
+        assert!("skip".parse::<MachineCode>().is_err());
+    }
+}
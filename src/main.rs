@@ -1,29 +1,318 @@
-mod font;
-mod vm;
-mod keypad;
-mod stack;
-mod display;
-mod memory;
-mod chip8;
-
-use clap::clap_app;
-use std::io;
+use chip8::analyze;
+use chip8::cfg::{Graph, Rom};
+use chip8::coverage;
+use chip8::config::{Config, KeymapConfig, PaletteConfig, RenderConfig, Settings};
+use chip8::database;
+use chip8::font::{self, Font};
+use chip8::instruction::Platform;
+use chip8::keymap::Keymap;
+use chip8::machine::MachineCode;
+use chip8::memory::{self, Layout, MEMORY_SIZE, VIP_DISPLAY_ADDRESS, VIP_STACK_ADDRESS};
+use chip8::megachip::MEGA_MEMORY_SIZE;
+use chip8::quirks::{self, Quirks};
+use chip8::palette::Palette;
+use chip8::record::Format;
+use chip8::stack::Overflow;
+use chip8::timing::Timing;
+use chip8::tui::{self, Charset};
+use chip8::vip::Firmware;
+use chip8::vm;
+use clap::{clap_app, ArgMatches};
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 fn main() -> io::Result<()> {
     let matches = clap_app!(chip8 =>
         (about: "Chip-8 Emulator")
         (version: "0.1")
-        (@arg SLEEP_DURATION: -s --sleep +takes_value "Sets a timeout between ticks in milliseconds, default 2")
+        (@setting SubcommandsNegateReqs)
+        (@arg SLEEP_DURATION: -s --sleep +takes_value "Sets a timeout between ticks in milliseconds, fractions allowed, default 2")
+        (@arg TIMING: --timing +takes_value possible_value[ticks vip] "Sets instruction timing: one tick each or COSMAC VIP machine cycles")
+        (@arg VIP: --vip +takes_value "Emulates a COSMAC VIP running the CHIP-8 interpreter from the given image")
+        (@arg VIP_MONITOR: --"vip-monitor" +takes_value "Loads the VIP monitor ROM from the given image, by default the font is substituted")
+        (@arg CONFIG: -c --config +takes_value "Loads settings from the given TOML file instead of the user config")
+        (@arg SCALE: --scale +takes_value "Sets an integer window scale, by default the window takes 2/3 of the screen height")
+        (@arg THEME: -t --theme +takes_value "Sets color theme: classic, green, amber, lcd or high-contrast")
+        (@arg FOREGROUND: --fg +takes_value "Sets foreground color as #rrggbb")
+        (@arg BACKGROUND: --bg +takes_value "Sets background color as #rrggbb")
+        (@arg PERSISTENCE: --persistence +takes_value possible_value[off fade blend] "Emulates phosphor persistence to reduce flicker, default off")
+        (@arg DECAY: --decay +takes_value "Sets brightness fraction a pixel loses every frame in fade mode, default 0.4")
+        (@arg FILTER: -f --filter +takes_value possible_value[none scale2x scale3x scale4x xbr scanlines crt] "Sets upscaling filter, default none")
+        (@arg KEYPAD: --keypad "Shows clickable hex keypad beside the display")
+        (@arg MUTE: --mute "Turns sound off")
+        (@arg KEYMAP: -k --keymap +takes_value possible_value[qwerty azerty dvorak numpad] "Sets keyboard layout, default qwerty")
+        (@arg QUIRK: -q --quirk +takes_value +multiple number_of_values(1) "Switches a quirk as name, name=on or name=off")
+        (@arg PLATFORM: --platform +takes_value possible_value[chip8 chip8x eti660 megachip] "Sets instruction set: CHIP-8, CHIP-8X with color and a second keypad, ETI-660 or MEGA-CHIP, default chip8")
+        (@arg LOAD_ADDRESS: --"load-address" +takes_value "Loads and starts the program at the given hex address instead of the platform default")
+        (@arg FONT: --font +takes_value "Sets font: default, vip, dream6800, eti660, fishnchips or path to a font image")
+        (@arg MEMORY_MAP: --"memory-map" "Keeps the call stack at EA0 and the display at F00 in memory, as on COSMAC VIP")
+        (@arg STACK_DEPTH: --"stack-depth" +takes_value "Sets how many nested calls fit, default 16, 12 for CHIP-8X and with VIP timing, firmware or memory map")
+        (@arg STACK_OVERFLOW: --"stack-overflow" +takes_value possible_value[error halt wrap] "Sets what stack overflow and underflow do: stop with an error, halt the program or wrap around")
+        (@arg FONT_ADDRESS: --"font-address" +takes_value "Puts the font at the given hex address instead of 050")
+        (@arg MACHINE_CODE: --"machine-code" +takes_value possible_value[run nop error] "Sets what 0nnn does: runs CDP1802 machine code, nothing or stops with an error")
+        (@arg SCREENSHOT: --screenshot +takes_value "Saves a PNG screenshot to the given file on exit")
+        (@arg SCREENSHOT_SCALE: --"screenshot-scale" +takes_value "Sets an integer scale factor for screenshots, default 8")
+        (@arg RECORD: --record +takes_value "Records gameplay to the given file (.gif, .y4m or .rgba), `-` for stdout")
+        (@arg RECORD_FORMAT: --"record-format" +takes_value possible_value[gif y4m raw] "Sets recording format, by default guessed from file extension")
+        (@arg RECORD_SCALE: --"record-scale" +takes_value "Sets an integer scale factor for recorded frames, default 8")
+        (@arg COVERAGE: --coverage +takes_value "Saves memory coverage report to the given file (.txt, .json or .png) on exit")
+        (@arg COVERAGE_FORMAT: --"coverage-format" +takes_value possible_value[text json png] "Sets coverage report format, by default guessed from file extension")
+        (@arg PROFILE: --profile "Prints instruction hotspots and subroutine costs to stderr on exit")
+        (@arg PROFILE_FOLDED: --"profile-folded" +takes_value "Saves folded call stacks for flame graphs to the given file on exit, implies profiling")
+        (@arg TUI: --tui "Runs in the terminal instead of a window")
+        (@arg CHARSET: --charset +takes_value possible_value[half braille] "Sets characters used to draw in the terminal, default half")
+        (@arg HEADLESS: --headless +takes_value "Runs the given number of ticks without a window, then exits")
         (@arg ROM: +required "Path to ROM to load")
+        (@subcommand analyze =>
+            (about: "Guesses platform and quirks a ROM needs without running it")
+            (@arg PLATFORM: --platform +takes_value possible_value[chip8 chip8x eti660 megachip] "Decodes instructions of the given platform from its load address, default chip8")
+            (@arg LOAD_ADDRESS: --"load-address" +takes_value "Traces the program from the given hex address instead of the platform default")
+            (@arg ROM: +required "Path to ROM to analyze")
+        )
+        (@subcommand cfg =>
+            (about: "Recovers control-flow graph of a ROM")
+            (@arg FORMAT: -f --format +takes_value possible_value[dot json] "Sets output format, default dot")
+            (@arg OUTPUT: -o --output +takes_value "Writes the graph to the given file instead of stdout")
+            (@arg PLATFORM: --platform +takes_value possible_value[chip8 chip8x eti660 megachip] "Decodes instructions of the given platform from its load address, default chip8")
+            (@arg LOAD_ADDRESS: --"load-address" +takes_value "Traces the program from the given hex address instead of the platform default")
+            (@arg ROM: +required "Path to ROM to analyze")
+        )
     ).get_matches();
 
-    let sleep_duration: u64 = matches.value_of_t("SLEEP_DURATION").unwrap_or(2);
+    if let Some(matches) = matches.subcommand_matches("analyze") {
+        let data = std::fs::read(matches.value_of("ROM").expect("No ROM filename set"))?;
+        println!("{}", analyze::analyze(&rom(matches, &data)?));
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("cfg") {
+        let data = std::fs::read(matches.value_of("ROM").expect("No ROM filename set"))?;
+        let graph = Graph::build(&rom(matches, &data)?);
+        let text = match matches.value_of("FORMAT") {
+            Some("json") => graph.to_json(),
+            _ => graph.to_dot(),
+        };
+        return match matches.value_of("OUTPUT") {
+            Some(path) => std::fs::write(path, text),
+            None => io::stdout().write_all(text.as_bytes()),
+        };
+    }
+
     let filename = matches
         .value_of("ROM")
         .expect("No ROM filename set");
-
     let data = std::fs::read(filename)?;
-    vm::run(&data, sleep_duration);
-    Ok(())
+    let config = match matches.value_of("CONFIG") {
+        Some(path) => Config::load(path)?,
+        None => Config::load_default()?,
+    };
+    let args = Settings {
+        sleep: parse_arg(&matches, "SLEEP_DURATION"),
+        timing: matches.value_of("TIMING").map(String::from),
+        machine_code: matches.value_of("MACHINE_CODE").map(String::from),
+        platform: matches.value_of("PLATFORM").map(String::from),
+        load_address: matches.value_of("LOAD_ADDRESS").map(memory::parse_address).transpose().map_err(invalid_input)?,
+        font: matches.value_of("FONT").map(String::from),
+        memory_map: matches.is_present("MEMORY_MAP").then_some(true),
+        audio: matches.is_present("MUTE").then_some(false),
+        stack_depth: parse_arg(&matches, "STACK_DEPTH"),
+        stack_overflow: matches.value_of("STACK_OVERFLOW").map(String::from),
+        font_address: matches.value_of("FONT_ADDRESS").map(memory::parse_address).transpose().map_err(invalid_input)?,
+        scale: parse_arg(&matches, "SCALE"),
+        screenshot_scale: parse_arg(&matches, "SCREENSHOT_SCALE"),
+        record_scale: parse_arg(&matches, "RECORD_SCALE"),
+        palette: PaletteConfig {
+            theme: matches.value_of("THEME").map(String::from),
+            foreground: matches.value_of("FOREGROUND").map(String::from),
+            background: matches.value_of("BACKGROUND").map(String::from),
+        },
+        render: RenderConfig {
+            persistence: matches.value_of("PERSISTENCE").map(String::from),
+            decay: parse_arg(&matches, "DECAY"),
+            filter: matches.value_of("FILTER").map(String::from),
+            keypad: matches.is_present("KEYPAD").then_some(true),
+        },
+        keymap: KeymapConfig {
+            preset: matches.value_of("KEYMAP").map(String::from),
+            ..KeymapConfig::default()
+        },
+        quirks: matches
+            .values_of("QUIRK")
+            .into_iter()
+            .flatten()
+            .map(quirks::parse_setting)
+            .collect::<Result<_, _>>()
+            .map_err(invalid_input)?,
+    };
+    // Command line takes precedence over ROM overrides, which take
+    // precedence over the database and global settings
+    let settings = config.settings(filename, &data).merge(&args);
+
+    let palette = settings.palette.apply(Palette::default())
+        .map_err(invalid_input)?;
+    let persistence = settings.render.persistence()
+        .map_err(invalid_input)?;
+    let filter = settings.render.filter()
+        .map_err(invalid_input)?;
+    let sleep_duration = match settings.sleep {
+        Some(ms) if ms.is_finite() && ms >= 0.0 => Duration::from_secs_f64(ms / 1000.0),
+        Some(ms) => return Err(invalid_input(format!("Bad sleep duration: {}", ms))),
+        None => vm::Options::default().sleep_duration,
+    };
+    let timing = match &settings.timing {
+        Some(timing) => timing.parse().map_err(invalid_input)?,
+        None => Timing::Ticks,
+    };
+    let machine_code = match &settings.machine_code {
+        Some(mode) => mode.parse().map_err(invalid_input)?,
+        None => MachineCode::Run,
+    };
+    let platform = match &settings.platform {
+        Some(platform) => platform.parse().map_err(invalid_input)?,
+        None => Platform::Chip8,
+    };
+    let mut layout = Layout::for_platform(platform);
+    layout.start = settings.load_address.unwrap_or(layout.start);
+    layout.font = settings.font_address.unwrap_or(layout.font);
+    let memory_size = if platform == Platform::MegaChip { MEGA_MEMORY_SIZE } else { MEMORY_SIZE };
+    // Fonts are looked up by name first, ETI-660 programs get their own
+    let font = match &settings.font {
+        Some(font) => match Font::builtin(font) {
+            Some(font) => font,
+            None => Font::load(font).map_err(|e| io::Error::new(e.kind(), format!("Font {}: {}", font, e)))?,
+        },
+        None if platform == Platform::Eti660 => Font::new(&font::ETI660_FONTSET),
+        None => Font::default(),
+    };
+    layout.check(memory_size, font.size(), data.len()).map_err(invalid_input)?;
+    // MEGA-CHIP programs replace the display with one too big to mirror
+    if settings.memory_map == Some(true) && platform == Platform::MegaChip {
+        return Err(invalid_input(String::from("The memory map is not supported on MEGA-CHIP")));
+    }
+    let stack_overflow = match &settings.stack_overflow {
+        Some(mode) => mode.parse().map_err(invalid_input)?,
+        None => Overflow::Error,
+    };
+    match settings.stack_depth {
+        Some(0) => return Err(invalid_input(String::from("Stack depth must be at least 1"))),
+        // Entries kept in memory must fit below the display at F00
+        Some(depth) if settings.memory_map == Some(true) && VIP_STACK_ADDRESS + depth * 2 > VIP_DISPLAY_ADDRESS => {
+            return Err(invalid_input(format!(
+                "Stack of {} entries at {:03X} runs into the display at {:03X}",
+                depth, VIP_STACK_ADDRESS, VIP_DISPLAY_ADDRESS,
+            )));
+        }
+        _ => {}
+    }
+    let vip = match matches.value_of("VIP") {
+        Some(path) => {
+            let monitor = match matches.value_of("VIP_MONITOR") {
+                Some(path) => Some(std::fs::read(path)?),
+                None => None,
+            };
+            Some(Firmware::new(std::fs::read(path)?, monitor).map_err(invalid_input)?)
+        }
+        None => None,
+    };
+    let program = database::find(&data);
+    if let Some(program) = program {
+        match program.platform.as_deref() {
+            Some(platform) if platform.parse::<Platform>().is_err() => {
+                eprintln!("{} is made for {}, it may not run correctly", program, platform);
+            }
+            _ => {}
+        }
+    }
+    let keymap = settings.keymap.apply(Keymap::default())
+        .and_then(|keymap| match program {
+            Some(program) => program.apply_hints(keymap),
+            None => Ok(keymap),
+        })
+        .map_err(invalid_input)?;
+    let quirks = settings.apply_quirks(Quirks::default())
+        .map_err(invalid_input)?;
+
+    let defaults = vm::Options::default();
+    let record = matches.value_of("RECORD").map(PathBuf::from);
+    let record_format = parse_arg(&matches, "RECORD_FORMAT").unwrap_or_else(|| {
+        record.as_ref()
+            .and_then(Format::from_path)
+            .unwrap_or(defaults.record_format)
+    });
+    let coverage = matches.value_of("COVERAGE").map(PathBuf::from);
+    let coverage_format = parse_arg(&matches, "COVERAGE_FORMAT").unwrap_or_else(|| {
+        coverage.as_ref()
+            .and_then(coverage::Format::from_path)
+            .unwrap_or(defaults.coverage_format)
+    });
+    let options = vm::Options {
+        sleep_duration,
+        timing,
+        platform,
+        layout,
+        memory_map: settings.memory_map.unwrap_or(defaults.memory_map),
+        stack_depth: settings.stack_depth,
+        stack_overflow,
+        font,
+        vip,
+        screenshot_scale: settings.screenshot_scale.unwrap_or(defaults.screenshot_scale),
+        palette,
+        persistence,
+        filter,
+        window_scale: settings.scale,
+        show_keypad: settings.render.keypad.unwrap_or(defaults.show_keypad),
+        audio: settings.audio.unwrap_or(defaults.audio),
+        keymap,
+        quirks,
+        machine_code,
+        screenshot: matches.value_of("SCREENSHOT").map(PathBuf::from),
+        record,
+        record_format,
+        record_scale: settings.record_scale.unwrap_or(defaults.record_scale),
+        coverage,
+        coverage_format,
+        profile: matches.is_present("PROFILE"),
+        profile_folded: matches.value_of("PROFILE_FOLDED").map(PathBuf::from),
+    };
+
+    if matches.is_present("HEADLESS") {
+        let ticks = parse_arg(&matches, "HEADLESS").unwrap_or(0);
+        vm::run_headless(&data, ticks, options)
+    } else if matches.is_present("TUI") {
+        let charset = parse_arg(&matches, "CHARSET").unwrap_or(Charset::HalfBlock);
+        tui::run(&data, options, charset)
+    } else {
+        vm::run(&data, options)
+    }
 }
 
+// ROM placed as the platform and load address given to a subcommand say
+fn rom<'a>(matches: &ArgMatches, data: &'a [u8]) -> io::Result<Rom<'a>> {
+    let platform = match matches.value_of("PLATFORM") {
+        Some(platform) => platform.parse().map_err(invalid_input)?,
+        None => Platform::Chip8,
+    };
+    Ok(match matches.value_of("LOAD_ADDRESS") {
+        Some(address) => Rom::with_start(data, platform, memory::parse_address(address).map_err(invalid_input)?),
+        None => Rom::new(data, platform),
+    })
+}
+
+// Parses an optional argument, exits with usage error if it is malformed
+fn parse_arg<T>(matches: &ArgMatches, name: &str) -> Option<T>
+where
+    T: FromStr,
+    <T as FromStr>::Err: Display,
+{
+    if matches.is_present(name) {
+        Some(matches.value_of_t(name).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
@@ -0,0 +1,279 @@
+use crate::display::Display;
+use crate::memory::Memory;
+use crate::palette::Color;
+
+// MEGA-CHIP display, switched on by 0011
+pub const MEGA_WIDTH: usize = 256;
+pub const MEGA_HEIGHT: usize = 192;
+// 01nn NNNN addresses 24 bits
+pub const MEGA_MEMORY_SIZE: usize = 0x100_0000;
+// Sample rate, 24-bit length and a reserved byte precede sample data
+const SAMPLE_HEADER_SIZE: usize = 6;
+const BLACK: Color = [0, 0, 0, 0xff];
+
+// How sprite pixels are combined with the screen, set by 080n
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Blend {
+    Normal,
+    // Sprite is drawn with the given opacity in percent
+    Alpha(u8),
+    Add,
+    Multiply,
+}
+
+impl Blend {
+    pub fn from_mode(mode: u8) -> Blend {
+        match mode {
+            1 => Blend::Alpha(25),
+            2 => Blend::Alpha(50),
+            3 => Blend::Alpha(75),
+            4 => Blend::Add,
+            5 => Blend::Multiply,
+            _ => Blend::Normal,
+        }
+    }
+
+    fn apply(self, sprite: Color, screen: Color) -> Color {
+        let mut color = BLACK;
+        for i in 0..3 {
+            let (s, d) = (sprite[i] as u32, screen[i] as u32);
+            color[i] = match self {
+                Blend::Normal => s,
+                Blend::Alpha(percent) => (s * percent as u32 + d * (100 - percent as u32)) / 100,
+                Blend::Add => (s + d).min(0xff),
+                Blend::Multiply => s * d / 0xff,
+            } as u8;
+        }
+        color
+    }
+}
+
+// Digitized sound started by 060n: unsigned 8-bit samples in memory
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sample {
+    // Samples per second
+    pub rate: u16,
+    // Address of the first sample, past the header
+    pub address: usize,
+    pub length: usize,
+    // Played in a loop rather than once
+    pub repeat: bool,
+}
+
+impl Sample {
+    // Reads the header at the given address
+    pub fn load(memory: &Memory, address: usize, repeat: bool) -> Sample {
+        let byte = |offset: usize| memory.peek(address + offset) as usize;
+        Sample {
+            rate: (byte(0) << 8 | byte(1)) as u16,
+            address: address + SAMPLE_HEADER_SIZE,
+            length: byte(2) << 16 | byte(3) << 8 | byte(4),
+            repeat,
+        }
+    }
+}
+
+// MEGA-CHIP rendering: sprites are bytes indexing a 256 color palette,
+// index 0 is transparent. Drawing goes to a back buffer which 00E0
+// shows and clears, so frames never flicker.
+pub struct Screen {
+    palette: Vec<Color>,
+    // Palette indices drawn since the last 00E0, checked for collisions
+    indices: Vec<u8>,
+    // Colors drawn since the last 00E0
+    pixels: Vec<Color>,
+    sprite_width: usize,
+    sprite_height: usize,
+    // Brightness of the whole screen, 255 is full
+    alpha: u8,
+    blend: Blend,
+    // Drawing over a pixel of this index sets VF
+    collision: u8,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen::new()
+    }
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Screen {
+            palette: vec![BLACK; 0x100],
+            indices: vec![0; MEGA_WIDTH * MEGA_HEIGHT],
+            pixels: vec![BLACK; MEGA_WIDTH * MEGA_HEIGHT],
+            sprite_width: 0,
+            sprite_height: 0,
+            alpha: 0xff,
+            blend: Blend::Normal,
+            collision: 0,
+        }
+    }
+
+    // Loads `count` ARGB colors from memory as palette entries 1 and on
+    pub fn load_palette(&mut self, memory: &Memory, address: usize, count: usize) {
+        for entry in 0..count {
+            let at = address + entry * 4;
+            let color = [memory.peek(at + 1), memory.peek(at + 2), memory.peek(at + 3), memory.peek(at)];
+            if let Some(slot) = self.palette.get_mut(entry + 1) {
+                *slot = color;
+            }
+        }
+    }
+
+    // Sprite size in pixels, 0 stands for 256
+    pub fn set_sprite_width(&mut self, width: u8) {
+        self.sprite_width = if width == 0 { 0x100 } else { width as usize };
+    }
+
+    pub fn set_sprite_height(&mut self, height: u8) {
+        self.sprite_height = if height == 0 { 0x100 } else { height as usize };
+    }
+
+    pub fn set_alpha(&mut self, alpha: u8) {
+        self.alpha = alpha;
+    }
+
+    pub fn set_blend(&mut self, blend: Blend) {
+        self.blend = blend;
+    }
+
+    pub fn set_collision(&mut self, index: u8) {
+        self.collision = index;
+    }
+
+    // Draws a sprite of palette indices from memory, clipped at the screen
+    // edges. Returns whether it hit a pixel of the collision color.
+    pub fn draw(&mut self, memory: &Memory, address: usize, x: usize, y: usize) -> bool {
+        let mut collision = false;
+        for row in 0..self.sprite_height {
+            for column in 0..self.sprite_width {
+                let index = memory.peek(address + row * self.sprite_width + column);
+                collision |= self.put(x + column, y + row, index, self.palette[index as usize]);
+            }
+        }
+        collision
+    }
+
+    // Draws a 1-bit font sprite of `rows` bytes in white
+    pub fn draw_font(&mut self, memory: &Memory, address: usize, x: usize, y: usize, rows: usize) -> bool {
+        let mut collision = false;
+        for row in 0..rows {
+            let byte = memory.peek(address + row);
+            for bit in 0..8 {
+                if byte >> (7 - bit) & 1 == 1 {
+                    collision |= self.put(x + bit, y + row, 0xff, [0xff; 4]);
+                }
+            }
+        }
+        collision
+    }
+
+    fn put(&mut self, x: usize, y: usize, index: u8, color: Color) -> bool {
+        if index == 0 || x >= MEGA_WIDTH || y >= MEGA_HEIGHT {
+            return false;
+        }
+        let i = y * MEGA_WIDTH + x;
+        let collision = self.indices[i] != 0 && self.indices[i] == self.collision;
+        self.indices[i] = index;
+        self.pixels[i] = self.blend.apply(color, self.pixels[i]);
+        collision
+    }
+
+    // Shows the back buffer on the display, dimmed by screen alpha, and
+    // clears it for the next frame
+    pub fn present(&mut self, display: &mut Display) {
+        for (i, color) in self.pixels.iter().enumerate() {
+            let mut shown = *color;
+            for channel in shown.iter_mut().take(3) {
+                *channel = (*channel as u32 * self.alpha as u32 / 0xff) as u8;
+            }
+            display.set_rgba(i % MEGA_WIDTH, i / MEGA_WIDTH, shown);
+        }
+        self.indices.iter_mut().for_each(|index| *index = 0);
+        self.pixels.iter_mut().for_each(|color| *color = BLACK);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Color = [0xff, 0, 0, 0xff];
+
+    fn memory(at: usize, bytes: &[u8]) -> Memory {
+        let mut memory = Memory::new();
+        memory.load_rom_at(bytes, at);
+        memory
+    }
+
+    fn shown(screen: &mut Screen, x: usize, y: usize) -> Color {
+        let mut display = Display::with_size(MEGA_WIDTH, MEGA_HEIGHT);
+        display.enable_rgba();
+        screen.present(&mut display);
+        display.palette(x, y, &Default::default()).foreground
+    }
+
+    #[test]
+    fn blend_modes_mix_sprite_and_screen() {
+        let (sprite, screen) = ([200, 100, 0, 0xff], [100, 200, 40, 0xff]);
+        assert_eq!(Blend::from_mode(0).apply(sprite, screen), [200, 100, 0, 0xff]);
+        assert_eq!(Blend::from_mode(2).apply(sprite, screen), [150, 150, 20, 0xff]);
+        assert_eq!(Blend::from_mode(1).apply(sprite, screen), [125, 175, 30, 0xff]);
+        assert_eq!(Blend::from_mode(4).apply(sprite, screen), [255, 255, 40, 0xff]);
+        assert_eq!(Blend::from_mode(5).apply(sprite, screen), [78, 78, 0, 0xff]);
+        assert_eq!(Blend::from_mode(9), Blend::Normal);
+    }
+
+    #[test]
+    fn palette_entries_are_argb_from_one() {
+        let mut screen = Screen::new();
+        screen.load_palette(&memory(0x300, &[0x80, 0x10, 0x20, 0x30, 0xff, 0xff, 0, 0]), 0x300, 2);
+        assert_eq!(screen.palette[0], BLACK);
+        assert_eq!(screen.palette[1], [0x10, 0x20, 0x30, 0x80]);
+        assert_eq!(screen.palette[2], RED);
+    }
+
+    #[test]
+    fn sprite_size_zero_is_256() {
+        let mut screen = Screen::new();
+        screen.set_sprite_width(0);
+        screen.set_sprite_height(3);
+        assert_eq!((screen.sprite_width, screen.sprite_height), (0x100, 3));
+    }
+
+    #[test]
+    fn sprites_skip_index_zero_and_report_collisions() {
+        let mut screen = Screen::new();
+        screen.palette[1] = RED;
+        screen.set_sprite_width(2);
+        screen.set_sprite_height(1);
+        screen.set_collision(1);
+        let memory = memory(0x300, &[0x01, 0x00]);
+        assert!(!screen.draw(&memory, 0x300, 10, 20));
+        assert!(screen.draw(&memory, 0x300, 10, 20));
+        assert!(!screen.draw(&memory, 0x300, 11, 20));
+        assert!(!screen.draw(&memory, 0x300, MEGA_WIDTH - 1, MEGA_HEIGHT));
+        assert_eq!(shown(&mut screen, 10, 20), RED);
+        assert_eq!(shown(&mut screen, 10, 20), BLACK);
+    }
+
+    #[test]
+    fn screen_alpha_dims_the_frame() {
+        let mut screen = Screen::new();
+        screen.palette[1] = RED;
+        screen.set_sprite_width(1);
+        screen.set_sprite_height(1);
+        screen.set_alpha(0x80);
+        screen.draw(&memory(0x300, &[0x01]), 0x300, 0, 0);
+        assert_eq!(shown(&mut screen, 0, 0), [0x80, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn sample_header_gives_rate_and_length() {
+        let memory = memory(0x400, &[0x1F, 0x40, 0x01, 0x00, 0x02, 0x00]);
+        let sample = Sample::load(&memory, 0x400, true);
+        assert_eq!(sample, Sample { rate: 8000, address: 0x406, length: 0x10002, repeat: true });
+    }
+}
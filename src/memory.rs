@@ -1,33 +1,118 @@
-const MEMORY_SIZE: usize = 4096;
-const FONTSET_START_ADDRESS: usize = 0x50;
-const FONTSET_SIZE: usize = 80;
-const START_ADDRESS: usize = 0x200;
-const FONTSET_CHAR_SIZE: usize = 5;
+use crate::font::{Font, BIG_CHAR_SIZE, CHAR_SIZE, FONT_SIZE};
+use crate::instruction::Platform;
+
+pub const MEMORY_SIZE: usize = 4096;
+pub const FONTSET_START_ADDRESS: usize = 0x50;
+pub const START_ADDRESS: usize = 0x200;
+// Where the COSMAC VIP interpreter keeps its call stack and framebuffer
+pub const VIP_STACK_ADDRESS: usize = 0xea0;
+pub const VIP_DISPLAY_ADDRESS: usize = 0xf00;
+
+// Where the program is loaded and started and where the font is kept
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Layout {
+    pub start: u16,
+    pub font: u16,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            start: START_ADDRESS as u16,
+            font: FONTSET_START_ADDRESS as u16,
+        }
+    }
+}
+
+impl Layout {
+    // Layout used by the interpreter of a platform: CHIP-8X programs
+    // load at 300, ETI-660 ones at 600
+    pub fn for_platform(platform: Platform) -> Layout {
+        let start = match platform {
+            Platform::Chip8X => 0x300,
+            Platform::Eti660 => 0x600,
+            _ => START_ADDRESS as u16,
+        };
+        Layout { start, ..Layout::default() }
+    }
+
+    // Checks that a program of the given size and a font of the given
+    // size both fit within memory of the given size without overlapping
+    pub fn check(&self, memory_size: usize, font_size: usize, rom_size: usize) -> Result<(), String> {
+        let start = self.start as usize;
+        let font = self.font as usize;
+        if start >= memory_size {
+            return Err(format!("Load address {:03X} is outside of memory", self.start));
+        }
+        if start + rom_size > memory_size {
+            return Err(format!("Program of {} bytes does not fit in memory at {:03X}", rom_size, self.start));
+        }
+        if font + font_size > memory_size {
+            return Err(format!("Font at {:03X} does not fit in memory", self.font));
+        }
+        if rom_size > 0 && font_size > 0 && start < font + font_size && font < start + rom_size {
+            return Err(format!("Program at {:03X} overlaps the font at {:03X}", self.start, self.font));
+        }
+        Ok(())
+    }
+}
+
+// Parses a hexadecimal address, with or without `0x`
+pub fn parse_address(s: &str) -> Result<u16, String> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Malformed address: {}", s))
+}
 
 pub struct Memory {
-    buffer: [u8; MEMORY_SIZE]
+    buffer: Vec<u8>,
+    font_address: usize,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Self {
         Memory{
-            buffer: [0; MEMORY_SIZE],
+            buffer: vec![0; MEMORY_SIZE],
+            font_address: FONTSET_START_ADDRESS,
         }
     }
 
-    pub fn load_fontset(&mut self, fontset: &[u8]) {
-        let fontset_size = fontset.len();
-        if fontset_size == FONTSET_SIZE {
-            self.buffer[FONTSET_START_ADDRESS..FONTSET_START_ADDRESS + fontset_size]
-                .copy_from_slice(&fontset);
-        } else {
-            panic!("Bad fontset");
+    // Moves the font, to be called before `load_font`
+    pub fn set_font_address(&mut self, address: usize) {
+        self.font_address = address;
+    }
+
+    // Loads small glyphs at the font address and large ones right after
+    pub fn load_font(&mut self, font: &Font) {
+        if self.font_address + font.size() > self.buffer.len() {
+            panic!("Font does not fit in memory");
         }
+        let big_address = self.font_address + font.small.len();
+        self.buffer[self.font_address..big_address].copy_from_slice(&font.small);
+        self.buffer[big_address..big_address + font.big.len()].copy_from_slice(&font.big);
     }
 
     pub fn load_rom(&mut self, rom: &[u8]) {
-        if rom.len() <= MEMORY_SIZE - START_ADDRESS {
-            self.buffer[START_ADDRESS..START_ADDRESS+ rom.len()].copy_from_slice(rom);
+        self.load_rom_at(rom, START_ADDRESS);
+    }
+
+    pub fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Grows or shrinks memory, keeping its contents
+    pub fn resize(&mut self, size: usize) {
+        self.buffer.resize(size, 0);
+    }
+
+    pub fn load_rom_at(&mut self, rom: &[u8], address: usize) {
+        if rom.len() <= self.buffer.len() - address {
+            self.buffer[address..address + rom.len()].copy_from_slice(rom);
         } else {
             panic!("Program too big to fit in memory");
         }
@@ -40,7 +125,11 @@ impl Memory {
     }
 
     pub fn get_char_addr(&self, digit: usize) -> u16 {
-        (FONTSET_START_ADDRESS + (digit * FONTSET_CHAR_SIZE)) as u16
+        (self.font_address + (digit * CHAR_SIZE)) as u16
+    }
+
+    pub fn get_big_char_addr(&self, digit: usize) -> u16 {
+        (self.font_address + FONT_SIZE + (digit * BIG_CHAR_SIZE)) as u16
     }
 
     pub fn poke(&mut self, address: usize, value: u8) {
@@ -50,4 +139,42 @@ impl Memory {
     pub fn peek(&self, address: usize) -> u8 {
         self.buffer[address]
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_fits_program_and_font() {
+        let layout = Layout::default();
+        assert_eq!(layout.check(MEMORY_SIZE, FONT_SIZE, MEMORY_SIZE - START_ADDRESS), Ok(()));
+        assert!(layout.check(MEMORY_SIZE, FONT_SIZE, MEMORY_SIZE - START_ADDRESS + 1).is_err());
+        let layout = Layout { start: 0xf00, ..Layout::default() };
+        assert_eq!(layout.check(MEMORY_SIZE, FONT_SIZE, 0x100), Ok(()));
+        assert!(layout.check(MEMORY_SIZE, FONT_SIZE, 0x102).is_err());
+        let layout = Layout::for_platform(Platform::Eti660);
+        assert!(layout.check(MEMORY_SIZE, FONT_SIZE, 0xa01).is_err());
+    }
+
+    #[test]
+    fn layout_outside_memory() {
+        let layout = Layout { start: 0x1000, ..Layout::default() };
+        assert!(layout.check(MEMORY_SIZE, FONT_SIZE, 0).is_err());
+        let layout = Layout { font: 0xfc0, ..Layout::default() };
+        assert!(layout.check(MEMORY_SIZE, FONT_SIZE, 2).is_err());
+    }
+
+    #[test]
+    fn layout_program_over_font() {
+        // The font fills 50..A0
+        let layout = Layout { start: 0x0, ..Layout::default() };
+        assert!(layout.check(MEMORY_SIZE, FONT_SIZE, 0x51).is_err());
+        assert_eq!(layout.check(MEMORY_SIZE, FONT_SIZE, 0x50), Ok(()));
+        let layout = Layout { start: 0x90, ..Layout::default() };
+        assert!(layout.check(MEMORY_SIZE, FONT_SIZE, 2).is_err());
+        let layout = Layout { start: 0xa0, ..Layout::default() };
+        assert_eq!(layout.check(MEMORY_SIZE, FONT_SIZE, 2), Ok(()));
+        let layout = Layout { font: 0x300, ..Layout::default() };
+        assert!(layout.check(MEMORY_SIZE, FONT_SIZE, 0x200).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use crate::font;
+use crate::keypad::Keypad;
+use crate::palette::{Color, Palette};
+use crate::renderer::Frame;
+
+// CHIP-8 keys as laid out on the COSMAC VIP hex keypad
+pub const LAYOUT: [[usize; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// Size of the keypad panel and of one key cell before scaling
+const PANEL_SIZE: usize = 32;
+const CELL_SIZE: usize = PANEL_SIZE / 4;
+
+// Something holding an on-screen key down
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Pointer {
+    Mouse,
+    // Finger on a touchscreen, by touch id
+    Touch(u64),
+}
+
+// On-screen hex keypad drawn to the right of the display. Keys may be
+// pressed with the mouse or by touch, keys pressed in any way are
+// highlighted.
+pub struct Overlay {
+    held: HashMap<Pointer, usize>,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Overlay::new()
+    }
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Overlay {
+            held: HashMap::new(),
+        }
+    }
+
+    // Integer scale of the panel placed beside the given display frame
+    fn scale(frame: &Frame) -> usize {
+        (frame.height / PANEL_SIZE).max(1)
+    }
+
+    // Size of the display frame combined with the panel
+    pub fn size(frame: &Frame) -> (usize, usize) {
+        let panel = PANEL_SIZE * Overlay::scale(frame);
+        (frame.width + panel, frame.height.max(panel))
+    }
+
+    // Returns CHIP-8 key under the given point of the combined frame
+    pub fn key_at(frame: &Frame, x: usize, y: usize) -> Option<usize> {
+        let cell = CELL_SIZE * Overlay::scale(frame);
+        let column = x.checked_sub(frame.width)? / cell;
+        let row = y / cell;
+        LAYOUT.get(row)?.get(column).copied()
+    }
+
+    // Moves the pointer onto the given key, `None` lifts it
+    pub fn set(&mut self, pointer: Pointer, key: Option<usize>) {
+        match key {
+            Some(key) => self.held.insert(pointer, key),
+            None => self.held.remove(&pointer),
+        };
+    }
+
+    // Presses keys held by pointers
+    pub fn press(&self, keypad: &mut Keypad) {
+        for key in self.held.values() {
+            keypad.press(*key);
+        }
+    }
+
+    // Draws the display frame with the keypad beside it
+    pub fn compose(frame: &Frame, keypad: &Keypad, palette: &Palette) -> Frame {
+        let (width, height) = Overlay::size(frame);
+        let mut output = Frame::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                output.put(x, y, palette.background);
+            }
+        }
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                output.put(x, y, frame.get(x, y));
+            }
+        }
+        let panel = draw_panel(keypad, palette).scaled(Overlay::scale(frame) as u32);
+        for y in 0..panel.height {
+            for x in 0..panel.width {
+                output.put(frame.width + x, y, panel.get(x, y));
+            }
+        }
+        output
+    }
+}
+
+// Every key is a square with one pixel gap on the top left, showing
+// its hex digit in the built-in font. Pressed keys are inverted.
+fn draw_panel(keypad: &Keypad, palette: &Palette) -> Frame {
+    let mut panel = Frame::new(PANEL_SIZE, PANEL_SIZE);
+    let face = dim(palette);
+    for (row, keys) in LAYOUT.iter().enumerate() {
+        for (column, key) in keys.iter().enumerate() {
+            let (fill, ink) = if keypad.pressed(*key) {
+                (palette.foreground, palette.background)
+            } else {
+                (face, palette.foreground)
+            };
+            let glyph = &font::DEFAULT_FONTSET[key * 5..key * 5 + 5];
+            for dy in 0..CELL_SIZE {
+                for dx in 0..CELL_SIZE {
+                    let color = if dx == 0 || dy == 0 {
+                        palette.background
+                    } else if (2..6).contains(&dx) && (2..7).contains(&dy)
+                        && glyph[dy - 2] & (0x80 >> (dx - 2)) != 0
+                    {
+                        ink
+                    } else {
+                        fill
+                    };
+                    panel.put(column * CELL_SIZE + dx, row * CELL_SIZE + dy, color);
+                }
+            }
+        }
+    }
+    panel
+}
+
+// Color of released keys, a quarter of the way from background to foreground
+fn dim(palette: &Palette) -> Color {
+    let mut color = palette.background;
+    for (channel, foreground) in color.iter_mut().zip(palette.foreground.iter()) {
+        *channel = ((*channel as u32 * 3 + *foreground as u32) / 4) as u8;
+    }
+    color
+}
@@ -0,0 +1,113 @@
+pub type Color = [u8; 4];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub foreground: Color,
+    pub background: Color,
+}
+
+// Built-in themes, the first one is the default
+pub const THEMES: [(&str, Palette); 5] = [
+    ("classic", Palette::new(rgb(0xffffff), rgb(0x000000))),
+    ("green", Palette::new(rgb(0x33ff66), rgb(0x0a1a0f))),
+    ("amber", Palette::new(rgb(0xffb000), rgb(0x1f1400))),
+    ("lcd", Palette::new(rgb(0x0f380f), rgb(0x9bbc0f))),
+    ("high-contrast", Palette::new(rgb(0x000000), rgb(0xffffff))),
+];
+
+// Foreground colors of the VP-590 color board used by CHIP-8X
+pub const VP590_FOREGROUNDS: [Color; 8] = [
+    rgb(0x000000), // black
+    rgb(0xff0000), // red
+    rgb(0x0000ff), // blue
+    rgb(0xff00ff), // violet
+    rgb(0x00ff00), // green
+    rgb(0xffff00), // yellow
+    rgb(0x00ffff), // aqua
+    rgb(0xffffff), // white
+];
+
+// Background colors of the VP-590 in the order 02A0 cycles through them
+pub const VP590_BACKGROUNDS: [Color; 4] = [
+    rgb(0x000080), // blue
+    rgb(0x000000), // black
+    rgb(0x008000), // green
+    rgb(0x800000), // red
+];
+
+impl Palette {
+    pub const fn new(foreground: Color, background: Color) -> Self {
+        Palette {
+            foreground,
+            background,
+        }
+    }
+
+    pub fn theme(name: &str) -> Option<Palette> {
+        THEMES.iter()
+            .find(|(theme, _)| theme.eq_ignore_ascii_case(name))
+            .map(|(_, palette)| *palette)
+    }
+
+    // Returns the built-in theme following this palette, custom palettes
+    // are followed by the first theme.
+    pub fn next_theme(&self) -> (&'static str, Palette) {
+        let next = THEMES.iter()
+            .position(|(_, palette)| palette == self)
+            .map_or(0, |i| (i + 1) % THEMES.len());
+        THEMES[next]
+    }
+
+    pub fn color(&self, pixel: u8) -> Color {
+        if pixel == 1 {
+            self.foreground
+        } else {
+            self.background
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        THEMES[0].1
+    }
+}
+
+const fn rgb(value: u32) -> Color {
+    [(value >> 16) as u8, (value >> 8) as u8, value as u8, 0xff]
+}
+
+// Parses a color in `#rrggbb` or `#rrggbbaa` notation, `#` is optional
+pub fn parse_color(s: &str) -> Result<Color, String> {
+    let hex = s.trim().trim_start_matches('#');
+    // `from_str_radix` would take a sign as well
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Bad color: {}", s));
+    }
+    let value = u32::from_str_radix(hex, 16)
+        .map_err(|_| format!("Bad color: {}", s))?;
+    match hex.len() {
+        6 => Ok(rgb(value)),
+        8 => Ok(value.to_be_bytes()),
+        _ => Err(format!("Bad color: {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_parse_with_optional_alpha() {
+        assert_eq!(parse_color("#00ff80"), Ok([0x00, 0xff, 0x80, 0xff]));
+        assert_eq!(parse_color("102030"), Ok([0x10, 0x20, 0x30, 0xff]));
+        assert_eq!(parse_color("#10203040"), Ok([0x10, 0x20, 0x30, 0x40]));
+    }
+
+    #[test]
+    fn colors_must_be_hex_digits_only() {
+        for color in ["+12345", "#+12345", "-1234567", "#12345", "#1234567", "#gg0000", ""].iter() {
+            assert!(parse_color(color).is_err(), "{}", color);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::instruction::Instruction;
+use crate::memory::{Memory, MEMORY_SIZE, START_ADDRESS};
+
+// Number of entries in every ranked table of the report
+const REPORT_SIZE: usize = 20;
+
+// Instructions executed inside a subroutine
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Cost {
+    pub calls: u64,
+    // Including subroutines it calls
+    pub inclusive: u64,
+    // In its own code only
+    pub exclusive: u64,
+}
+
+// Counts executed instructions by address, by opcode class and by
+// subroutine. Cost is measured in instructions, the program entry point
+// is treated as a subroutine called once.
+pub struct Profiler {
+    // Program entry point, reported as `main`
+    entry: u16,
+    total: u64,
+    by_address: Vec<u64>,
+    by_class: HashMap<&'static str, u64>,
+    subroutines: HashMap<u16, Cost>,
+    // Entries of subroutines being executed, innermost last
+    stack: Vec<u16>,
+    // Instructions executed with a given call stack
+    folded: HashMap<Vec<u16>, u64>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::with_entry(START_ADDRESS as u16)
+    }
+
+    pub fn with_entry(entry: u16) -> Self {
+        let mut subroutines = HashMap::new();
+        subroutines.insert(entry, Cost { calls: 1, ..Cost::default() });
+        Profiler {
+            entry,
+            total: 0,
+            by_address: vec![0; MEMORY_SIZE],
+            by_class: HashMap::new(),
+            subroutines,
+            stack: vec![entry],
+            folded: HashMap::new(),
+        }
+    }
+
+    // Records an instruction about to be executed at the given address
+    pub fn record(&mut self, address: u16, instruction: Instruction) {
+        self.total += 1;
+        self.by_address[address as usize % MEMORY_SIZE] += 1;
+        *self.by_class.entry(instruction.pattern()).or_insert(0) += 1;
+
+        let current = *self.stack.last().unwrap_or(&self.entry);
+        self.subroutines.entry(current).or_default().exclusive += 1;
+        // Recursive subroutines are counted once per instruction
+        for (depth, entry) in self.stack.iter().enumerate() {
+            if !self.stack[..depth].contains(entry) {
+                self.subroutines.entry(*entry).or_default().inclusive += 1;
+            }
+        }
+        match self.folded.get_mut(&self.stack[..]) {
+            Some(count) => *count += 1,
+            None => {
+                self.folded.insert(self.stack.clone(), 1);
+            }
+        }
+
+        match instruction {
+            Instruction::Call(nnn) => {
+                self.stack.push(nnn);
+                self.subroutines.entry(nnn).or_default().calls += 1;
+            }
+            // Returning from the entry point itself is left to the interpreter
+            Instruction::Ret if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn executions(&self, address: u16) -> u64 {
+        self.by_address[address as usize % MEMORY_SIZE]
+    }
+
+    pub fn subroutine(&self, entry: u16) -> Option<&Cost> {
+        self.subroutines.get(&entry)
+    }
+
+    // Ranked hotspot report, instructions are disassembled from memory
+    pub fn write_report<W: Write>(&self, out: &mut W, memory: &Memory) -> io::Result<()> {
+        let percent = |count: u64| count as f64 * 100.0 / self.total.max(1) as f64;
+        writeln!(out, "Profile: {} instructions executed", self.total)?;
+
+        writeln!(out, "\nHot instructions:")?;
+        let mut addresses: Vec<usize> = (0..MEMORY_SIZE).filter(|a| self.by_address[*a] > 0).collect();
+        addresses.sort_by_key(|address| std::cmp::Reverse(self.by_address[*address]));
+        for address in addresses.iter().take(REPORT_SIZE) {
+            let count = self.by_address[*address];
+            let opcode = memory.get_opcode(*address as u16);
+            let text = Instruction::decode(opcode)
+                .map_or(String::from("???"), |instruction| instruction.to_string());
+            writeln!(out, "  {:03X}  {:04X}  {:<16} {:>10} {:>6.2}%", address, opcode, text, count, percent(count))?;
+        }
+
+        writeln!(out, "\nOpcode classes:")?;
+        let mut classes: Vec<(&&str, &u64)> = self.by_class.iter().collect();
+        classes.sort_by_key(|(class, count)| (std::cmp::Reverse(**count), **class));
+        for (class, count) in classes.iter().take(REPORT_SIZE) {
+            writeln!(out, "  {}  {:>10} {:>6.2}%", class, count, percent(**count))?;
+        }
+
+        writeln!(out, "\nSubroutines:              calls  inclusive          exclusive")?;
+        let mut subroutines: Vec<(&u16, &Cost)> = self.subroutines.iter().collect();
+        subroutines.sort_by_key(|(entry, cost)| (std::cmp::Reverse(cost.inclusive), **entry));
+        for (entry, cost) in subroutines.iter().take(REPORT_SIZE) {
+            writeln!(
+                out,
+                "  {:<20} {:>8} {:>10} {:>6.2}% {:>10} {:>6.2}%",
+                self.frame_name(**entry),
+                cost.calls,
+                cost.inclusive,
+                percent(cost.inclusive),
+                cost.exclusive,
+                percent(cost.exclusive)
+            )?;
+        }
+        Ok(())
+    }
+
+    // Folded stacks as consumed by flamegraph.pl and inferno, one line
+    // per call stack: `main;sub_2A0;sub_31C 1234`
+    pub fn write_folded<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut stacks: Vec<(String, u64)> = self.folded
+            .iter()
+            .map(|(stack, count)| {
+                let names: Vec<String> = stack.iter().map(|entry| self.frame_name(*entry)).collect();
+                (names.join(";"), *count)
+            })
+            .collect();
+        stacks.sort();
+        for (stack, count) in stacks {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_folded<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_folded(&mut out)?;
+        out.flush()
+    }
+
+    fn frame_name(&self, entry: u16) -> String {
+        if entry == self.entry {
+            String::from("main")
+        } else {
+            format!("sub_{:03X}", entry)
+        }
+    }
+}
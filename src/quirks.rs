@@ -0,0 +1,104 @@
+use std::str::FromStr;
+
+// Behaviors which differ between CHIP-8 interpreters
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quirks {
+    // Fx0A waits for the key to be pressed and released, as on COSMAC VIP
+    pub key_release: bool,
+    // 8xy6 and 8xyE shift Vx in place instead of shifting Vy into Vx
+    pub shift: bool,
+    // Fx55 and Fx65 leave I unchanged instead of incrementing it past Vx
+    pub load_store: bool,
+    // Bnnn jumps to xnn + Vx instead of nnn + V0, as on SCHIP
+    pub jump: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around
+    pub clip: bool,
+    // 8xy1, 8xy2 and 8xy3 reset VF to 0, as on COSMAC VIP
+    pub vf_reset: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            key_release: false,
+            shift: true,
+            load_store: true,
+            jump: false,
+            clip: false,
+            vf_reset: false,
+        }
+    }
+}
+
+impl Quirks {
+    // Sets quirk by its name
+    pub fn set(&mut self, name: &str, value: bool) -> Result<(), String> {
+        match name {
+            "key_release" => self.key_release = value,
+            "shift" => self.shift = value,
+            "load_store" => self.load_store = value,
+            "jump" => self.jump = value,
+            "clip" => self.clip = value,
+            "vf_reset" => self.vf_reset = value,
+            _ => return Err(format!("Unknown quirk: {}", name)),
+        }
+        Ok(())
+    }
+
+    // Applies a `name`, `name=on` or `name=off` setting
+    pub fn apply(&mut self, setting: &str) -> Result<(), String> {
+        let (name, value) = parse_setting(setting)?;
+        self.set(&name, value)
+    }
+}
+
+// Splits a `name`, `name=on` or `name=off` setting into name and value
+pub fn parse_setting(setting: &str) -> Result<(String, bool), String> {
+    let (name, value) = match setting.find('=') {
+        Some(i) => (&setting[..i], parse_switch(&setting[i + 1..])?),
+        None => (setting, true),
+    };
+    Ok((name.trim().to_string(), value))
+}
+
+fn parse_switch(value: &str) -> Result<bool, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => bool::from_str(value).map_err(|_| format!("Bad quirk value: {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_parse_name_and_switch() {
+        assert_eq!(parse_setting("clip"), Ok((String::from("clip"), true)));
+        assert_eq!(parse_setting("shift=off"), Ok((String::from("shift"), false)));
+        assert_eq!(parse_setting(" jump = On "), Ok((String::from("jump"), true)));
+        assert_eq!(parse_setting("vf_reset=0"), Ok((String::from("vf_reset"), false)));
+        assert!(parse_setting("clip=maybe").is_err());
+    }
+
+    #[test]
+    fn apply_parses_name_and_switch() {
+        let mut quirks = Quirks::default();
+        assert!(!quirks.key_release);
+        quirks.apply("key_release").unwrap();
+        quirks.apply("load_store=false").unwrap();
+        quirks.apply(" jump = On ").unwrap();
+        assert!(quirks.key_release);
+        assert!(!quirks.load_store);
+        assert!(quirks.jump);
+        assert!(quirks.apply("clip=maybe").is_err());
+    }
+
+    #[test]
+    fn unknown_quirks_are_rejected() {
+        let mut quirks = Quirks::default();
+        assert_eq!(quirks.apply("wobble"), Err(String::from("Unknown quirk: wobble")));
+        assert!(quirks.set("shift=on", true).is_err());
+    }
+}
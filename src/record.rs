@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+// Recordings are always captured at the CHIP-8 timer rate
+pub const FRAME_RATE: u32 = 60;
+
+// GIF frame delays are measured in hundredths of a second
+const GIF_TIME_BASE: u64 = 100;
+const GIF_QUANTIZE_SPEED: i32 = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    // Animated GIF, identical frames are merged into one
+    Gif,
+    // YUV4MPEG2 stream with 4:4:4 chroma, suitable for ffmpeg or x264
+    Y4m,
+    // Headerless stream of RGBA frames
+    Raw,
+}
+
+impl Format {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Format> {
+        path.as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ext.parse().ok())
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gif" => Ok(Format::Gif),
+            "y4m" => Ok(Format::Y4m),
+            "raw" | "rgba" => Ok(Format::Raw),
+            _ => Err(format!("Unknown recording format: {}", s)),
+        }
+    }
+}
+
+enum Writer {
+    Gif {
+        encoder: gif::Encoder<Box<dyn Write>>,
+        // Last frame seen and number of 60 Hz frames it was shown for
+        pending: Option<(Vec<u8>, u64)>,
+        // Total duration written so far, in 60 Hz frames and in centiseconds
+        frames: u64,
+        centiseconds: u64,
+    },
+    Y4m(Box<dyn Write>),
+    Raw(Box<dyn Write>),
+}
+
+pub struct Recorder {
+    writer: Writer,
+    width: usize,
+    height: usize,
+}
+
+impl Recorder {
+    // Creates a recorder writing to the given file, `-` stands for stdout.
+    pub fn create<P: AsRef<Path>>(path: P, format: Format, width: usize, height: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        let output: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(BufWriter::new(io::stdout()))
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        Recorder::new(output, format, width, height)
+    }
+
+    pub fn new(mut output: Box<dyn Write>, format: Format, width: usize, height: usize) -> io::Result<Self> {
+        let writer = match format {
+            Format::Gif => {
+                if width > u16::MAX as usize || height > u16::MAX as usize {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Frame is too big for GIF"));
+                }
+                let mut encoder = gif::Encoder::new(output, width as u16, height as u16, &[])
+                    .map_err(gif_error)?;
+                encoder.set_repeat(gif::Repeat::Infinite).map_err(gif_error)?;
+                Writer::Gif {
+                    encoder,
+                    pending: None,
+                    frames: 0,
+                    centiseconds: 0,
+                }
+            }
+            Format::Y4m => {
+                writeln!(output, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444", width, height, FRAME_RATE)?;
+                Writer::Y4m(output)
+            }
+            Format::Raw => Writer::Raw(output),
+        };
+        Ok(Recorder {
+            writer,
+            width,
+            height,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Appends one 60 Hz frame given as an RGBA buffer of width x height pixels.
+    pub fn frame(&mut self, rgba: &[u8]) -> io::Result<()> {
+        if rgba.len() != self.width * self.height * 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Frame size mismatch"));
+        }
+        let (width, height) = (self.width, self.height);
+        match &mut self.writer {
+            Writer::Gif { pending, .. } => {
+                if let Some((last, count)) = pending {
+                    if last.as_slice() == rgba {
+                        *count += 1;
+                        return Ok(());
+                    }
+                }
+                self.flush_gif()?;
+                if let Writer::Gif { pending, .. } = &mut self.writer {
+                    *pending = Some((rgba.to_vec(), 1));
+                }
+                Ok(())
+            }
+            Writer::Y4m(output) => {
+                output.write_all(b"FRAME\n")?;
+                output.write_all(&rgba_to_yuv444(rgba, width * height))
+            }
+            Writer::Raw(output) => output.write_all(rgba),
+        }
+    }
+
+    // Flushes buffered frames and writes the trailer, if the format has one.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush_gif()?;
+        match self.writer {
+            Writer::Gif { encoder, .. } => encoder.into_inner()?.flush(),
+            Writer::Y4m(mut output) | Writer::Raw(mut output) => output.flush(),
+        }
+    }
+
+    // Writes the pending GIF frame with a delay covering all its repeats.
+    // Delays are rounded against the total elapsed time, so that 60 Hz
+    // frames alternate between 1 and 2 centiseconds without drifting.
+    fn flush_gif(&mut self) -> io::Result<()> {
+        let (width, height) = (self.width as u16, self.height as u16);
+        if let Writer::Gif { encoder, pending, frames, centiseconds } = &mut self.writer {
+            if let Some((rgba, count)) = pending.take() {
+                *frames += count;
+                let end = (*frames * GIF_TIME_BASE + FRAME_RATE as u64 / 2) / FRAME_RATE as u64;
+                let delay = (end - *centiseconds).min(u16::MAX as u64);
+                *centiseconds += delay;
+
+                let mut frame = gif_frame(width, height, &rgba);
+                frame.delay = delay as u16;
+                encoder.write_frame(&frame).map_err(gif_error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Builds an exact palette when the frame has at most 256 colors,
+// otherwise falls back to quantization.
+fn gif_frame(width: u16, height: u16, rgba: &[u8]) -> gif::Frame<'static> {
+    let mut colors: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut palette = Vec::new();
+    let mut indices = Vec::with_capacity(rgba.len() / 4);
+    for pixel in rgba.chunks_exact(4) {
+        let color = opaque(pixel);
+        let index = match colors.get(&color) {
+            Some(index) => *index,
+            None if colors.len() < 256 => {
+                let index = colors.len() as u8;
+                colors.insert(color, index);
+                palette.extend_from_slice(&color);
+                index
+            }
+            None => {
+                let rgb: Vec<u8> = rgba.chunks_exact(4).flat_map(opaque).collect();
+                return gif::Frame::from_rgb_speed(width, height, &rgb, GIF_QUANTIZE_SPEED);
+            }
+        };
+        indices.push(index);
+    }
+    gif::Frame::from_palette_pixels(width, height, &indices, &palette, None)
+}
+
+// Composes a pixel over black background
+fn opaque(pixel: &[u8]) -> [u8; 3] {
+    let alpha = pixel[3] as u16;
+    [
+        (pixel[0] as u16 * alpha / 255) as u8,
+        (pixel[1] as u16 * alpha / 255) as u8,
+        (pixel[2] as u16 * alpha / 255) as u8,
+    ]
+}
+
+// Converts RGBA pixels into planar BT.601 studio range Y'CbCr
+fn rgba_to_yuv444(rgba: &[u8], pixels: usize) -> Vec<u8> {
+    let mut yuv = vec![0; pixels * 3];
+    for (i, pixel) in rgba.chunks_exact(4).enumerate() {
+        let [r, g, b] = opaque(pixel);
+        let (r, g, b) = (r as i32, g as i32, b as i32);
+        yuv[i] = (16 + ((66 * r + 129 * g + 25 * b + 128) >> 8)) as u8;
+        yuv[pixels + i] = (128 + ((-38 * r - 74 * g + 112 * b + 128) >> 8)) as u8;
+        yuv[pixels * 2 + i] = (128 + ((112 * r - 94 * g - 18 * b + 128) >> 8)) as u8;
+    }
+    yuv
+}
+
+fn gif_error(e: gif::EncodingError) -> io::Error {
+    match e {
+        gif::EncodingError::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
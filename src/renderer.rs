@@ -0,0 +1,241 @@
+use std::str::FromStr;
+use crate::display::Display;
+use crate::filter::Filter;
+use crate::palette::{Color, Palette};
+
+pub const DEFAULT_DECAY: f32 = 0.4;
+
+// RGBA image produced from the display
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Self {
+        Frame {
+            width,
+            height,
+            pixels: vec![0; width * height * 4],
+        }
+    }
+
+    pub fn from_display(display: &Display, palette: &Palette) -> Self {
+        let mut frame = Frame::new(display.width(), display.height());
+        for y in 0..display.height() {
+            for x in 0..display.width() {
+                frame.put(x, y, display.palette(x, y, palette).color(display.peek(x, y)));
+            }
+        }
+        frame
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Color {
+        let i = (y * self.width + x) * 4;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+
+    pub fn put(&mut self, x: usize, y: usize, color: Color) {
+        let i = (y * self.width + x) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&color);
+    }
+
+    // Nearest-neighbour upscaling, each pixel becomes a `scale` x `scale` square
+    pub fn scaled(&self, scale: u32) -> Frame {
+        let scale = scale.max(1) as usize;
+        let mut frame = Frame::new(self.width * scale, self.height * scale);
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                frame.put(x, y, self.get(x / scale, y / scale));
+            }
+        }
+        frame
+    }
+
+    // Nearest-neighbour stretching to the given size
+    pub fn resized(&self, width: usize, height: usize) -> Frame {
+        let mut frame = Frame::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                frame.put(x, y, self.get(x * self.width / width, y * self.height / height));
+            }
+        }
+        frame
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Persistence {
+    // Pixels are shown exactly as in the framebuffer
+    Off,
+    // Erased pixels fade out, losing given fraction of brightness every frame
+    Fade(f32),
+    // Every frame is an average of the last two framebuffers
+    Blend,
+}
+
+impl FromStr for Persistence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Persistence::Off),
+            "fade" => Ok(Persistence::Fade(DEFAULT_DECAY)),
+            "blend" => Ok(Persistence::Blend),
+            _ => Err(format!("Unknown persistence mode: {}", s)),
+        }
+    }
+}
+
+// Turns the 1-bit framebuffer into colored frames, emulating phosphor
+// persistence and applying an upscaling filter on top of it if requested.
+// The framebuffer itself is never touched, so collisions stay exact.
+pub struct Renderer {
+    palette: Palette,
+    persistence: Persistence,
+    filter: Filter,
+    // Per-pixel brightness in 0.0..=1.0
+    intensity: Vec<f32>,
+    // Framebuffer seen on the previous frame
+    previous: Vec<f32>,
+    // Per-pixel colors of a colored display, empty for monochrome ones
+    palettes: Vec<Palette>,
+    // Colored frame before filtering
+    colored: Frame,
+    // Display size frames are stretched to, for programs switching
+    // resolution while running
+    size: Option<(usize, usize)>,
+    frame: Frame,
+}
+
+impl Renderer {
+    pub fn new(palette: Palette, persistence: Persistence, filter: Filter) -> Self {
+        Renderer {
+            palette,
+            persistence,
+            filter,
+            intensity: Vec::new(),
+            previous: Vec::new(),
+            palettes: Vec::new(),
+            colored: Frame::new(0, 0),
+            size: None,
+            frame: Frame::new(0, 0),
+        }
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.colorize();
+    }
+
+    // Makes every frame the same size whatever the display size
+    pub fn set_size(&mut self, width: usize, height: usize) {
+        self.size = Some((width, height));
+    }
+
+    // Advances the renderer by one 60 Hz frame
+    pub fn update(&mut self, display: &Display) {
+        let (width, height) = (display.width(), display.height());
+        if self.colored.width != width || self.colored.height != height {
+            self.colored = Frame::new(width, height);
+            self.intensity = vec![0.0; width * height];
+            self.previous = vec![0.0; width * height];
+        }
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let lit = if display.peek(x, y) == 1 { 1.0 } else { 0.0 };
+                self.intensity[i] = match self.persistence {
+                    Persistence::Off => lit,
+                    Persistence::Fade(decay) => lit.max(self.intensity[i] * (1.0 - decay.clamp(0.0, 1.0))),
+                    Persistence::Blend => (lit + self.previous[i]) / 2.0,
+                };
+                self.previous[i] = lit;
+            }
+        }
+        self.palettes.clear();
+        if display.colored() {
+            for y in 0..height {
+                for x in 0..width {
+                    self.palettes.push(display.palette(x, y, &self.palette));
+                }
+            }
+        }
+        self.colorize();
+    }
+
+    // Returns the last rendered frame, enlarged by the filter
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    // Pixels of the display in the current palette, stretched to the size
+    // given to `set_size`. Unlike `frame` it has no persistence or filter
+    // applied, so screenshots and recordings show the machine state.
+    pub fn capture(&self, display: &Display) -> Frame {
+        let frame = Frame::from_display(display, &self.palette);
+        match self.size {
+            Some((width, height)) if (width, height) != (frame.width, frame.height) => frame.resized(width, height),
+            _ => frame,
+        }
+    }
+
+    fn colorize(&mut self) {
+        for y in 0..self.colored.height {
+            for x in 0..self.colored.width {
+                let i = y * self.colored.width + x;
+                let color = mix(self.palettes.get(i).unwrap_or(&self.palette), self.intensity[i]);
+                self.colored.put(x, y, color);
+            }
+        }
+        self.frame = match self.size {
+            Some((width, height)) if (width, height) != (self.colored.width, self.colored.height) => {
+                self.filter.apply(&self.colored.resized(width, height))
+            }
+            _ => self.filter.apply(&self.colored),
+        };
+    }
+}
+
+// Interpolates between background and foreground colors
+fn mix(palette: &Palette, intensity: f32) -> Color {
+    let mut color = [0; 4];
+    for (i, channel) in color.iter_mut().enumerate() {
+        let background = palette.background[i] as f32;
+        let foreground = palette.foreground[i] as f32;
+        *channel = (background + (foreground - background) * intensity).round() as u8;
+    }
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captures_leave_out_fading_pixels() {
+        let palette = Palette::default();
+        let mut renderer = Renderer::new(palette, Persistence::Fade(DEFAULT_DECAY), Filter::None);
+        let mut display = Display::new();
+        display.poke(3, 2, 1);
+        renderer.update(&display);
+        display.poke(3, 2, 0);
+        renderer.update(&display);
+        assert_ne!(renderer.frame().get(3, 2), palette.background);
+        assert_eq!(renderer.capture(&display).get(3, 2), palette.background);
+    }
+
+    #[test]
+    fn captures_keep_the_session_size() {
+        let mut renderer = Renderer::new(Palette::default(), Persistence::Off, Filter::None);
+        renderer.set_size(128, 64);
+        let capture = renderer.capture(&Display::new());
+        assert_eq!((capture.width, capture.height), (128, 64));
+    }
+}
@@ -0,0 +1,18 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use crate::renderer::Frame;
+
+pub const DEFAULT_SCALE: u32 = 8;
+
+// Saves the frame as a PNG file, each pixel becomes a `scale` x `scale` square.
+pub fn save<P: AsRef<Path>>(path: P, frame: &Frame, scale: u32) -> io::Result<()> {
+    let image = frame.scaled(scale);
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image.pixels)?;
+    Ok(())
+}
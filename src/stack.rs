@@ -1,25 +1,220 @@
-const STACK_COUNT: usize = 16;
+use std::str::FromStr;
+use crate::instruction::Platform;
+use crate::memory::Memory;
+
+// What happens when a call goes past the deepest entry or a return
+// finds the stack empty
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Overflow {
+    // Stops the emulator with a diagnostic
+    Error,
+    // Stops executing instructions, frontends keep showing the display
+    // with a diagnostic
+    Halt,
+    // Stack pointer wraps around, overwriting the oldest entry
+    Wrap,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Overflow::Error),
+            "halt" => Ok(Overflow::Halt),
+            "wrap" => Ok(Overflow::Wrap),
+            _ => Err(format!("Unknown stack overflow mode: {}", s)),
+        }
+    }
+}
+
+// Nesting the original COSMAC VIP interpreter allows, used when VIP
+// timing, firmware or memory layout is selected
+pub const VIP_DEPTH: usize = 12;
+
+// Nesting the interpreter of a platform allows: 12 levels for CHIP-8X,
+// which only ran on COSMAC VIP, 16 for CHIP-8 as most interpreters have it,
+// SCHIP and later ones
+pub fn depth_for(platform: Platform) -> usize {
+    match platform {
+        Platform::Chip8X => VIP_DEPTH,
+        _ => 16,
+    }
+}
 
 pub struct Stack {
-    stack: [u16; STACK_COUNT],
-    stack_pointer: usize
+    stack: Vec<u16>,
+    stack_pointer: usize,
+    overflow: Overflow,
+    // Address of the first entry when the stack is kept in memory, as on
+    // COSMAC VIP, entries are big-endian and grow upwards
+    address: Option<usize>,
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Stack::new()
+    }
 }
 
 impl Stack {
     pub fn new() -> Self {
+        Stack::with_depth(depth_for(Platform::Chip8))
+    }
+
+    pub fn with_depth(depth: usize) -> Self {
         Stack {
-            stack: [0; STACK_COUNT],
-            stack_pointer: 0
+            stack: vec![0; depth.max(1)],
+            stack_pointer: 0,
+            overflow: Overflow::Error,
+            address: None,
         }
     }
 
-    pub fn push(&mut self, address: u16) {
-        self.stack[self.stack_pointer] = address;
+    // Changes how many entries fit, to be called before running
+    pub fn set_depth(&mut self, depth: usize) {
+        self.stack.resize(depth.max(1), 0);
+        self.stack_pointer = self.stack_pointer.min(self.stack.len());
+    }
+
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    // Keeps entries in memory at the given address from now on
+    pub fn map(&mut self, address: usize) {
+        self.address = Some(address);
+    }
+
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    // Number of entries, which is the stack pointer
+    pub fn len(&self) -> usize {
+        self.stack_pointer
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack_pointer == 0
+    }
+
+    // Return addresses, innermost last
+    pub fn entries(&self, memory: &Memory) -> Vec<u16> {
+        (0..self.stack_pointer).map(|i| self.get(memory, i)).collect()
+    }
+
+    pub fn push(&mut self, memory: &mut Memory, address: u16) -> Result<(), String> {
+        if self.stack_pointer == self.depth() {
+            if self.overflow != Overflow::Wrap {
+                return Err(format!("Stack overflow: more than {} nested calls", self.depth()));
+            }
+            self.stack_pointer = 0;
+        }
+        match self.address {
+            Some(base) => {
+                let at = base + self.stack_pointer * 2;
+                memory.poke(at, (address >> 8) as u8);
+                memory.poke(at + 1, address as u8);
+            }
+            None => self.stack[self.stack_pointer] = address,
+        }
         self.stack_pointer += 1;
+        Ok(())
     }
 
-    pub fn pop(&mut self) -> u16 {
+    pub fn pop(&mut self, memory: &Memory) -> Result<u16, String> {
+        if self.stack_pointer == 0 {
+            if self.overflow != Overflow::Wrap {
+                return Err(String::from("Stack underflow: return without a call"));
+            }
+            self.stack_pointer = self.depth();
+        }
         self.stack_pointer -= 1;
-        self.stack[self.stack_pointer]
+        Ok(self.get(memory, self.stack_pointer))
+    }
+
+    fn get(&self, memory: &Memory, i: usize) -> u16 {
+        match self.address {
+            Some(base) => memory.get_opcode((base + i * 2) as u16),
+            None => self.stack[i],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(overflow: Overflow, memory: &mut Memory) -> Stack {
+        let mut stack = Stack::with_depth(2);
+        stack.set_overflow(overflow);
+        stack.push(memory, 0x202).unwrap();
+        stack.push(memory, 0x204).unwrap();
+        stack
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn depth_depends_on_platform() {
+        assert_eq!(depth_for(Platform::Chip8), 16);
+        assert_eq!(depth_for(Platform::Chip8X), VIP_DEPTH);
+        assert_eq!(Stack::new().depth(), 16);
+        assert_eq!(Stack::with_depth(0).depth(), 1);
+    }
+
+    #[test]
+    fn entries_pop_in_reverse_order() {
+        let mut memory = Memory::new();
+        let mut stack = filled(Overflow::Error, &mut memory);
+        assert_eq!(stack.entries(&memory), vec![0x202, 0x204]);
+        assert_eq!(stack.pop(&memory), Ok(0x204));
+        assert_eq!(stack.pop(&memory), Ok(0x202));
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn error_and_halt_refuse_overflow_and_underflow() {
+        for overflow in [Overflow::Error, Overflow::Halt].iter() {
+            let mut memory = Memory::new();
+            let mut stack = filled(*overflow, &mut memory);
+            assert!(stack.push(&mut memory, 0x206).is_err());
+            assert_eq!(stack.len(), 2);
+            stack.pop(&memory).unwrap();
+            stack.pop(&memory).unwrap();
+            assert!(stack.pop(&memory).is_err());
+            assert_eq!(stack.len(), 0);
+        }
+    }
+
+    #[test]
+    fn wrap_overwrites_the_oldest_entry() {
+        let mut memory = Memory::new();
+        let mut stack = filled(Overflow::Wrap, &mut memory);
+        stack.push(&mut memory, 0x206).unwrap();
+        assert_eq!(stack.entries(&memory), vec![0x206]);
+        assert_eq!(stack.pop(&memory), Ok(0x206));
+        assert_eq!(stack.pop(&memory), Ok(0x204));
+    }
+
+    #[test]
+    fn mapped_entries_live_in_memory() {
+        let mut memory = Memory::new();
+        let mut stack = Stack::with_depth(VIP_DEPTH);
+        stack.map(0xEA0);
+        stack.push(&mut memory, 0x2AB).unwrap();
+        assert_eq!((memory.peek(0xEA0), memory.peek(0xEA1)), (0x02, 0xAB));
+        memory.poke(0xEA1, 0xCD);
+        assert_eq!(stack.pop(&memory), Ok(0x2CD));
+    }
+
+    #[test]
+    fn overflow_modes_parse_by_name() {
+        assert_eq!("Halt".parse(), Ok(Overflow::Halt));
+        assert_eq!("wrap".parse(), Ok(Overflow::Wrap));
+        assert!("ignore".parse::<Overflow>().is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::str::FromStr;
+use crate::instruction::Instruction;
+
+// COSMAC VIP runs the CDP1802 at 1.76 MHz, 8 clocks per machine cycle
+pub const CYCLES_PER_FRAME: i32 = 3668;
+// Taken every frame by the CDP1861 display DMA and its interrupt routine
+pub const INTERRUPT_CYCLES: i32 = 1832;
+// Left to the interpreter in every 60 Hz frame
+pub const FRAME_BUDGET: i32 = CYCLES_PER_FRAME - INTERRUPT_CYCLES;
+
+// Fetching and dispatching an instruction, paid by every one of them
+const FETCH_CYCLES: u32 = 68;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Timing {
+    // Every instruction takes one tick, speed is set by `--sleep`
+    Ticks,
+    // Instructions take as long as in the COSMAC VIP interpreter and Dxyn
+    // waits for the next 60 Hz interrupt
+    Vip,
+}
+
+impl FromStr for Timing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ticks" => Ok(Timing::Ticks),
+            "vip" => Ok(Timing::Vip),
+            _ => Err(format!("Unknown timing: {}", s)),
+        }
+    }
+}
+
+// Machine cycles the COSMAC VIP interpreter spends on an instruction,
+// given registers before it runs. Figures follow disassembly of the
+// original interpreter, data dependent loops are approximated. Dxyn is
+// charged for drawing only, the wait for the interrupt comes on top.
+pub fn cycles(instruction: Instruction, v: &[u8; 16]) -> u32 {
+    use Instruction::*;
+    let execute = match instruction {
+        // Clears 256 bytes of display memory
+        Cls => 3078,
+        Ret => 10,
+        Jp(_) => 12,
+        Call(_) => 26,
+        SeByte(..) | SneByte(..) => 10,
+        Se(..) | Sne(..) => 14,
+        LdByte(..) => 6,
+        AddByte(..) => 10,
+        Ld(..) | Or(..) | And(..) | Xor(..) | Add(..) | Sub(..) | Shr(..) | Subn(..) | Shl(..) => 44,
+        LdI(_) => 12,
+        JpV0(..) => 22,
+        Rnd(..) => 36,
+        Drw(x, _, n) => {
+            // Unaligned rows are shifted bit by bit and span two bytes
+            let shift = v[x] as u32 % 8;
+            let row = if shift == 0 { 34 } else { 46 + 4 * shift };
+            26 + row * n as u32
+        }
+        Skp(_) | Sknp(_) => 14,
+        LdVxDt(_) | LdDtVx(_) | LdStVx(_) => 10,
+        // Paid on every poll while waiting for a key
+        LdVxK(_) => 18,
+        AddIVx(_) | LdFVx(_) => 16,
+        // Digits are found by repeated subtraction
+        LdBVx(x) => {
+            let digits = v[x] as u32 / 100 + v[x] as u32 / 10 % 10 + v[x] as u32 % 10;
+            80 + 16 * digits
+        }
+        LdIVx(x) | LdVxI(x) => 14 + 14 * (x as u32 + 1),
+        _ => 0,
+    };
+    FETCH_CYCLES + execute
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Instruction::*;
+
+    const V: [u8; 16] = [0; 16];
+
+    #[test]
+    fn frame_budget_leaves_the_interrupt_cycles_out() {
+        assert_eq!(FRAME_BUDGET, 1836);
+        assert_eq!(FRAME_BUDGET + INTERRUPT_CYCLES, CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn instructions_cost_fetch_and_execute() {
+        let table = [
+            (Cls, 3146),
+            (Ret, 78),
+            (Jp(0x200), 80),
+            (Call(0x200), 94),
+            (SeByte(0, 0), 78),
+            (Se(0, 1), 82),
+            (LdByte(0, 0), 74),
+            (AddByte(0, 1), 78),
+            (Add(0, 1), 112),
+            (LdI(0x300), 80),
+            (JpV0(0, 0x300), 90),
+            (Rnd(0, 0xFF), 104),
+            (Skp(0), 82),
+            (LdVxK(0), 86),
+            (LdFVx(0), 84),
+            (ScrollRight, 68),
+        ];
+        for (instruction, expected) in table.iter() {
+            assert_eq!(cycles(*instruction, &V), *expected, "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn data_dependent_instructions() {
+        let mut v = V;
+        // Aligned rows take 34 cycles, shifted ones 46 plus 4 per bit
+        assert_eq!(cycles(Drw(0, 1, 5), &v), 68 + 26 + 34 * 5);
+        v[0] = 3;
+        assert_eq!(cycles(Drw(0, 1, 5), &v), 68 + 26 + 58 * 5);
+        // 1 + 2 + 3 subtractions for 123
+        v[0] = 123;
+        assert_eq!(cycles(LdBVx(0), &v), 68 + 80 + 16 * 6);
+        assert_eq!(cycles(LdIVx(3), &v), 68 + 14 + 14 * 4);
+        assert_eq!(cycles(LdVxI(0), &v), 68 + 28);
+    }
+
+    #[test]
+    fn timings_parse_by_name() {
+        assert_eq!("VIP".parse(), Ok(Timing::Vip));
+        assert_eq!("ticks".parse(), Ok(Timing::Ticks));
+        assert!("fast".parse::<Timing>().is_err());
+    }
+}
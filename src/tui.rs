@@ -0,0 +1,307 @@
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use crossterm::{cursor, event, execute, queue, style, terminal};
+use crossterm::event::{
+    Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers, KeyboardEnhancementFlags,
+};
+use crossterm::style::Color as TermColor;
+use crate::keymap::{HostKey, Keymap};
+use crate::keypad::{self, Keypad};
+use crate::palette::{Color, Palette};
+use crate::renderer::Frame;
+use crate::vm::{Options, Session};
+
+// Most terminals only report key presses and autorepeat, so a key is
+// considered released when no event came for it during this time. It
+// outlasts the usual autorepeat delay of 250 to 600 ms, once repeats
+// arrive twice their interval is used instead. Terminals supporting
+// kitty keyboard protocol report releases directly.
+const KEY_TIMEOUT: Duration = Duration::from_millis(650);
+// Lower bound for the timeout of a repeating key, events may arrive in bursts
+const MIN_REPEAT_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Charset {
+    // Upper half block, two pixels per character cell in full color
+    HalfBlock,
+    // Braille patterns, eight pixels per character cell in two colors
+    Braille,
+}
+
+impl FromStr for Charset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "half" | "halfblock" => Ok(Charset::HalfBlock),
+            "braille" => Ok(Charset::Braille),
+            _ => Err(format!("Unknown charset: {}", s)),
+        }
+    }
+}
+
+// Puts terminal into raw mode on creation and restores it when dropped,
+// even if the emulator exits with an error.
+struct TerminalGuard {
+    enhanced: bool,
+}
+
+impl TerminalGuard {
+    fn new() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            execute!(
+                stdout,
+                event::PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        Ok(TerminalGuard { enhanced })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.enhanced {
+            let _ = execute!(stdout, event::PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, style::ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+struct TerminalKeys {
+    keymap: Keymap,
+    // Release events are reported by the terminal
+    enhanced: bool,
+    // Time of the last press or repeat event of every CHIP-8 key
+    pressed: [Option<Instant>; keypad::KEY_COUNT],
+    // Measured autorepeat interval of keys being held
+    repeat: [Option<Duration>; keypad::KEY_COUNT],
+}
+
+impl TerminalKeys {
+    fn new(keymap: Keymap, enhanced: bool) -> Self {
+        TerminalKeys {
+            keymap,
+            enhanced,
+            pressed: [None; keypad::KEY_COUNT],
+            repeat: [None; keypad::KEY_COUNT],
+        }
+    }
+
+    fn handle(&mut self, event: &KeyEvent) {
+        let host_key = match host_key(event) {
+            Some(host_key) => host_key,
+            None => return,
+        };
+        let pressed = match event.kind {
+            KeyEventKind::Press | KeyEventKind::Repeat => Some(Instant::now()),
+            KeyEventKind::Release => None,
+        };
+        for key in self.keymap.keys(host_key) {
+            // An event for a key still held is an autorepeat
+            self.repeat[key] = match (self.pressed[key], pressed) {
+                (Some(last), Some(now)) if self.held(key) => Some(now - last),
+                _ => None,
+            };
+            self.pressed[key] = pressed;
+        }
+    }
+
+    fn held(&self, key: usize) -> bool {
+        let timeout = self.repeat[key].map_or(KEY_TIMEOUT, |interval| (interval * 2).max(MIN_REPEAT_TIMEOUT));
+        match self.pressed[key] {
+            Some(time) => self.enhanced || time.elapsed() < timeout,
+            None => false,
+        }
+    }
+
+    fn keypad(&self) -> Keypad {
+        let mut keypad = Keypad::new();
+        for key in 0..keypad::KEY_COUNT {
+            if self.held(key) {
+                keypad.press(key);
+            }
+        }
+        keypad
+    }
+}
+
+// Runs the emulator in the terminal, sharing the core loop and timing
+// with the windowed frontend.
+pub fn run(data: &[u8], options: Options, charset: Charset) -> io::Result<()> {
+    let mut session = Session::new(data, options)?;
+    let guard = TerminalGuard::new()?;
+    let mut keys = TerminalKeys::new(session.options().keymap.clone(), guard.enhanced);
+    let mut stdout = BufWriter::new(io::stdout());
+    let mut message = String::from("Esc quit  F5 theme  F12 screenshot");
+    draw(&mut stdout, &session, charset, &message)?;
+    let mut halted = false;
+    let mut beeping = false;
+
+    'running: loop {
+        while event::poll(Duration::from_secs(0))? {
+            if let Event::Key(key) = event::read()? {
+                let pressed = key.kind != KeyEventKind::Release;
+                match key.code {
+                    KeyCode::Esc => break 'running,
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break 'running,
+                    KeyCode::F(12) if pressed => message = session.take_screenshot(),
+                    KeyCode::F(5) if pressed => message = format!("Theme: {}", session.next_theme()),
+                    _ => keys.handle(&key),
+                }
+            }
+        }
+
+        let frame = session.tick(keys.keypad());
+        // The terminal bell stands in for the buzzer
+        let sound = session.chip8().sound_timer() > 0;
+        if sound && !beeping && session.options().audio {
+            write!(stdout, "\x07")?;
+        }
+        beeping = sound;
+        if let (false, Some(reason)) = (halted, session.chip8().halted()) {
+            message = format!("Halted: {}", reason);
+            halted = true;
+        }
+        if frame {
+            draw(&mut stdout, &session, charset, &message)?;
+        }
+        if session.chip8().exit_requested() {
+            break 'running;
+        }
+        session.sleep();
+    }
+
+    drop(guard);
+    if let Some(reason) = session.chip8().halted() {
+        eprintln!("Halted: {}", reason);
+    }
+    session.finish()
+}
+
+// Numeric keypad keys are only told apart from the main ones when
+// the terminal supports kitty keyboard protocol.
+fn host_key(event: &KeyEvent) -> Option<HostKey> {
+    let keypad = event.state.contains(KeyEventState::KEYPAD);
+    let key = match event.code {
+        KeyCode::Char(' ') => HostKey::Space,
+        KeyCode::Char(c) if keypad => HostKey::Numpad(c),
+        KeyCode::Char(c) => HostKey::Char(c.to_ascii_uppercase()),
+        KeyCode::Enter if keypad => HostKey::NumpadEnter,
+        KeyCode::Enter => HostKey::Enter,
+        KeyCode::Up => HostKey::Up,
+        KeyCode::Down => HostKey::Down,
+        KeyCode::Left => HostKey::Left,
+        KeyCode::Right => HostKey::Right,
+        KeyCode::Tab => HostKey::Tab,
+        KeyCode::Backspace => HostKey::Backspace,
+        KeyCode::Insert => HostKey::Insert,
+        KeyCode::Delete => HostKey::Delete,
+        KeyCode::Home => HostKey::Home,
+        KeyCode::End => HostKey::End,
+        KeyCode::PageUp => HostKey::PageUp,
+        KeyCode::PageDown => HostKey::PageDown,
+        _ => return None,
+    };
+    Some(key)
+}
+
+fn draw<W: Write>(out: &mut W, session: &Session, charset: Charset, message: &str) -> io::Result<()> {
+    let frame = session.renderer().frame();
+    let palette = session.renderer().palette();
+    queue!(out, cursor::MoveTo(0, 0))?;
+    let rows = match charset {
+        Charset::HalfBlock => draw_half_blocks(out, frame)?,
+        Charset::Braille => draw_braille(out, frame, palette)?,
+    };
+    queue!(out, style::ResetColor)?;
+
+    let chip8 = session.chip8();
+    queue!(out, cursor::MoveTo(0, rows + 1), terminal::Clear(terminal::ClearType::CurrentLine))?;
+    write!(
+        out,
+        "PC {:04X}  I {:04X}  DT {:02X}  ST {:02X}  SP {}/{}",
+        chip8.pc(),
+        chip8.index(),
+        chip8.delay_timer(),
+        chip8.sound_timer(),
+        chip8.stack().len(),
+        chip8.stack().depth()
+    )?;
+    queue!(out, cursor::MoveTo(0, rows + 2), terminal::Clear(terminal::ClearType::CurrentLine))?;
+    for (i, value) in chip8.registers().iter().enumerate() {
+        write!(out, "V{:X} {:02X} ", i, value)?;
+    }
+    queue!(out, cursor::MoveTo(0, rows + 3), terminal::Clear(terminal::ClearType::CurrentLine))?;
+    write!(out, "{}", message)?;
+    out.flush()
+}
+
+// Returns number of terminal rows used
+fn draw_half_blocks<W: Write>(out: &mut W, frame: &Frame) -> io::Result<u16> {
+    let rows = frame.height.div_ceil(2);
+    for row in 0..rows {
+        queue!(out, cursor::MoveTo(0, row as u16))?;
+        for x in 0..frame.width {
+            let top = frame.get(x, row * 2);
+            let bottom = if row * 2 + 1 < frame.height { frame.get(x, row * 2 + 1) } else { top };
+            queue!(
+                out,
+                style::SetForegroundColor(term_color(top)),
+                style::SetBackgroundColor(term_color(bottom)),
+                style::Print('\u{2580}')
+            )?;
+        }
+    }
+    Ok(rows as u16)
+}
+
+fn draw_braille<W: Write>(out: &mut W, frame: &Frame, palette: &Palette) -> io::Result<u16> {
+    // Dot bits of a braille character cell, indexed by [y][x]
+    const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+    let rows = frame.height.div_ceil(4);
+    queue!(
+        out,
+        style::SetForegroundColor(term_color(palette.foreground)),
+        style::SetBackgroundColor(term_color(palette.background))
+    )?;
+    for row in 0..rows {
+        queue!(out, cursor::MoveTo(0, row as u16))?;
+        for column in 0..frame.width.div_ceil(2) {
+            let mut bits = 0;
+            for (dy, line) in DOTS.iter().enumerate() {
+                for (dx, bit) in line.iter().enumerate() {
+                    let (x, y) = (column * 2 + dx, row * 4 + dy);
+                    if x < frame.width && y < frame.height && lit(frame.get(x, y), palette) {
+                        bits |= bit;
+                    }
+                }
+            }
+            let symbol = std::char::from_u32(0x2800 + bits).unwrap_or(' ');
+            queue!(out, style::Print(symbol))?;
+        }
+    }
+    Ok(rows as u16)
+}
+
+// Pixel is lit when its color is closer to the foreground than to the background
+fn lit(color: Color, palette: &Palette) -> bool {
+    let distance = |other: Color| -> u32 {
+        color.iter().zip(other.iter()).take(3).map(|(a, b)| (*a as i32 - *b as i32).unsigned_abs()).sum()
+    };
+    distance(palette.foreground) < distance(palette.background)
+}
+
+fn term_color(color: Color) -> TermColor {
+    TermColor::Rgb {
+        r: color[0],
+        g: color[1],
+        b: color[2],
+    }
+}
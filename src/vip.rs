@@ -0,0 +1,258 @@
+use crate::cdp1802::{Bus, Cpu};
+use crate::display::Display;
+use crate::font;
+use crate::keypad::Keypad;
+use crate::memory::START_ADDRESS;
+use crate::timing::CYCLES_PER_FRAME;
+
+// 4 KiB of RAM, mirrored up to the monitor ROM
+const RAM_SIZE: usize = 0x1000;
+// Monitor ROM at 8000, mirrored up to FFFF
+const ROM_ADDRESS: u16 = 0x8000;
+const ROM_SIZE: usize = 0x200;
+// Where the VIP interpreter keeps V0-VF, just below the display page
+const REGISTERS_ADDRESS: usize = RAM_SIZE - 0x110;
+
+// CDP1861 scans 262 lines of 14 machine cycles each, 128 of them show
+// the display. The interrupt comes 2 lines and EF1 4 lines ahead of it.
+const LINE_CYCLES: u32 = 14;
+const DISPLAY_START: u32 = 80;
+const DISPLAY_LINES: usize = 128;
+const INTERRUPT_LINES: u32 = 2;
+const FLAG_LINES: u32 = 4;
+// Bytes fetched by DMA for every display line, 64 pixels
+const LINE_BYTES: usize = 8;
+
+// Interpreter image loaded at 0000 and optional monitor ROM image
+pub struct Firmware {
+    interpreter: Vec<u8>,
+    monitor: Option<Vec<u8>>,
+}
+
+impl Firmware {
+    pub fn new(interpreter: Vec<u8>, monitor: Option<Vec<u8>>) -> Result<Firmware, String> {
+        if interpreter.len() > START_ADDRESS {
+            return Err(format!(
+                "Interpreter image must fit below {:03X}, got {} bytes",
+                START_ADDRESS,
+                interpreter.len()
+            ));
+        }
+        if let Some(monitor) = &monitor {
+            if monitor.len() > ROM_SIZE {
+                return Err(format!("Monitor ROM image must be at most {} bytes, got {}", ROM_SIZE, monitor.len()));
+            }
+        }
+        Ok(Firmware { interpreter, monitor })
+    }
+}
+
+// Memory map and I/O of the VIP board
+struct Board {
+    ram: Vec<u8>,
+    rom: Vec<u8>,
+    // ROM shows at 0000 after reset until the first access above 8000
+    rom_low: bool,
+    // Key selected by OUT 2, reported on EF3 when down
+    latch: u8,
+    keys: Keypad,
+    // CDP1861 is turned on by INP 1 and off by OUT 1
+    display_on: bool,
+    // EF1 from the CDP1861 marks the display window
+    display_flag: bool,
+}
+
+impl Bus for Board {
+    fn read(&mut self, address: u16) -> u8 {
+        if address >= ROM_ADDRESS {
+            self.rom_low = false;
+        }
+        if address >= ROM_ADDRESS || self.rom_low {
+            self.rom[address as usize % ROM_SIZE]
+        } else {
+            self.ram[address as usize % RAM_SIZE]
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address >= ROM_ADDRESS {
+            self.rom_low = false;
+        } else {
+            self.ram[address as usize % RAM_SIZE] = value;
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_on = false,
+            2 => self.latch = value & 0xf,
+            _ => {}
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.display_on = true;
+        }
+        0xff
+    }
+
+    fn flag(&self, number: u8) -> bool {
+        match number {
+            1 => self.display_flag,
+            3 => self.keys.pressed(self.latch as usize),
+            _ => false,
+        }
+    }
+}
+
+// COSMAC VIP running the CHIP-8 interpreter from its firmware, emulated
+// at machine cycle level
+pub struct Vip {
+    cpu: Cpu,
+    board: Board,
+    // Machine cycles since the start of the frame
+    cycle: u32,
+    // Display lines fetched by DMA during the frame
+    lines: Vec<[u8; LINE_BYTES]>,
+}
+
+impl Vip {
+    pub fn new(firmware: &Firmware, program: &[u8]) -> Self {
+        let mut ram = vec![0; RAM_SIZE];
+        ram[..firmware.interpreter.len()].copy_from_slice(&firmware.interpreter);
+        let size = program.len().min(RAM_SIZE - START_ADDRESS);
+        ram[START_ADDRESS..START_ADDRESS + size].copy_from_slice(&program[..size]);
+        let rom = match &firmware.monitor {
+            Some(monitor) => {
+                let mut rom = monitor.clone();
+                rom.resize(ROM_SIZE, 0);
+                rom
+            }
+            None => substitute_monitor(),
+        };
+        let mut cpu = Cpu::new();
+        if firmware.monitor.is_none() {
+            // Set up as left by the monitor: it sizes RAM and passes its
+            // last page, which the interpreter uses for display, in R1.1
+            cpu.r[1] = ((RAM_SIZE - 1) & 0xff00) as u16;
+        }
+        Vip {
+            cpu,
+            board: Board {
+                ram,
+                rom,
+                rom_low: firmware.monitor.is_some(),
+                latch: 0,
+                keys: Keypad::new(),
+                display_on: false,
+                display_flag: false,
+            },
+            cycle: 0,
+            lines: Vec::with_capacity(DISPLAY_LINES),
+        }
+    }
+
+    // The whole frame has been emulated and the interpreter waits for
+    // the next one
+    pub fn frame_done(&self) -> bool {
+        self.cycle >= CYCLES_PER_FRAME as u32
+    }
+
+    // Emulates the rest of the frame with keys held as given
+    pub fn run_frame(&mut self, keys: &Keypad) {
+        self.board.keys = *keys;
+        while !self.frame_done() {
+            self.step();
+        }
+    }
+
+    // Runs one instruction, or the DMA or interrupt which is due first
+    fn step(&mut self) {
+        let line = self.cycle / LINE_CYCLES;
+        let display_end = DISPLAY_START + DISPLAY_LINES as u32;
+        if (DISPLAY_START..display_end).contains(&line) {
+            // Lines passed while the display was off stay blank
+            let due = (line - DISPLAY_START) as usize;
+            while self.lines.len() < due || (!self.board.display_on && self.lines.len() == due) {
+                self.lines.push([0; LINE_BYTES]);
+            }
+            if self.lines.len() == due {
+                let mut bytes = [0; LINE_BYTES];
+                for byte in bytes.iter_mut() {
+                    *byte = self.cpu.dma_out(&mut self.board);
+                }
+                self.lines.push(bytes);
+                self.cycle += LINE_BYTES as u32;
+                return;
+            }
+        }
+
+        self.board.display_flag = self.board.display_on
+            && ((DISPLAY_START - FLAG_LINES..DISPLAY_START).contains(&line)
+                || (display_end - FLAG_LINES..display_end).contains(&line));
+        if self.board.display_on && (DISPLAY_START - INTERRUPT_LINES..DISPLAY_START).contains(&line) {
+            let cycles = self.cpu.interrupt();
+            if cycles > 0 {
+                self.cycle += cycles;
+                return;
+            }
+        }
+        self.cycle += self.cpu.step(&mut self.board);
+    }
+
+    // Ends the frame: shows what the CDP1861 displayed, every fourth
+    // line as the interpreter repeats each row 4 times
+    pub fn end_frame(&mut self, display: &mut Display) {
+        let repeat = DISPLAY_LINES / display.height();
+        for y in 0..display.height() {
+            let bytes = self.lines.get(y * repeat).copied().unwrap_or([0; LINE_BYTES]);
+            for x in 0..display.width() {
+                let pixel = bytes[x / 8] >> (7 - x % 8) & 1;
+                if display.peek(x, y) != pixel {
+                    display.poke(x, y, pixel);
+                }
+            }
+        }
+        self.lines.clear();
+        self.cycle -= (CYCLES_PER_FRAME as u32).min(self.cycle);
+    }
+
+    // CHIP-8 registers as kept by the interpreter in RAM
+    pub fn registers(&self) -> [u8; 16] {
+        let mut v = [0; 16];
+        v.copy_from_slice(&self.board.ram[REGISTERS_ADDRESS..REGISTERS_ADDRESS + 16]);
+        v
+    }
+
+    // The interpreter keeps I in RA, PC in R5 and timers in R8
+    pub fn index(&self) -> u16 {
+        self.cpu.r[0xa]
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.r[5]
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        (self.cpu.r[8] >> 8) as u8
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.cpu.r[8] as u8
+    }
+}
+
+// Stand-in for the monitor ROM holding the built-in font, laid out the
+// way the interpreter looks digits up: a table of sprite offsets within
+// the ROM page at 8100, followed by the sprites
+fn substitute_monitor() -> Vec<u8> {
+    let mut rom = vec![0; ROM_SIZE];
+    let table = 0x100;
+    let sprites = table + 0x10;
+    for digit in 0..16 {
+        rom[table + digit] = (sprites + digit * 5) as u8;
+    }
+    rom[sprites..sprites + font::DEFAULT_FONTSET.len()].copy_from_slice(&font::DEFAULT_FONTSET);
+    rom
+}
@@ -1,70 +1,451 @@
-use winit::event::{Event, VirtualKeyCode};
-use crate::{keypad, font};
+use winit::event::{Event, TouchPhase, VirtualKeyCode, WindowEvent};
+use crate::font::Font;
+use crate::keymap::{HostKey, Keymap};
 use crate::keypad::Keypad;
+use crate::instruction::Platform;
+use crate::machine::MachineCode;
+use crate::memory::Layout;
+use crate::megachip::{MEGA_HEIGHT, MEGA_WIDTH};
+use crate::quirks::Quirks;
+use crate::overlay::{Overlay, Pointer};
+use crate::palette::Palette;
+use crate::renderer::{Frame, Persistence, Renderer};
+use crate::filter::Filter;
+use crate::coverage;
+use crate::screenshot;
+use crate::stack::{self, Overflow};
+use crate::timing::Timing;
+use crate::vip::{Firmware, Vip};
+use crate::record::{self, Format, Recorder};
 use winit_input_helper::WinitInputHelper;
 use crate::chip8::Chip8;
 use pixels::{SurfaceTexture, Pixels};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use winit::event_loop::{ControlFlow, EventLoop};
 use std::thread;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use winit::dpi::{PhysicalSize, LogicalSize, LogicalPosition};
 
-const KEYPAD_MAP: [(usize, VirtualKeyCode); keypad::KEYPAD_SIZE] = [
-    (0, VirtualKeyCode::X),
-    (1, VirtualKeyCode::Key1),
-    (2, VirtualKeyCode::Key2),
-    (3, VirtualKeyCode::Key3),
-    (4, VirtualKeyCode::Q),
-    (5, VirtualKeyCode::W),
-    (6, VirtualKeyCode::E),
-    (7, VirtualKeyCode::A),
-    (8, VirtualKeyCode::S),
-    (9, VirtualKeyCode::D),
-    (10, VirtualKeyCode::Z),
-    (11, VirtualKeyCode::C),
-    (12, VirtualKeyCode::Key4),
-    (13, VirtualKeyCode::R),
-    (14, VirtualKeyCode::F),
-    (15, VirtualKeyCode::V)
-];
-
-pub fn run(data: &[u8], sleep_duration: u64) {
+const SCREENSHOT_KEY: VirtualKeyCode = VirtualKeyCode::F12;
+const THEME_KEY: VirtualKeyCode = VirtualKeyCode::F5;
+
+pub struct Options {
+    // Timeout between ticks
+    pub sleep_duration: Duration,
+    // How long instructions take, with VIP timing `sleep_duration` is unused
+    pub timing: Timing,
+    // Runs the original interpreter on an emulated COSMAC VIP
+    pub vip: Option<Firmware>,
+    pub palette: Palette,
+    pub persistence: Persistence,
+    pub filter: Filter,
+    // Integer scale of the window, by default it takes 2/3 of the screen height
+    pub window_scale: Option<u32>,
+    // Show clickable hex keypad beside the display
+    pub show_keypad: bool,
+    // Sound while the sound timer runs
+    pub audio: bool,
+    pub keymap: Keymap,
+    pub quirks: Quirks,
+    // What 0nnn does
+    pub machine_code: MachineCode,
+    // CHIP-8X programs load at 300 and get colors and a second keypad,
+    // MEGA-CHIP ones get 16 MiB of memory and a 256x192 color display
+    pub platform: Platform,
+    // Load and font addresses, by default those of the platform
+    pub layout: Layout,
+    // Keep stack and display in memory as the VIP interpreter does
+    pub memory_map: bool,
+    // Nested calls allowed, by default as many as the platform allows
+    pub stack_depth: Option<usize>,
+    pub stack_overflow: Overflow,
+    pub font: Font,
+    // Integer scale factor for PNG screenshots
+    pub screenshot_scale: u32,
+    // Screenshot to save when the emulator exits
+    pub screenshot: Option<PathBuf>,
+    // Gameplay recording destination, `-` for stdout
+    pub record: Option<PathBuf>,
+    pub record_format: Format,
+    // Integer scale factor for recorded frames
+    pub record_scale: u32,
+    // Memory coverage report to save when the emulator exits
+    pub coverage: Option<PathBuf>,
+    pub coverage_format: coverage::Format,
+    // Print hotspot report to stderr when the emulator exits
+    pub profile: bool,
+    // Folded call stacks for flame graphs to save when the emulator exits
+    pub profile_folded: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            sleep_duration: Duration::from_millis(2),
+            timing: Timing::Ticks,
+            vip: None,
+            palette: Palette::default(),
+            persistence: Persistence::Off,
+            filter: Filter::None,
+            window_scale: None,
+            show_keypad: false,
+            audio: true,
+            keymap: Keymap::default(),
+            quirks: Quirks::default(),
+            machine_code: MachineCode::Run,
+            platform: Platform::Chip8,
+            layout: Layout::default(),
+            memory_map: false,
+            stack_depth: None,
+            stack_overflow: Overflow::Error,
+            font: Font::default(),
+            screenshot_scale: screenshot::DEFAULT_SCALE,
+            screenshot: None,
+            record: None,
+            record_format: Format::Gif,
+            record_scale: screenshot::DEFAULT_SCALE,
+            coverage: None,
+            coverage_format: coverage::Format::Text,
+            profile: false,
+            profile_folded: None,
+        }
+    }
+}
+
+fn create_chip8(data: &[u8], options: &Options) -> Chip8 {
     let mut chip8 = Chip8::new();
-    chip8.load_fontset(&font::DEFAULT_FONTSET);
-    chip8.load_rom(&data);
+    chip8.set_platform(options.platform);
+    chip8.set_layout(options.layout);
+    chip8.load_font(&options.font);
+    chip8.load_rom(data);
+    // Options already include quirks recommended by the database, see
+    // `Config::settings`
+    chip8.set_quirks(options.quirks);
+    chip8.set_timing(options.timing);
+    chip8.set_machine_code(options.machine_code);
+    if let Some(firmware) = &options.vip {
+        chip8.enable_vip(Vip::new(firmware, data));
+    }
+    // Programs run the VIP way get its shallower stack
+    let vip = options.timing == Timing::Vip || options.vip.is_some() || options.memory_map;
+    match options.stack_depth {
+        Some(depth) => chip8.set_stack_depth(depth),
+        None if vip => chip8.set_stack_depth(stack::VIP_DEPTH),
+        None => {}
+    }
+    chip8.set_stack_overflow(options.stack_overflow);
+    if options.memory_map {
+        if let Err(e) = chip8.enable_memory_map() {
+            eprintln!("{}", e);
+        }
+    }
+    if options.coverage.is_some() {
+        chip8.enable_coverage();
+    }
+    if options.profile || options.profile_folded.is_some() {
+        chip8.enable_profiler();
+    }
+    chip8
+}
+
+// Decides when a 60 Hz frame ends
+enum FrameClock {
+    // Frames follow wall clock time, used by interactive frontends
+    Realtime { last_frame: Instant },
+    // Frames end every given number of ticks, used when running headless
+    Ticks { per_frame: u64, count: u64 },
+    // Frames end when the interpreter has used up their machine cycles,
+    // used when running headless with VIP timing
+    Cycles,
+}
+
+// Core loop shared by all frontends: runs the interpreter, renders a frame
+// every 60 Hz and feeds it to the recorder.
+pub struct Session {
+    chip8: Chip8,
+    renderer: Renderer,
+    recorder: Option<Recorder>,
+    clock: FrameClock,
+    options: Options,
+}
+
+impl Session {
+    pub fn new(data: &[u8], options: Options) -> io::Result<Self> {
+        Session::with_clock(data, options, true)
+    }
+
+    // Creates a session which is not bound to wall clock time, frames
+    // are counted in ticks at the configured speed instead.
+    pub fn headless(data: &[u8], options: Options) -> io::Result<Self> {
+        Session::with_clock(data, options, false)
+    }
+
+    fn with_clock(data: &[u8], options: Options, realtime: bool) -> io::Result<Self> {
+        let chip8 = create_chip8(data, &options);
+        let clock = match chip8.timing() {
+            _ if realtime => FrameClock::Realtime { last_frame: Instant::now() },
+            Timing::Ticks => FrameClock::Ticks { per_frame: ticks_per_frame(options.sleep_duration), count: 0 },
+            Timing::Vip => FrameClock::Cycles,
+        };
+        let mut renderer = Renderer::new(options.palette, options.persistence, options.filter);
+        // MEGA-CHIP programs switch to a larger display once running, the
+        // window and recordings are made for it from the start
+        if chip8.platform() == Platform::MegaChip {
+            renderer.set_size(MEGA_WIDTH, MEGA_HEIGHT);
+        }
+        renderer.update(chip8.display());
+        let recorder = create_recorder(&renderer.capture(chip8.display()), &options)?;
+        Ok(Session {
+            chip8,
+            renderer,
+            recorder,
+            clock,
+            options,
+        })
+    }
+
+    pub fn chip8(&self) -> &Chip8 {
+        &self.chip8
+    }
+
+    pub fn renderer(&self) -> &Renderer {
+        &self.renderer
+    }
+
+    pub fn renderer_mut(&mut self) -> &mut Renderer {
+        &mut self.renderer
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    // Runs one tick, returns true if a new frame was rendered
+    pub fn tick(&mut self, keypad: Keypad) -> bool {
+        self.chip8.tick(keypad);
+        let mut frames = 0;
+        match &mut self.clock {
+            FrameClock::Realtime { last_frame } => {
+                let frame_duration = Duration::from_secs(1) / record::FRAME_RATE;
+                while last_frame.elapsed() >= frame_duration {
+                    *last_frame += frame_duration;
+                    frames += 1;
+                }
+            }
+            FrameClock::Ticks { per_frame, count } => {
+                *count += 1;
+                if *count % *per_frame == 0 {
+                    frames = 1;
+                }
+            }
+            FrameClock::Cycles => {
+                if self.chip8.frame_done() {
+                    frames = 1;
+                }
+            }
+        }
+        for _ in 0..frames {
+            self.chip8.start_frame();
+            self.renderer.update(self.chip8.display());
+            if let Some(recorder) = &mut self.recorder {
+                let frame = self.renderer.capture(self.chip8.display());
+                if let Err(e) = recorder.frame(&frame.scaled(self.options.record_scale).pixels) {
+                    eprintln!("Recording stopped: {}", e);
+                    self.recorder = None;
+                }
+            }
+        }
+        frames > 0
+    }
+
+    // Waits before the next tick according to the configured speed. With
+    // VIP timing ticks run at full speed until the frame is used up, then
+    // wait for the next one.
+    pub fn sleep(&self) {
+        if let FrameClock::Realtime { last_frame } = self.clock {
+            match self.chip8.timing() {
+                Timing::Ticks => thread::sleep(self.options.sleep_duration),
+                Timing::Vip if self.chip8.frame_done() => {
+                    let frame_duration = Duration::from_secs(1) / record::FRAME_RATE;
+                    thread::sleep(frame_duration.saturating_sub(last_frame.elapsed()));
+                }
+                Timing::Vip => {}
+            }
+        }
+    }
+
+    pub fn save_screenshot(&self, path: &Path) -> io::Result<()> {
+        screenshot::save(path, &self.renderer.capture(self.chip8.display()), self.options.screenshot_scale)
+    }
+
+    // Saves a screenshot into current directory, returns a message
+    // describing the outcome
+    pub fn take_screenshot(&self) -> String {
+        let path = screenshot_filename();
+        match self.save_screenshot(&path) {
+            Ok(()) => format!("Screenshot saved to {}", path.display()),
+            Err(e) => format!("Failed to save screenshot {}: {}", path.display(), e),
+        }
+    }
+
+    // Switches to the next built-in color theme, returns its name
+    pub fn next_theme(&mut self) -> &'static str {
+        let (name, theme) = self.renderer.palette().next_theme();
+        self.renderer.set_palette(theme);
+        name
+    }
+
+    // Finishes the recording and saves the exit screenshot, coverage and
+    // profiler reports, if requested
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish()?;
+        }
+        if let Some(path) = &self.options.screenshot {
+            self.save_screenshot(path)?;
+        }
+        if let (Some(path), Some(coverage)) = (&self.options.coverage, self.chip8.coverage()) {
+            coverage.save(path, self.options.coverage_format, self.chip8.memory())?;
+        }
+        if let Some(profiler) = self.chip8.profiler() {
+            if self.options.profile {
+                profiler.write_report(&mut io::stderr().lock(), self.chip8.memory())?;
+            }
+            if let Some(path) = &self.options.profile_folded {
+                profiler.save_folded(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn create_recorder(frame: &Frame, options: &Options) -> io::Result<Option<Recorder>> {
+    match &options.record {
+        Some(path) => {
+            let scale = options.record_scale.max(1) as usize;
+            let width = frame.width * scale;
+            let height = frame.height * scale;
+            Recorder::create(path, options.record_format, width, height).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+// Number of ticks emulated during one 60 Hz frame at the given speed
+fn ticks_per_frame(sleep_duration: Duration) -> u64 {
+    let frame_duration = Duration::from_secs(1) / record::FRAME_RATE;
+    let ticks = frame_duration.as_secs_f64() / sleep_duration.max(Duration::from_micros(1)).as_secs_f64();
+    (ticks.round() as u64).max(1)
+}
+
+// Runs the given number of ticks without opening a window, then saves
+// the requested screenshot, if any.
+pub fn run_headless(data: &[u8], ticks: u64, options: Options) -> io::Result<()> {
+    let mut session = Session::headless(data, options)?;
+    for _ in 0..ticks {
+        session.tick(Keypad::new());
+        if session.chip8().exit_requested() {
+            break;
+        }
+    }
+    if let Some(message) = session.chip8().halted() {
+        eprintln!("Halted: {}", message);
+    }
+    session.finish()
+}
+
+pub fn run(data: &[u8], options: Options) -> io::Result<()> {
+    let keypad_map: Vec<(usize, VirtualKeyCode)> = options.keymap
+        .bindings()
+        .filter_map(|(key, host_key)| virtual_key_code(host_key).map(|code| (key, code)))
+        .collect();
+    // Window scale is given in display pixels, filters enlarge the frame
+    let window_scale = options.window_scale.map(|scale| scale.max(1) as f64 / options.filter.factor() as f64);
+    let mut session = Session::new(data, options)?;
+    let mut overlay = if session.options().show_keypad { Some(Overlay::new()) } else { None };
 
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
+    let frame = session.renderer().frame();
+    let (buffer_width, buffer_height) = match overlay {
+        Some(_) => Overlay::size(frame),
+        None => (frame.width, frame.height),
+    };
+    let title = match session.chip8().program() {
+        Some(program) => format!("Chip8 Emulator - {}", program),
+        None => String::from("Chip8 Emulator"),
+    };
     let (window, surface, width, height, mut _hidpi_factor) =
-        create_window("Chip8 Emulator", &event_loop);
+        create_window(&title, buffer_width as f64, buffer_height as f64, window_scale, &event_loop);
     let surface_texture = SurfaceTexture::new(width, height, surface);
-    let mut pixels = Pixels::new(64, 32, surface_texture).unwrap();
-    let sleep_duration = Duration::from_millis(sleep_duration);
+    let mut pixels = Pixels::new(buffer_width as u32, buffer_height as u32, surface_texture).unwrap();
+    let mut halted = false;
 
     event_loop.run(move |event, _, control_flow| {
         // The one and only event that winit_input_helper doesn't have for us...
         if let Event::RedrawRequested(_) = event {
-            if chip8.display_changed() {
-                chip8.draw(pixels.get_frame());
-                if pixels
-                    .render()
-                    // .map_err(|e| error!("pixels.render() failed: {}", e))
-                    .is_err()
-                {
-                    *control_flow = ControlFlow::Exit;
-                    return;
+            let renderer = session.renderer();
+            match overlay {
+                Some(_) => {
+                    let frame = Overlay::compose(renderer.frame(), session.chip8().keypad(), renderer.palette());
+                    pixels.get_frame().copy_from_slice(&frame.pixels);
                 }
+                None => pixels.get_frame().copy_from_slice(&renderer.frame().pixels),
+            }
+            if pixels
+                .render()
+                // .map_err(|e| error!("pixels.render() failed: {}", e))
+                .is_err()
+            {
+                *control_flow = ControlFlow::Exit;
+                return;
             }
         }
 
+        // ...and touches
+        if let (Some(overlay), Event::WindowEvent { event: WindowEvent::Touch(touch), .. }) = (&mut overlay, &event) {
+            let key = match touch.phase {
+                TouchPhase::Started | TouchPhase::Moved => {
+                    let position = (touch.location.x as f32, touch.location.y as f32);
+                    overlay_key(&pixels, session.renderer().frame(), position)
+                }
+                TouchPhase::Ended | TouchPhase::Cancelled => None,
+            };
+            overlay.set(Pointer::Touch(touch.id), key);
+        }
+
         if input.update(&event) {
             // Close events
             if input.key_pressed(VirtualKeyCode::Escape) || input.quit() {
+                if let Err(e) = session.finish() {
+                    eprintln!("{}", e);
+                }
                 *control_flow = ControlFlow::Exit;
                 return;
             }
 
-            let keypad = keypad(&input);
+            if input.key_pressed(SCREENSHOT_KEY) {
+                println!("{}", session.take_screenshot());
+            }
+
+            if input.key_pressed(THEME_KEY) {
+                println!("Theme: {}", session.next_theme());
+                window.request_redraw();
+            }
+
+            let mut keypad = keypad(&input, &keypad_map);
+            if let Some(overlay) = &mut overlay {
+                let key = match input.mouse() {
+                    Some(position) if input.mouse_held(0) => {
+                        overlay_key(&pixels, session.renderer().frame(), position)
+                    }
+                    _ => None,
+                };
+                overlay.set(Pointer::Mouse, key);
+                overlay.press(&mut keypad);
+            }
 
             // Adjust high DPI factor
             if let Some(factor) = input.scale_factor_changed() {
@@ -76,34 +457,50 @@ pub fn run(data: &[u8], sleep_duration: u64) {
                 pixels.resize(size.width, size.height);
             }
 
-            chip8.tick(keypad);
-            window.request_redraw();
-            if chip8.sound_timer() > 0 {
-                // TODO
-            } else {
-                // TODO
+            if session.tick(keypad) {
+                window.request_redraw();
+            }
+            // The display stays open to show how the program ended,
+            // unless the emulator is asked to stop
+            if let (false, Some(message)) = (halted, session.chip8().halted()) {
+                eprintln!("Halted: {}", message);
+                window.set_title(&format!("{} - Halted: {}", title, message));
+                halted = true;
+                if session.chip8().exit_requested() {
+                    if let Err(e) = session.finish() {
+                        eprintln!("{}", e);
+                    }
+                    *control_flow = ControlFlow::Exit;
+                }
             }
-            thread::sleep(sleep_duration);
+            // TODO: play sound while chip8.sound_timer() > 0 and options.audio is set
+            session.sleep();
         }
     })
+}
 
+// Returns overlay key under the given physical window position
+fn overlay_key(pixels: &Pixels, frame: &Frame, position: (f32, f32)) -> Option<usize> {
+    let (x, y) = pixels.window_pos_to_pixel(position).ok()?;
+    Overlay::key_at(frame, x, y)
 }
 
 fn create_window(
     title: &str,
+    width: f64,
+    height: f64,
+    scale: Option<f64>,
     event_loop: &EventLoop<()>,
 ) -> (winit::window::Window, pixels::wgpu::Surface, u32, u32, f64) {
     // Create a hidden window so we can estimate a good default window size
     let window = winit::window::WindowBuilder::new()
         .with_visible(false)
         .with_title(title)
-        .build(&event_loop)
+        .build(event_loop)
         .unwrap();
     let hidpi_factor = window.scale_factor();
 
     // Get dimensions
-    let width = 64.0;
-    let height = 32.0;
     let (monitor_width, monitor_height) = {
         let size = window.current_monitor().size();
         (
@@ -111,7 +508,10 @@ fn create_window(
             size.height as f64 / hidpi_factor,
         )
     };
-    let scale = (monitor_height / height * 2.0 / 3.0).round();
+    let scale = match scale {
+        Some(scale) => scale,
+        None => (monitor_height / height * 2.0 / 3.0).round(),
+    };
 
     // Resize, center, and display the window
     let min_size = PhysicalSize::new(width, height).to_logical::<f64>(hidpi_factor);
@@ -137,12 +537,60 @@ fn create_window(
     )
 }
 
-fn keypad(input: &WinitInputHelper) -> Keypad {
+fn keypad(input: &WinitInputHelper, keypad_map: &[(usize, VirtualKeyCode)]) -> Keypad {
     let mut keypad = Keypad::new();
-    for (key, key_code) in &KEYPAD_MAP {
+    for (key, key_code) in keypad_map {
         if input.key_pressed(*key_code) || input.key_held(*key_code) {
             keypad.press(*key);
         }
     }
     keypad
 }
+
+fn screenshot_filename() -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    PathBuf::from(format!("chip8-{}.png", timestamp))
+}
+
+fn virtual_key_code(host_key: HostKey) -> Option<VirtualKeyCode> {
+    use VirtualKeyCode::*;
+    let code = match host_key {
+        HostKey::Char(c) => match c {
+            '0' => Key0, '1' => Key1, '2' => Key2, '3' => Key3, '4' => Key4,
+            '5' => Key5, '6' => Key6, '7' => Key7, '8' => Key8, '9' => Key9,
+            'A' => A, 'B' => B, 'C' => C, 'D' => D, 'E' => E, 'F' => F, 'G' => G,
+            'H' => H, 'I' => I, 'J' => J, 'K' => K, 'L' => L, 'M' => M, 'N' => N,
+            'O' => O, 'P' => P, 'Q' => Q, 'R' => R, 'S' => S, 'T' => T, 'U' => U,
+            'V' => V, 'W' => W, 'X' => X, 'Y' => Y, 'Z' => Z,
+            ',' => Comma, '.' => Period, '/' => Slash, ';' => Semicolon,
+            '\'' => Apostrophe, '-' => Minus, '=' => Equals, '[' => LBracket,
+            ']' => RBracket, '\\' => Backslash, '`' => Grave,
+            _ => return None,
+        },
+        HostKey::Numpad(c) => match c {
+            '0' => Numpad0, '1' => Numpad1, '2' => Numpad2, '3' => Numpad3, '4' => Numpad4,
+            '5' => Numpad5, '6' => Numpad6, '7' => Numpad7, '8' => Numpad8, '9' => Numpad9,
+            '+' => Add, '-' => Subtract, '*' => Multiply, '/' => Divide, '.' => Decimal,
+            _ => return None,
+        },
+        HostKey::NumpadEnter => NumpadEnter,
+        HostKey::Up => Up,
+        HostKey::Down => Down,
+        HostKey::Left => Left,
+        HostKey::Right => Right,
+        HostKey::Space => Space,
+        HostKey::Enter => Return,
+        HostKey::Tab => Tab,
+        HostKey::Backspace => Back,
+        HostKey::Insert => Insert,
+        HostKey::Delete => Delete,
+        HostKey::Home => Home,
+        HostKey::End => End,
+        HostKey::PageUp => PageUp,
+        HostKey::PageDown => PageDown,
+    };
+    Some(code)
+}